@@ -9,6 +9,7 @@ use p2proxy_lib::display_chain;
 use p2proxy_lib::proto::ServerPortMapString;
 use std::path::PathBuf;
 use std::process::ExitCode;
+use std::time::Duration;
 use tokio::runtime::LocalRuntime;
 
 #[derive(Debug, clap::Parser)]
@@ -45,9 +46,21 @@ pub enum Subcommand {
         /// The optional remote port routing name.
         #[clap(long, env)]
         named_port: Option<String>,
+        /// The protocol to serve locally, needs to match the remote port's protocol.
+        #[clap(long, env, default_value = "tcp")]
+        protocol: LocalProtocol,
+        /// For udp, seconds without datagrams from a local source before its flow is closed.
+        #[clap(long, env, default_value_t = 60)]
+        udp_idle_timeout_secs: u64,
     },
 }
 
+#[derive(Debug, Copy, Clone, clap::ValueEnum)]
+pub enum LocalProtocol {
+    Tcp,
+    Udp,
+}
+
 fn main() -> ExitCode {
     let args = Args::parse();
     let runtime = LocalRuntime::new().expect("failed to create p2proxyd runtime");
@@ -81,6 +94,8 @@ async fn run_app(args: Args) -> anyhow::Result<()> {
             peer,
             local_port,
             named_port: remote_port_name,
+            protocol,
+            udp_idle_timeout_secs,
         } => {
             let rmp = if let Some(p) = remote_port_name {
                 Some(ServerPortMapString::try_new(p).context("failed to create server port map")?)
@@ -113,9 +128,21 @@ async fn run_app(args: Args) -> anyhow::Result<()> {
                 .await
                 .context("failed to bind endpoint")?;
             let (_ks, listen) = ProxyKillSwitch::new_pair();
-            let mut receiver = p2proxy_client::spawn_serve_with_updates_killswitched(
-                ep, peer, local_port, rmp, listen,
-            );
+            let mut receiver = match protocol {
+                LocalProtocol::Tcp => p2proxy_client::spawn_serve_with_updates_killswitched(
+                    ep, peer, local_port, rmp, listen,
+                ),
+                LocalProtocol::Udp => {
+                    p2proxy_client::udp::spawn_udp_serve_with_updates_killswitched(
+                        ep,
+                        peer,
+                        local_port,
+                        rmp,
+                        Duration::from_secs(udp_idle_timeout_secs),
+                        listen,
+                    )
+                }
+            };
             while let Some(update) = receiver.recv().await {
                 let up = update?;
                 tracing::info!("received update: {up:?}");
@@ -1,15 +1,17 @@
 pub mod killswitch;
+pub mod udp;
 
 use crate::killswitch::{KillSwitchResult, ProxyKillSwitchListener};
 use anyhow::{Context, bail};
 use iroh::endpoint::Connection;
 use iroh::{Endpoint, NodeAddr, NodeId, SecretKey};
 use p2proxy_lib::display_chain;
-use p2proxy_lib::proto::{ALPN, ServerPortMapString};
+use p2proxy_lib::proto::{ALPN, REDIRECT_MESSAGE_LENGTH, ServerPortMapString, decode_redirect};
 use p2proxy_lib::proxy_copy_buf::{BufCopyError, BufferedCopy};
 use std::fmt::{Display, Formatter};
 use std::path::Path;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex, PoisonError};
 use std::time::{Duration, Instant};
 use tokio::net::{TcpListener, TcpStream};
 
@@ -88,8 +90,79 @@ pub enum ServeUpdate {
     BindingTcp,
     ListeningTcp,
     AcceptedTcp(ConId),
+    BindingUdp,
+    ListeningUdp,
+    UdpFlowOpened(ConId),
+    UdpFlowClosed(ConId),
     IrohConnecting(ConId),
     ConnectionError(ConId, anyhow::Error),
+    /// The server is rotating its key, new connections go to `to` from now on
+    PeerRedirected {
+        from: NodeId,
+        to: NodeId,
+    },
+}
+
+/// The node id connections are made to, replaced by its successor when the server announces a key rotation
+#[derive(Debug, Clone)]
+pub(crate) struct FollowedPeer(Arc<Mutex<NodeId>>);
+
+impl FollowedPeer {
+    pub(crate) fn new(peer: NodeId) -> Self {
+        Self(Arc::new(Mutex::new(peer)))
+    }
+
+    pub(crate) fn get(&self) -> NodeId {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Starts listening for a redirect on `con`, made to `connected_to`, for as long as the connection is open
+    pub(crate) fn spawn_watch_redirect(
+        &self,
+        con: &Connection,
+        connected_to: NodeId,
+        sender: tokio::sync::mpsc::Sender<anyhow::Result<ServeUpdate>>,
+    ) {
+        let followed = self.clone();
+        let con = con.clone();
+        tokio::task::spawn(async move {
+            // Fails when the connection closes, which is the normal case without a rotation
+            let Ok(mut recv) = con.accept_uni().await else {
+                return;
+            };
+            let mut msg = [0u8; REDIRECT_MESSAGE_LENGTH];
+            let successor = match recv.read_exact(&mut msg).await {
+                Ok(()) => decode_redirect(&msg),
+                Err(e) => Err(anyhow::anyhow!(
+                    "failed to read redirect: {}",
+                    display_chain(&e)
+                )),
+            };
+            let successor = match successor {
+                Ok(successor) => successor,
+                Err(e) => {
+                    tracing::warn!(
+                        "invalid redirect from {connected_to}: {}",
+                        display_chain(&*e)
+                    );
+                    return;
+                }
+            };
+            {
+                let mut current = followed.0.lock().unwrap_or_else(PoisonError::into_inner);
+                // Another connection may have followed already
+                if *current != connected_to {
+                    return;
+                }
+                *current = successor;
+            }
+            tracing::info!("{connected_to} is rotating its key, following it to {successor}");
+            let _ = sender.try_send(Ok(ServeUpdate::PeerRedirected {
+                from: connected_to,
+                to: successor,
+            }));
+        });
+    }
 }
 
 #[must_use]
@@ -137,6 +210,7 @@ async fn drive_tcp_task(
     peer: NodeId,
     mut proxy_kill_switch_listener: ProxyKillSwitchListener,
 ) {
+    let peer = FollowedPeer::new(peer);
     {
         let addr = format!("0.0.0.0:{local_port}");
         if send.try_send(Ok(ServeUpdate::BindingTcp)).is_err() {
@@ -187,7 +261,7 @@ async fn drive_tcp_task(
                         tracing::info!("received kill signal before connection was spawned");
                         return;
                     };
-                    tokio::task::spawn(run_on_tcp(endpoint.clone(), con_id, next, peer, dest_port_map.clone(), send.clone(), ks_c));
+                    tokio::task::spawn(run_on_tcp(endpoint.clone(), con_id, next, peer.clone(), dest_port_map.clone(), send.clone(), ks_c));
                 }
                 () = send.closed() => {
                     tracing::debug!("updates receiver dropped");
@@ -203,13 +277,12 @@ async fn run_on_tcp(
     endpoint: Endpoint,
     con_id: ConId,
     mut tcp: TcpStream,
-    peer: NodeId,
+    peer: FollowedPeer,
     dest_port_map: Option<ServerPortMapString>,
     sender: tokio::sync::mpsc::Sender<anyhow::Result<ServeUpdate>>,
     mut proxy_kill_switch_listener: ProxyKillSwitchListener,
 ) {
     const CONNECTION_LIVE_AFTER: Duration = Duration::from_secs(2);
-    let node_addr = NodeAddr::new(peer);
     let mut failed_connects = 0;
 
     loop {
@@ -229,10 +302,12 @@ async fn run_on_tcp(
         {
             return;
         }
+        // Read on every attempt, a reconnect goes to the successor if the server redirected
+        let node_id = peer.get();
         let KillSwitchResult::Finished(con_res) = proxy_kill_switch_listener
             .if_not_killed(tokio::time::timeout(
                 Duration::from_millis(10_000),
-                endpoint.connect(node_addr.clone(), ALPN),
+                endpoint.connect(NodeAddr::new(node_id), ALPN),
             ))
             .await
         else {
@@ -241,6 +316,7 @@ async fn run_on_tcp(
         };
         match con_res {
             Ok(Ok(con)) => {
+                peer.spawn_watch_redirect(&con, node_id, sender.clone());
                 let con_start = Instant::now();
                 if let Err(e) = run_connection(
                     con,
@@ -270,12 +346,51 @@ async fn run_on_tcp(
                             // Don't retry on forbidden
                             return;
                         }
-                        BufCopyError::TCPEoF => {
-                            tracing::debug!("Tcp EOF, shutting down connection");
+                        BufCopyError::QuicKicked => {
+                            let _ = sender.try_send(Err(anyhow::anyhow!(
+                                "connection closed by the server operator"
+                            )));
+                            tracing::warn!("connection closed by the server operator");
+                            // Don't retry when kicked
+                            return;
+                        }
+                        BufCopyError::QuicQuotaExceeded => {
+                            let _ = sender.try_send(Err(anyhow::anyhow!(
+                                "traffic quota on the server exceeded"
+                            )));
+                            tracing::warn!("traffic quota on the server exceeded");
+                            // Won't succeed until the quota's period is over
+                            return;
+                        }
+                        BufCopyError::QuicBanned(until) => {
+                            let _ = sender.try_send(Err(anyhow::anyhow!(
+                                "banned by the server until {until}"
+                            )));
+                            tracing::warn!("banned by the server until {until}");
+                            // Won't succeed until the ban runs out
+                            return;
+                        }
+                        BufCopyError::QuicIdleTimeout | BufCopyError::QuicMaxLifetime => {
+                            // Closed on purpose by the server's route settings,
+                            // a new local connection gets a new stream
+                            tracing::info!("server closed the connection: {e}");
+                            return;
+                        }
+                        BufCopyError::QuicServerShutdown => {
+                            // A clean close, new local connections will connect again
+                            // once the server is back
+                            tracing::info!("server is shutting down, closing connection");
+                            return;
+                        }
+                        BufCopyError::TCPEoF | BufCopyError::QuicEoF => {
+                            tracing::debug!("{e}, shutting down connection");
                             // Connection is complete, this is not necessarily
                             // an error (although it could theoretically be)
                             return;
                         }
+                        BufCopyError::QuicLimited => {
+                            tracing::warn!("server refused the connection, a limit was reached");
+                        }
                         BufCopyError::QuicClosed(_)
                         | BufCopyError::QuicInternal
                         | BufCopyError::Unactionable(_) => {}
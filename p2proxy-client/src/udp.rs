@@ -0,0 +1,311 @@
+use crate::killswitch::{KillSwitchResult, ProxyKillSwitchListener};
+use crate::{ConId, FollowedPeer, ServeUpdate};
+use anyhow::Context;
+use iroh::endpoint::{Connection, RecvStream, SendStream};
+use iroh::{Endpoint, NodeAddr, NodeId};
+use p2proxy_lib::datagram::{FlowActivity, MAX_DATAGRAM_SIZE, read_datagram, write_datagram};
+use p2proxy_lib::display_chain;
+use p2proxy_lib::proto::{ALPN, ServerPortMapString};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::error::TrySendError;
+
+/// Datagrams from a local source that are queued up while its flow is connecting.
+/// If the flow can't keep up, datagrams are dropped, the same as a congested UDP link would.
+const FLOW_QUEUE_SIZE: usize = 64;
+
+/// Serve a UDP port locally, each local source address gets its own flow over the tunnel,
+/// which is closed after `idle_timeout` without datagrams in either direction.
+#[must_use]
+pub fn spawn_udp_serve_with_updates_killswitched(
+    endpoint: Endpoint,
+    peer: NodeId,
+    port: u16,
+    dest_port_map: Option<ServerPortMapString>,
+    idle_timeout: Duration,
+    mut kill_switch: ProxyKillSwitchListener,
+) -> tokio::sync::mpsc::Receiver<anyhow::Result<ServeUpdate>> {
+    let (send, recv) = tokio::sync::mpsc::channel(64);
+
+    tokio::spawn(async move {
+        let Some(ks_c) = kill_switch.duplicate() else {
+            tracing::warn!("received kill signal before proxy was spawned");
+            return;
+        };
+        match kill_switch
+            .if_not_killed(drive_udp_task(
+                send,
+                port,
+                dest_port_map,
+                endpoint,
+                peer,
+                idle_timeout,
+                ks_c,
+            ))
+            .await
+        {
+            KillSwitchResult::Killed => {
+                tracing::info!("udp proxy at {peer} on port {port} was killed, exiting proxy task");
+            }
+            KillSwitchResult::Finished(()) => {
+                tracing::info!(
+                    "udp proxy at {peer} on port {port} task completed, exiting proxy task"
+                );
+            }
+        }
+    });
+    recv
+}
+
+async fn drive_udp_task(
+    send: tokio::sync::mpsc::Sender<anyhow::Result<ServeUpdate>>,
+    local_port: u16,
+    dest_port_map: Option<ServerPortMapString>,
+    endpoint: Endpoint,
+    peer: NodeId,
+    idle_timeout: Duration,
+    mut proxy_kill_switch_listener: ProxyKillSwitchListener,
+) {
+    let peer = FollowedPeer::new(peer);
+    let addr = format!("0.0.0.0:{local_port}");
+    if send.try_send(Ok(ServeUpdate::BindingUdp)).is_err() {
+        tracing::warn!("failed to send binding update");
+        return;
+    }
+    tracing::info!("binding udp socket at {addr}");
+    let socket = match UdpSocket::bind(&addr).await {
+        Ok(o) => Arc::new(o),
+        Err(e) => {
+            let _ = send.try_send(Err(anyhow::anyhow!(
+                "failed to bind udp socket at {addr}: {}",
+                display_chain(&e)
+            )));
+            tracing::warn!("failed to bind udp socket at {addr}: {}", display_chain(&e));
+            return;
+        }
+    };
+    if send.try_send(Ok(ServeUpdate::ListeningUdp)).is_err() {
+        tracing::warn!("failed to send listening UDP");
+        return;
+    }
+    let mut flows: HashMap<SocketAddr, tokio::sync::mpsc::Sender<Vec<u8>>> = HashMap::new();
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    let mut con_count = 0u64;
+    loop {
+        tokio::select! {
+            () = proxy_kill_switch_listener.killed() => {
+                tracing::info!("received kill signal, exiting udp task");
+                return;
+            }
+            udp_res = socket.recv_from(&mut buf) => {
+                let (len, source) = match udp_res {
+                    Ok(o) => o,
+                    Err(e) => {
+                        if send
+                            .try_send(Err(anyhow::anyhow!(
+                                "failed to receive udp datagram: {}",
+                                display_chain(&e)
+                            ))).is_err() {
+                            tracing::warn!("failed to send udp receive error");
+                        }
+                        return;
+                    }
+                };
+                let datagram = buf[..len].to_vec();
+                let datagram = if let Some(flow) = flows.get(&source) {
+                    match flow.try_send(datagram) {
+                        Ok(()) => None,
+                        Err(TrySendError::Full(_)) => {
+                            tracing::debug!("udp flow from {source} is congested, dropping datagram");
+                            None
+                        }
+                        // The flow has ended (idle or failed), start a new one
+                        Err(TrySendError::Closed(d)) => Some(d),
+                    }
+                } else {
+                    Some(datagram)
+                };
+                let Some(datagram) = datagram else {
+                    continue;
+                };
+                flows.retain(|_, flow| !flow.is_closed());
+                con_count += 1;
+                let con_id = ConId(con_count);
+                tracing::debug!("new udp flow from {source} for con_id={con_id}");
+                let (flow_send, flow_recv) = tokio::sync::mpsc::channel(FLOW_QUEUE_SIZE);
+                let _ = flow_send.try_send(datagram);
+                flows.insert(source, flow_send);
+                let Some(ks_c) = proxy_kill_switch_listener.duplicate() else {
+                    tracing::info!("received kill signal before udp flow was spawned");
+                    return;
+                };
+                tokio::task::spawn(run_udp_flow(
+                    UdpFlow {
+                        endpoint: endpoint.clone(),
+                        con_id,
+                        socket: socket.clone(),
+                        source,
+                        peer: peer.clone(),
+                        dest_port_map: dest_port_map.clone(),
+                        idle_timeout,
+                    },
+                    flow_recv,
+                    send.clone(),
+                    ks_c,
+                ));
+            }
+            () = send.closed() => {
+                tracing::debug!("updates receiver dropped");
+                return;
+            }
+        }
+    }
+}
+
+struct UdpFlow {
+    endpoint: Endpoint,
+    con_id: ConId,
+    socket: Arc<UdpSocket>,
+    source: SocketAddr,
+    peer: FollowedPeer,
+    dest_port_map: Option<ServerPortMapString>,
+    idle_timeout: Duration,
+}
+
+async fn run_udp_flow(
+    flow: UdpFlow,
+    mut datagrams: tokio::sync::mpsc::Receiver<Vec<u8>>,
+    sender: tokio::sync::mpsc::Sender<anyhow::Result<ServeUpdate>>,
+    mut proxy_kill_switch_listener: ProxyKillSwitchListener,
+) {
+    if sender
+        .send(Ok(ServeUpdate::IrohConnecting(flow.con_id)))
+        .await
+        .is_err()
+    {
+        return;
+    }
+    let node_id = flow.peer.get();
+    let KillSwitchResult::Finished(con_res) = proxy_kill_switch_listener
+        .if_not_killed(tokio::time::timeout(
+            Duration::from_millis(10_000),
+            flow.endpoint.connect(NodeAddr::new(node_id), ALPN),
+        ))
+        .await
+    else {
+        tracing::info!("received kill signal, exiting udp flow task");
+        return;
+    };
+    let con = match con_res {
+        Ok(Ok(con)) => con,
+        Ok(Err(e)) => {
+            let _ = sender.try_send(Err(anyhow::anyhow!(
+                "failed to connect to peer: {}",
+                display_chain(&e)
+            )));
+            tracing::warn!("failed to connect to peer: {}", display_chain(&e));
+            return;
+        }
+        Err(_e) => {
+            let _ = sender.try_send(Err(anyhow::anyhow!("failed to connect to peer timeout")));
+            tracing::warn!("failed to connect to peer, timeout");
+            return;
+        }
+    };
+    flow.peer
+        .spawn_watch_redirect(&con, node_id, sender.clone());
+    let _ = sender.try_send(Ok(ServeUpdate::UdpFlowOpened(flow.con_id)));
+    match proxy_kill_switch_listener
+        .if_not_killed(run_udp_connection(&flow, con, &mut datagrams))
+        .await
+    {
+        KillSwitchResult::Killed => {
+            tracing::info!("received kill signal, exiting udp flow task");
+            return;
+        }
+        KillSwitchResult::Finished(Ok(())) => {}
+        KillSwitchResult::Finished(Err(e)) => {
+            tracing::warn!("udp flow failed: {}", display_chain(&*e));
+            if sender
+                .try_send(Ok(ServeUpdate::ConnectionError(
+                    flow.con_id,
+                    anyhow::anyhow!("udp flow failed: {}", display_chain(&*e)),
+                )))
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+    let _ = sender.try_send(Ok(ServeUpdate::UdpFlowClosed(flow.con_id)));
+}
+
+async fn run_udp_connection(
+    flow: &UdpFlow,
+    downstream_connection: Connection,
+    datagrams: &mut tokio::sync::mpsc::Receiver<Vec<u8>>,
+) -> anyhow::Result<()> {
+    let (mut downstream_write, mut downstream_read) = downstream_connection
+        .open_bi()
+        .await
+        .context("failed to open udp flow stream")?;
+    let payload = if let Some(dpm) = &flow.dest_port_map {
+        dpm.as_bytes()
+    } else {
+        p2proxy_lib::proto::DEFAULT_ROUTE
+    };
+    downstream_write
+        .write_all(payload)
+        .await
+        .context("failed to write hello to upstream")?;
+    let activity = FlowActivity::new();
+    let res = tokio::select! {
+        res = local_to_remote(datagrams, &mut downstream_write, &activity) => res,
+        res = remote_to_local(&mut downstream_read, &flow.socket, flow.source, &activity) => res,
+        () = activity.idle(flow.idle_timeout) => {
+            tracing::debug!("udp flow from {} idle for {:?}, closing", flow.source, flow.idle_timeout);
+            Ok(())
+        }
+    };
+    if res.is_err() {
+        let _ = downstream_write.reset(p2proxy_lib::proto::GENERIC_QUIC_ERROR_CODE);
+        let _ = downstream_read.stop(p2proxy_lib::proto::GENERIC_QUIC_ERROR_CODE);
+    } else {
+        let _ = downstream_write.finish();
+        let _ = downstream_read.stop(p2proxy_lib::proto::QUIC_OK_ERROR_CODE);
+    }
+    res
+}
+
+async fn local_to_remote(
+    datagrams: &mut tokio::sync::mpsc::Receiver<Vec<u8>>,
+    downstream_write: &mut SendStream,
+    activity: &FlowActivity,
+) -> anyhow::Result<()> {
+    while let Some(datagram) = datagrams.recv().await {
+        activity.touch();
+        write_datagram(downstream_write, &datagram).await?;
+    }
+    Ok(())
+}
+
+async fn remote_to_local(
+    downstream_read: &mut RecvStream,
+    socket: &UdpSocket,
+    source: SocketAddr,
+    activity: &FlowActivity,
+) -> anyhow::Result<()> {
+    let mut buf = Box::new([0u8; MAX_DATAGRAM_SIZE]);
+    while let Some(len) = read_datagram(downstream_read, &mut buf).await? {
+        activity.touch();
+        socket
+            .send_to(&buf[..len], source)
+            .await
+            .context("failed to send datagram to local source")?;
+    }
+    tracing::debug!("remote finished udp flow from {source}");
+    Ok(())
+}
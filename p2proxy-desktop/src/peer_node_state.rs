@@ -108,12 +108,21 @@ impl Stream for PeerTcpNodeStream {
             ServeUpdate::BindingTcp => {
                 AppMessage::con_update(self.peer_id, "binding tcp".to_string())
             }
-            ServeUpdate::ListeningTcp => {
+            ServeUpdate::BindingUdp => {
+                AppMessage::con_update(self.peer_id, "binding udp".to_string())
+            }
+            ServeUpdate::ListeningTcp | ServeUpdate::ListeningUdp => {
                 AppMessage::PeerNodeState(PeerNodeStateMessage::ConnectionReady(self.peer_id))
             }
             ServeUpdate::AcceptedTcp(_o) => {
                 AppMessage::con_update(self.peer_id, "accepted tcp".to_string())
             }
+            ServeUpdate::UdpFlowOpened(_o) => {
+                AppMessage::con_update(self.peer_id, "udp flow opened".to_string())
+            }
+            ServeUpdate::UdpFlowClosed(_o) => {
+                AppMessage::con_update(self.peer_id, "udp flow closed".to_string())
+            }
             ServeUpdate::IrohConnecting(_o) => {
                 AppMessage::con_update(self.peer_id, "connecting".to_string())
             }
@@ -121,6 +130,10 @@ impl Stream for PeerTcpNodeStream {
                 self.peer_id,
                 format!("connection error: {}", display_chain(&*e)),
             ),
+            ServeUpdate::PeerRedirected { from, to } => AppMessage::con_update(
+                self.peer_id,
+                format!("{from} rotated its key, now connecting to {to}"),
+            ),
         };
         Poll::Ready(Some(msg))
     }
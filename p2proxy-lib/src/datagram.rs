@@ -0,0 +1,83 @@
+use crate::display_chain;
+use anyhow::Context;
+use iroh::endpoint::{ReadExactError, RecvStream, SendStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Largest datagram that can be relayed, the length prefix is a `u16`
+pub const MAX_DATAGRAM_SIZE: usize = u16::MAX as usize;
+
+/// A UDP flow is relayed over a single QUIC stream, each datagram is written
+/// as a big-endian `u16` length followed by the datagram itself.
+pub async fn write_datagram(send: &mut SendStream, datagram: &[u8]) -> anyhow::Result<()> {
+    let len = u16::try_from(datagram.len())
+        .with_context(|| format!("datagram too large to relay: {}", datagram.len()))?;
+    send.write_all(&len.to_be_bytes())
+        .await
+        .context("failed to write datagram length")?;
+    send.write_all(datagram)
+        .await
+        .context("failed to write datagram")
+}
+
+/// Reads the next datagram into `buf`, returning its length, or `None` if the stream was finished
+/// between datagrams.
+pub async fn read_datagram(
+    recv: &mut RecvStream,
+    buf: &mut [u8; MAX_DATAGRAM_SIZE],
+) -> anyhow::Result<Option<usize>> {
+    let mut len = [0u8; 2];
+    match recv.read_exact(&mut len).await {
+        Ok(()) => {}
+        Err(ReadExactError::FinishedEarly(0)) => return Ok(None),
+        Err(e) => {
+            anyhow::bail!("failed to read datagram length: {}", display_chain(&e));
+        }
+    }
+    let len = usize::from(u16::from_be_bytes(len));
+    recv.read_exact(&mut buf[..len])
+        .await
+        .context("failed to read datagram")?;
+    Ok(Some(len))
+}
+
+/// Tracks when a flow last saw a datagram, or a stream last moved bytes, in either direction
+pub struct FlowActivity {
+    started: Instant,
+    last_active_millis: AtomicU64,
+}
+
+impl FlowActivity {
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            last_active_millis: AtomicU64::new(0),
+        }
+    }
+
+    #[inline]
+    pub fn touch(&self) {
+        self.last_active_millis
+            .store(self.elapsed_millis(), Ordering::Relaxed);
+    }
+
+    /// Completes when there was no activity for `timeout`
+    pub async fn idle(&self, timeout: Duration) {
+        loop {
+            let idle_for = Duration::from_millis(
+                self.elapsed_millis()
+                    .saturating_sub(self.last_active_millis.load(Ordering::Relaxed)),
+            );
+            if idle_for >= timeout {
+                return;
+            }
+            tokio::time::sleep(timeout.saturating_sub(idle_for)).await;
+        }
+    }
+
+    #[inline]
+    fn elapsed_millis(&self) -> u64 {
+        u64::try_from(self.started.elapsed().as_millis()).unwrap_or(u64::MAX)
+    }
+}
@@ -1,5 +1,7 @@
+pub mod datagram;
 pub mod proto;
 pub mod proxy_copy_buf;
+pub mod rate_limit;
 
 pub struct ErrFmt<'a>(&'a dyn core::error::Error);
 
@@ -1,4 +1,5 @@
-use anyhow::bail;
+use anyhow::{Context, bail};
+use iroh::NodeId;
 use iroh::endpoint::VarInt;
 use std::borrow::Borrow;
 use std::fmt::Display;
@@ -11,9 +12,49 @@ pub const PING: &[u8; HEADER_LENGTH] = b"PINGPINGPINGPING";
 
 pub const DEFAULT_ROUTE: &[u8; HEADER_LENGTH] = b"9999999999999999";
 
+/// Sent by the server on a unidirectional stream, on connections to a node id that's being rotated out.
+/// Followed by the 32 bytes of the node id that replaces it.
+pub const REDIRECT: &[u8; HEADER_LENGTH] = b"REDIRECTREDIRECT";
+
+pub const REDIRECT_MESSAGE_LENGTH: usize = HEADER_LENGTH + 32;
+
 pub const QUIC_OK_ERROR_CODE: VarInt = VarInt::from_u32(0);
 pub const GENERIC_QUIC_ERROR_CODE: VarInt = VarInt::from_u32(1);
 pub const FORBIDDEN_QUIC_ERROR_CODE: VarInt = VarInt::from_u32(2);
+/// An operator closed the connection or stream on the server
+pub const KICKED_QUIC_ERROR_CODE: VarInt = VarInt::from_u32(3);
+/// The server is shutting down, connections are closed cleanly with this code once drained
+pub const SHUTDOWN_QUIC_ERROR_CODE: VarInt = VarInt::from_u32(4);
+/// The server refused the connection or stream because a limit was reached, retrying later may work
+pub const LIMITED_QUIC_ERROR_CODE: VarInt = VarInt::from_u32(5);
+/// The peer used up its traffic quota, streams are refused until the quota's period is over
+pub const QUOTA_QUIC_ERROR_CODE: VarInt = VarInt::from_u32(6);
+/// The stream went without traffic in either direction for its route's idle timeout
+pub const IDLE_TIMEOUT_QUIC_ERROR_CODE: VarInt = VarInt::from_u32(7);
+/// The stream was open for its route's maximum lifetime
+pub const MAX_LIFETIME_QUIC_ERROR_CODE: VarInt = VarInt::from_u32(8);
+/// The peer, or its address, is banned after repeated rejections. The close reason is when the ban runs out, Rfc3339.
+pub const BANNED_QUIC_ERROR_CODE: VarInt = VarInt::from_u32(9);
+
+#[must_use]
+pub fn encode_redirect(successor: &NodeId) -> [u8; REDIRECT_MESSAGE_LENGTH] {
+    let mut msg = [0u8; REDIRECT_MESSAGE_LENGTH];
+    msg[..HEADER_LENGTH].copy_from_slice(REDIRECT);
+    msg[HEADER_LENGTH..].copy_from_slice(successor.as_bytes());
+    msg
+}
+
+pub fn decode_redirect(msg: &[u8; REDIRECT_MESSAGE_LENGTH]) -> anyhow::Result<NodeId> {
+    let (header, node_id) = msg.split_at(HEADER_LENGTH);
+    if header != REDIRECT {
+        bail!(
+            "expected a redirect, got {}",
+            String::from_utf8_lossy(header)
+        );
+    }
+    let node_id: &[u8; 32] = node_id.try_into()?;
+    NodeId::from_bytes(node_id).context("redirect has an invalid node id")
+}
 
 #[repr(transparent)]
 #[derive(Eq, PartialEq, Hash, Debug, Clone, Default)]
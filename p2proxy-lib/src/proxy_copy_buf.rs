@@ -1,6 +1,10 @@
+use crate::datagram::FlowActivity;
 use crate::display_chain;
+use crate::rate_limit::Throttle;
 use anyhow::Context;
 use iroh::endpoint::{ConnectionError, ReadError, RecvStream, SendStream, VarInt, WriteError};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::tcp::{ReadHalf, WriteHalf};
 
@@ -8,6 +12,13 @@ pub struct BufferedCopy<const N: usize> {
     read_offset: usize,
     write_offset: usize,
     data: Box<[u8; N]>,
+    // Bytes written to the output are added to each, readable while the copy is running
+    counters: Vec<Arc<AtomicU64>>,
+    throttle: Throttle,
+    // Bytes the throttle let through that weren't written yet, kept if the write is cancelled
+    acquired: usize,
+    // Touched on every write, to tell when the copy went idle
+    activity: Option<Arc<FlowActivity>>,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -20,8 +31,27 @@ pub enum BufCopyError {
     QuicInternal,
     #[error("Quic closed")]
     QuicClosed(u64),
+    #[error("Quic kicked by server operator")]
+    QuicKicked,
+    #[error("Quic closed, server shutting down")]
+    QuicServerShutdown,
+    #[error("Quic refused, server limit reached")]
+    QuicLimited,
+    #[error("Quic refused, traffic quota exceeded")]
+    QuicQuotaExceeded,
+    #[error("Quic closed by the server, the stream was idle")]
+    QuicIdleTimeout,
+    #[error("Quic closed by the server, the stream reached its maximum lifetime")]
+    QuicMaxLifetime,
+    /// Holds when the ban runs out, as the server sent it
+    #[error("Quic connection refused, banned until {0}")]
+    QuicBanned(String),
+    /// The TCP (or unix socket) side reached end of file
     #[error("Tcp EOF")]
     TCPEoF,
+    /// The QUIC side finished its send half, a clean end of the stream
+    #[error("Quic EOF")]
+    QuicEoF,
     #[error(transparent)]
     Unactionable(#[from] anyhow::Error),
 }
@@ -32,15 +62,40 @@ impl BufCopyError {
             crate::proto::QUIC_OK_ERROR_CODE => Self::QuicClosed(var_int.into_inner()),
             crate::proto::GENERIC_QUIC_ERROR_CODE => Self::QuicInternal,
             crate::proto::FORBIDDEN_QUIC_ERROR_CODE => Self::QuicStreamForbidden,
+            crate::proto::KICKED_QUIC_ERROR_CODE => Self::QuicKicked,
+            crate::proto::SHUTDOWN_QUIC_ERROR_CODE => Self::QuicServerShutdown,
+            crate::proto::LIMITED_QUIC_ERROR_CODE => Self::QuicLimited,
+            crate::proto::QUOTA_QUIC_ERROR_CODE => Self::QuicQuotaExceeded,
+            crate::proto::IDLE_TIMEOUT_QUIC_ERROR_CODE => Self::QuicIdleTimeout,
+            crate::proto::MAX_LIFETIME_QUIC_ERROR_CODE => Self::QuicMaxLifetime,
             unk => Self::Unactionable(anyhow::anyhow!(
                 "quic stream stopped with unmapped code: {unk}",
             )),
         }
     }
+
+    /// The error for a connection the other side closed with `code` and `reason`
+    fn from_close_code(code: VarInt, reason: &[u8]) -> Self {
+        match code {
+            crate::proto::FORBIDDEN_QUIC_ERROR_CODE => Self::QuicConnectionForbidden,
+            crate::proto::KICKED_QUIC_ERROR_CODE => Self::QuicKicked,
+            crate::proto::SHUTDOWN_QUIC_ERROR_CODE => Self::QuicServerShutdown,
+            crate::proto::LIMITED_QUIC_ERROR_CODE => Self::QuicLimited,
+            crate::proto::QUOTA_QUIC_ERROR_CODE => Self::QuicQuotaExceeded,
+            crate::proto::IDLE_TIMEOUT_QUIC_ERROR_CODE => Self::QuicIdleTimeout,
+            crate::proto::MAX_LIFETIME_QUIC_ERROR_CODE => Self::QuicMaxLifetime,
+            crate::proto::BANNED_QUIC_ERROR_CODE => {
+                Self::QuicBanned(String::from_utf8_lossy(reason).into_owned())
+            }
+            code => Self::QuicClosed(code.into_inner()),
+        }
+    }
 }
 
 pub trait TcpOrQuicWrite {
     fn write(&mut self, buf: &[u8]) -> impl Future<Output = Result<usize, BufCopyError>> + Send;
+    /// Ends the write side cleanly, the other end reads end of file
+    fn shutdown(&mut self) -> impl Future<Output = Result<(), BufCopyError>> + Send;
 }
 
 impl TcpOrQuicWrite for WriteHalf<'_> {
@@ -50,6 +105,13 @@ impl TcpOrQuicWrite for WriteHalf<'_> {
             .context("failed to write to TCP")
             .map_err(BufCopyError::from)
     }
+
+    async fn shutdown(&mut self) -> Result<(), BufCopyError> {
+        AsyncWriteExt::shutdown(self)
+            .await
+            .context("failed to shutdown TCP write")
+            .map_err(BufCopyError::from)
+    }
 }
 
 impl TcpOrQuicWrite for SendStream {
@@ -59,11 +121,7 @@ impl TcpOrQuicWrite for SendStream {
             Ok(o) => Ok(o),
             Err(WriteError::Stopped(e)) => Err(BufCopyError::from_varint(e)),
             Err(WriteError::ConnectionLost(ConnectionError::ApplicationClosed(cc))) => {
-                if cc.error_code == crate::proto::FORBIDDEN_QUIC_ERROR_CODE {
-                    Err(BufCopyError::QuicConnectionForbidden)
-                } else {
-                    Err(BufCopyError::QuicClosed(cc.error_code.into_inner()))
-                }
+                Err(BufCopyError::from_close_code(cc.error_code, &cc.reason))
             }
             Err(e) => Err(BufCopyError::Unactionable(anyhow::anyhow!(
                 "write error: {}",
@@ -71,10 +129,33 @@ impl TcpOrQuicWrite for SendStream {
             ))),
         }
     }
+
+    async fn shutdown(&mut self) -> Result<(), BufCopyError> {
+        self.finish()
+            .context("failed to finish quic stream")
+            .map_err(BufCopyError::from)
+    }
+}
+
+#[cfg(unix)]
+impl TcpOrQuicWrite for tokio::net::unix::WriteHalf<'_> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, BufCopyError> {
+        AsyncWriteExt::write(self, buf)
+            .await
+            .context("failed to write to unix socket")
+            .map_err(BufCopyError::from)
+    }
+
+    async fn shutdown(&mut self) -> Result<(), BufCopyError> {
+        AsyncWriteExt::shutdown(self)
+            .await
+            .context("failed to shutdown unix socket write")
+            .map_err(BufCopyError::from)
+    }
 }
 
 pub trait TcpOrQuicRead {
-    fn read(&mut self, buf: &mut [u8]) -> impl Future<Output = Result<usize, BufCopyError>>;
+    fn read(&mut self, buf: &mut [u8]) -> impl Future<Output = Result<usize, BufCopyError>> + Send;
 }
 
 impl TcpOrQuicRead for ReadHalf<'_> {
@@ -90,17 +171,27 @@ impl TcpOrQuicRead for ReadHalf<'_> {
     }
 }
 
+#[cfg(unix)]
+impl TcpOrQuicRead for tokio::net::unix::ReadHalf<'_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, BufCopyError> {
+        let bytes = AsyncReadExt::read(self, buf)
+            .await
+            .context("failed to read from unix socket")
+            .map_err(BufCopyError::from)?;
+        if bytes == 0 {
+            return Err(BufCopyError::TCPEoF);
+        }
+        Ok(bytes)
+    }
+}
+
 impl TcpOrQuicRead for RecvStream {
     async fn read(&mut self, buf: &mut [u8]) -> Result<usize, BufCopyError> {
         match RecvStream::read(self, buf).await {
             Ok(Some(bytes)) => Ok(bytes),
-            Ok(None) => Err(BufCopyError::Unactionable(anyhow::anyhow!("quic EOF"))),
+            Ok(None) => Err(BufCopyError::QuicEoF),
             Err(ReadError::ConnectionLost(ConnectionError::ApplicationClosed(cc))) => {
-                if cc.error_code == crate::proto::FORBIDDEN_QUIC_ERROR_CODE {
-                    Err(BufCopyError::QuicConnectionForbidden)
-                } else {
-                    Err(BufCopyError::QuicClosed(cc.error_code.into_inner()))
-                }
+                Err(BufCopyError::from_close_code(cc.error_code, &cc.reason))
             }
             Err(ReadError::Reset(code)) => Err(BufCopyError::from_varint(code)),
             Err(e) => Err(BufCopyError::Unactionable(anyhow::anyhow!(
@@ -118,9 +209,48 @@ impl<const N: usize> BufferedCopy<N> {
             read_offset: 0,
             write_offset: 0,
             data: Box::new([0; N]),
+            counters: Vec::new(),
+            throttle: Throttle::default(),
+            acquired: 0,
+            activity: None,
+        }
+    }
+
+    /// Like [`BufferedCopy::new`], but adds every written byte to `counter`
+    #[must_use]
+    pub fn new_counted(counter: Arc<AtomicU64>) -> Self {
+        Self {
+            read_offset: 0,
+            write_offset: 0,
+            data: Box::new([0; N]),
+            counters: vec![counter],
+            throttle: Throttle::default(),
+            acquired: 0,
+            activity: None,
         }
     }
 
+    /// Adds every written byte to `counter` as well
+    #[must_use]
+    pub fn with_counter(mut self, counter: Arc<AtomicU64>) -> Self {
+        self.counters.push(counter);
+        self
+    }
+
+    /// Touches `activity` on every write
+    #[must_use]
+    pub fn with_activity(mut self, activity: Arc<FlowActivity>) -> Self {
+        self.activity = Some(activity);
+        self
+    }
+
+    /// Holds back writes to the output until the throttle allows them
+    #[must_use]
+    pub fn with_throttle(mut self, throttle: Throttle) -> Self {
+        self.throttle = throttle;
+        self
+    }
+
     // Cancel safe copy
     pub async fn copy(
         &mut self,
@@ -131,13 +261,28 @@ impl<const N: usize> BufferedCopy<N> {
             let needs_copy = self.write_offset - self.read_offset;
             if needs_copy > 0 {
                 let sect = &self.data[self.read_offset..self.write_offset];
+                let sect = if self.throttle.is_unlimited() {
+                    sect
+                } else {
+                    if self.acquired == 0 {
+                        self.acquired = self.throttle.acquire(sect.len()).await;
+                    }
+                    &sect[..self.acquired.min(sect.len())]
+                };
                 let written = output.write(sect).await?;
+                self.acquired = self.acquired.saturating_sub(written);
                 if written == 0 && !sect.is_empty() {
                     return Err(BufCopyError::Unactionable(anyhow::anyhow!(
                         "failed to write, write end closed"
                     )));
                 }
                 self.read_offset += written;
+                for counter in &self.counters {
+                    counter.fetch_add(written as u64, Ordering::Relaxed);
+                }
+                if let Some(activity) = &self.activity {
+                    activity.touch();
+                }
                 if self.read_offset == self.write_offset {
                     self.read_offset = 0;
                     self.write_offset = 0;
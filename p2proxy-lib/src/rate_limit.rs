@@ -0,0 +1,167 @@
+#[cfg(test)]
+mod test;
+
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+// Tokio's clock, so that time can be paused and advanced in tests
+use tokio::time::Instant;
+
+/// The most bytes let through at once, so that a large burst doesn't starve the other streams sharing a bucket
+const MAX_CHUNK: u64 = 16 * 1024;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// A sustained rate, and how many bytes can go through at once after being idle
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct BandwidthLimit {
+    pub bytes_per_sec: u64,
+    pub burst_bytes: u64,
+}
+
+/// Tokens are bytes, refilled at the limit's rate up to its burst.
+/// Takes can overdraw it, the debt is paid off before anything else goes through.
+#[derive(Debug)]
+pub struct TokenBucket {
+    limit: BandwidthLimit,
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: i128,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    /// Starts full
+    #[must_use]
+    pub fn new(limit: BandwidthLimit) -> Self {
+        Self {
+            limit,
+            state: Mutex::new(BucketState {
+                tokens: i128::from(limit.burst_bytes),
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn limit(&self) -> BandwidthLimit {
+        self.limit
+    }
+
+    /// How long until `bytes` tokens are available, zero if they already are
+    fn wait_for(&self, bytes: u64, now: Instant) -> Duration {
+        let mut state = self.lock();
+        self.refill(&mut state, now);
+        let deficit = i128::from(bytes) - state.tokens;
+        if deficit <= 0 {
+            return Duration::ZERO;
+        }
+        let nanos = (deficit.unsigned_abs() * NANOS_PER_SEC)
+            .div_ceil(u128::from(self.limit.bytes_per_sec.max(1)));
+        Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::MAX))
+    }
+
+    fn take(&self, bytes: u64) {
+        self.lock().tokens -= i128::from(bytes);
+    }
+
+    fn refill(&self, state: &mut BucketState, now: Instant) {
+        let rate = u128::from(self.limit.bytes_per_sec);
+        let elapsed = now.saturating_duration_since(state.refilled).as_nanos();
+        let new_tokens = elapsed * rate / NANOS_PER_SEC;
+        if new_tokens == 0 {
+            return;
+        }
+        let burst = i128::from(self.limit.burst_bytes);
+        let tokens = state
+            .tokens
+            .saturating_add(i128::try_from(new_tokens).unwrap_or(i128::MAX));
+        if tokens >= burst {
+            state.tokens = burst;
+            state.refilled = now;
+        } else {
+            state.tokens = tokens;
+            // Only advance by the time the whole tokens took, so fractions aren't lost between refills
+            let used = new_tokens * NANOS_PER_SEC / rate.max(1);
+            state.refilled += Duration::from_nanos(u64::try_from(used).unwrap_or(u64::MAX));
+        }
+    }
+
+    #[inline]
+    fn lock(&self) -> std::sync::MutexGuard<'_, BucketState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// The buckets that apply to one direction of a stream. Bytes only go through once every bucket allows them,
+/// so the strictest limit wins. Without buckets nothing is throttled.
+#[derive(Debug, Clone, Default)]
+pub struct Throttle {
+    buckets: Vec<Arc<TokenBucket>>,
+}
+
+impl Throttle {
+    pub fn push(&mut self, bucket: Arc<TokenBucket>) {
+        self.buckets.push(bucket);
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn is_unlimited(&self) -> bool {
+        self.buckets.is_empty()
+    }
+
+    /// Waits until some of `bytes` can go through, and returns how many.
+    /// Cancel safe, nothing is taken until the wait is over.
+    pub async fn acquire(&self, bytes: usize) -> usize {
+        if self.is_unlimited() || bytes == 0 {
+            return bytes;
+        }
+        let chunk = self
+            .buckets
+            .iter()
+            .map(|b| b.limit.burst_bytes)
+            .fold(MAX_CHUNK.min(bytes as u64), u64::min)
+            .max(1);
+        self.wait_and_take(chunk, chunk).await;
+        usize::try_from(chunk).unwrap_or(bytes)
+    }
+
+    /// Waits until all of `bytes` can go through at once, for data that can't be split, like datagrams.
+    /// More bytes than a bucket's burst overdraw it.
+    pub async fn acquire_all(&self, bytes: usize) {
+        if self.is_unlimited() {
+            return;
+        }
+        let bytes = bytes as u64;
+        let burst = self
+            .buckets
+            .iter()
+            .map(|b| b.limit.burst_bytes)
+            .min()
+            .unwrap_or(bytes);
+        self.wait_and_take(bytes.min(burst), bytes).await;
+    }
+
+    async fn wait_and_take(&self, wait_for: u64, take: u64) {
+        loop {
+            let now = Instant::now();
+            let wait = self
+                .buckets
+                .iter()
+                .map(|b| b.wait_for(wait_for, now))
+                .max()
+                .unwrap_or(Duration::ZERO);
+            if wait.is_zero() {
+                break;
+            }
+            tokio::time::sleep(wait).await;
+        }
+        for bucket in &self.buckets {
+            bucket.take(take);
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use crate::rate_limit::{BandwidthLimit, MAX_CHUNK, Throttle, TokenBucket};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+fn bucket(bytes_per_sec: u64, burst_bytes: u64) -> Arc<TokenBucket> {
+    Arc::new(TokenBucket::new(BandwidthLimit {
+        bytes_per_sec,
+        burst_bytes,
+    }))
+}
+
+fn throttle(buckets: &[&Arc<TokenBucket>]) -> Throttle {
+    let mut throttle = Throttle::default();
+    for bucket in buckets {
+        throttle.push((*bucket).clone());
+    }
+    throttle
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_refill_keeps_fractions() {
+    let bucket = bucket(3, 100);
+    bucket.take(100);
+    // 1.5 tokens, the half is carried over to the next refill
+    tokio::time::advance(Duration::from_millis(500)).await;
+    assert_eq!(Duration::ZERO, bucket.wait_for(1, Instant::now()));
+    tokio::time::advance(Duration::from_millis(500)).await;
+    assert_eq!(Duration::ZERO, bucket.wait_for(3, Instant::now()));
+    assert_eq!(
+        Duration::from_nanos(333_333_334),
+        bucket.wait_for(4, Instant::now())
+    );
+
+    // Never refills past its burst, however long it's idle
+    tokio::time::advance(Duration::from_secs(3600)).await;
+    assert_eq!(Duration::ZERO, bucket.wait_for(100, Instant::now()));
+    assert_eq!(
+        Duration::from_nanos(333_333_334),
+        bucket.wait_for(101, Instant::now())
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_overdraft_is_paid_off_first() {
+    let bucket = bucket(1000, 1000);
+    let throttle = throttle(&[&bucket]);
+    throttle.acquire_all(3000).await;
+    assert_eq!(
+        Duration::from_millis(2001),
+        bucket.wait_for(1, Instant::now())
+    );
+    let start = Instant::now();
+    assert_eq!(1, throttle.acquire(1).await);
+    assert_eq!(Duration::from_millis(2001), start.elapsed());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_acquire_chunks() {
+    let unlimited = Throttle::default();
+    assert_eq!(100_000, unlimited.acquire(100_000).await);
+
+    let large = bucket(1024 * 1024, 1024 * 1024);
+    let throttle = throttle(&[&large]);
+    assert_eq!(0, throttle.acquire(0).await);
+    assert_eq!(10, throttle.acquire(10).await);
+    assert_eq!(
+        usize::try_from(MAX_CHUNK).unwrap(),
+        throttle.acquire(100_000).await
+    );
+
+    // Never more than the burst, the rest waits for a refill
+    let small = bucket(4096, 4096);
+    let throttle = self::throttle(&[&small]);
+    let start = Instant::now();
+    assert_eq!(4096, throttle.acquire(100_000).await);
+    assert_eq!(Duration::ZERO, start.elapsed());
+    assert_eq!(4096, throttle.acquire(100_000).await);
+    assert_eq!(Duration::from_secs(1), start.elapsed());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_acquire_all_above_burst() {
+    let bucket = bucket(1000, 1000);
+    let throttle = throttle(&[&bucket]);
+    let start = Instant::now();
+    // Only waits for a full bucket, then overdraws it
+    throttle.acquire_all(2500).await;
+    assert_eq!(Duration::ZERO, start.elapsed());
+    throttle.acquire_all(500).await;
+    assert_eq!(Duration::from_secs(2), start.elapsed());
+    throttle.acquire_all(2500).await;
+    assert_eq!(Duration::from_secs(3), start.elapsed());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_stricter_bucket_wins() {
+    let route = bucket(1000, 1000);
+    let peer = bucket(100, 100);
+    let throttle = throttle(&[&route, &peer]);
+    let start = Instant::now();
+    assert_eq!(100, throttle.acquire(10_000).await);
+    assert_eq!(100, throttle.acquire(10_000).await);
+    assert_eq!(Duration::from_secs(1), start.elapsed());
+    // Both buckets were taken from
+    assert_eq!(
+        Duration::from_millis(100),
+        route.wait_for(1000, Instant::now())
+    );
+    assert_eq!(Duration::from_secs(1), peer.wait_for(100, Instant::now()));
+
+    // The order of the buckets doesn't matter
+    let throttle = self::throttle(&[&peer, &route]);
+    assert_eq!(100, throttle.acquire(10_000).await);
+    assert_eq!(Duration::from_secs(2), start.elapsed());
+}
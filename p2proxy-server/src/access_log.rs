@@ -0,0 +1,966 @@
+use crate::ban::BanKey;
+use crate::limits::LimitExceeded;
+use crate::quota::QuotaExceeded;
+use anyhow::Context;
+use iroh::NodeId;
+use p2proxy_lib::display_chain;
+use p2proxy_lib::proxy_copy_buf::BufCopyError;
+use std::fmt::{Display, Formatter};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{RecvTimeoutError, TrySendError};
+use std::time::{Duration, Instant};
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+
+/// Local syslog socket
+#[cfg(unix)]
+const SYSLOG_SOCKET: &str = "/dev/log";
+/// Facility daemon (3), severity informational (6)
+#[cfg(unix)]
+const SYSLOG_PRIORITY: u8 = 3 * 8 + 6;
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    /// Tab separated, human readable
+    #[default]
+    Tsv,
+    /// One json object per line
+    Json,
+    Logfmt,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum AccessLogSink {
+    File(PathBuf),
+    Stdout,
+    #[cfg(unix)]
+    Syslog,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AccessLogConfig {
+    pub format: AccessLogFormat,
+    pub sink: AccessLogSink,
+    /// Only used by the file sink
+    pub rotation: Option<AccessLogRotation>,
+}
+
+/// Rotates `<path>` to `<path>.1`, shifting older files up to `<path>.<retain>`, and drops anything older
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct AccessLogRotation {
+    pub max_bytes: Option<u64>,
+    pub daily: bool,
+    pub retain: usize,
+}
+
+/// Entries that can be queued up while the writer is busy, past this they're counted as dropped.
+const ACCESS_LOG_QUEUE_SIZE: usize = 16 * 1024;
+/// How often the writer wakes up to report drops and pick up reloads if no entries are coming in
+const ACCESS_LOG_WAKE_INTERVAL: Duration = Duration::from_secs(5);
+/// How often a flush retries while the queue is full
+const ACCESS_LOG_FLUSH_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Logging never blocks or fails the caller, if the writer can't keep up the entry is counted as dropped,
+/// and the count is written to the access log once it catches up.
+#[derive(Debug, Clone)]
+pub struct AccessLogHandle {
+    chan: Option<std::sync::mpsc::SyncSender<AccessLogWriterMessage>>,
+    shared: Arc<WriterShared>,
+    hooks: AccessHooks,
+    /// The identity that entries logged through this handle are for, if any
+    identity: Option<Arc<str>>,
+}
+
+/// Called for every access log event, whether or not an access log is configured.
+/// Runs on the task that produced the event, so it shouldn't block.
+/// `identity` is the name of the identity the peer connected to, and `address` the peer's address,
+/// `None` for events that aren't tied to one, like an operator lifting a ban.
+pub trait AccessHook: Send + Sync + 'static {
+    fn on_event(&self, identity: Option<&str>, address: Option<SocketAddr>, event: &AccessEvent);
+}
+
+impl<F> AccessHook for F
+where
+    F: Fn(Option<&str>, Option<SocketAddr>, &AccessEvent) + Send + Sync + 'static,
+{
+    #[inline]
+    fn on_event(&self, identity: Option<&str>, address: Option<SocketAddr>, event: &AccessEvent) {
+        self(identity, address, event);
+    }
+}
+
+#[derive(Clone, Default)]
+struct AccessHooks(Arc<Vec<Arc<dyn AccessHook>>>);
+
+impl std::fmt::Debug for AccessHooks {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "AccessHooks({})", self.0.len())
+    }
+}
+
+#[derive(Debug, Default)]
+struct WriterShared {
+    dropped: AtomicU64,
+    reload: AtomicBool,
+}
+
+pub enum AccessLogWriterMessage {
+    IncomingConnection(IncomingConnection),
+    /// Wakes the writer up to reload
+    ReloadFile,
+    /// Acknowledged once every entry queued before it is written
+    Flush(std::sync::mpsc::SyncSender<()>),
+}
+
+impl AccessLogHandle {
+    #[must_use]
+    pub fn maybe_spawn(cfg: Option<AccessLogConfig>) -> Self {
+        let shared = Arc::new(WriterShared::default());
+        if let Some(cfg) = cfg {
+            let (chan, receiver) = std::sync::mpsc::sync_channel(ACCESS_LOG_QUEUE_SIZE);
+            let writer_shared = shared.clone();
+            std::thread::spawn(move || {
+                access_log_writer_outer_loop(&receiver, &writer_shared, &cfg);
+            });
+            Self {
+                chan: Some(chan),
+                shared,
+                hooks: AccessHooks::default(),
+                identity: None,
+            }
+        } else {
+            Self {
+                chan: None,
+                shared,
+                hooks: AccessHooks::default(),
+                identity: None,
+            }
+        }
+    }
+
+    /// Adds a hook that's called for every event, before it's handed to the writer
+    #[must_use]
+    pub fn with_hook(mut self, hook: impl AccessHook) -> Self {
+        let mut hooks = self.hooks.0.as_ref().clone();
+        hooks.push(Arc::new(hook));
+        self.hooks = AccessHooks(Arc::new(hooks));
+        self
+    }
+
+    /// A handle that logs to the same access log, with every entry naming `identity`.
+    /// Tells identities apart when several of them share one access log.
+    #[must_use]
+    pub fn for_identity(&self, identity: &str) -> Self {
+        Self {
+            identity: Some(Arc::from(identity)),
+            ..self.clone()
+        }
+    }
+
+    pub fn log_rejected_missing_node_id(&self, address: SocketAddr) {
+        self.send(Some(address), AccessEvent::MissingNodeId);
+    }
+
+    pub fn log_rejected_not_allowed_at(&self, address: SocketAddr, node_id: NodeId, port: String) {
+        self.send(
+            Some(address),
+            AccessEvent::RejectedNotAllowedPort(node_id, port),
+        );
+    }
+
+    pub fn log_rejected_default_not_present(&self, address: SocketAddr, node_id: NodeId) {
+        self.send(Some(address), AccessEvent::RejectedDefaultRoute(node_id));
+    }
+
+    pub fn log_rejected_unknown_port_mapping(
+        &self,
+        address: SocketAddr,
+        node_id: NodeId,
+        mapping: String,
+    ) {
+        self.send(
+            Some(address),
+            AccessEvent::RejectedUnknownPortMapping(node_id, mapping),
+        );
+    }
+
+    pub fn log_rejected_garbage_port_mapping(
+        &self,
+        address: SocketAddr,
+        node_id: NodeId,
+        mapping: [u8; 16],
+    ) {
+        self.send(
+            Some(address),
+            AccessEvent::RejectedGarbagePortMapping(node_id, mapping),
+        );
+    }
+
+    pub fn log_rejected_expired_grant(&self, address: SocketAddr, node_id: NodeId, port: String) {
+        self.send(
+            Some(address),
+            AccessEvent::RejectedExpiredGrant(node_id, port),
+        );
+    }
+
+    pub fn log_rejected_outside_schedule(
+        &self,
+        address: SocketAddr,
+        node_id: NodeId,
+        port: String,
+    ) {
+        self.send(
+            Some(address),
+            AccessEvent::RejectedOutsideSchedule(node_id, port),
+        );
+    }
+
+    pub fn log_rejected_limit(&self, address: SocketAddr, node_id: NodeId, limit: LimitExceeded) {
+        self.send(Some(address), AccessEvent::RejectedLimit(node_id, limit));
+    }
+
+    pub fn log_rejected_quota(&self, address: SocketAddr, node_id: NodeId, quota: QuotaExceeded) {
+        self.send(Some(address), AccessEvent::RejectedQuota(node_id, quota));
+    }
+
+    pub fn log_rejected_banned(&self, address: SocketAddr, node_id: NodeId, ban: BanKey) {
+        self.send(Some(address), AccessEvent::RejectedBanned(node_id, ban));
+    }
+
+    pub fn log_banned(
+        &self,
+        address: SocketAddr,
+        node_id: NodeId,
+        ban: BanKey,
+        ban_time: Duration,
+    ) {
+        self.send(Some(address), AccessEvent::Banned(node_id, ban, ban_time));
+    }
+
+    pub fn log_accepted(&self, address: SocketAddr, node_id: NodeId) {
+        self.send(Some(address), AccessEvent::Accepted(node_id));
+    }
+
+    pub fn log_stream_closed(&self, address: SocketAddr, stream: ClosedStream) {
+        self.send(Some(address), AccessEvent::StreamClosed(Box::new(stream)));
+    }
+
+    /// An operator lifted the ban before it ran out
+    pub fn log_ban_lifted(&self, ban: BanKey) {
+        self.send(None, AccessEvent::BanLifted(ban));
+    }
+
+    /// Waits for the entries logged so far to be written, for at most `timeout`.
+    /// Blocks the calling thread, returns whether they were written in time.
+    /// The writer thread itself exits once every handle is dropped.
+    #[must_use]
+    pub fn flush(&self, timeout: Duration) -> bool {
+        let Some(chan) = &self.chan else {
+            return true;
+        };
+        let deadline = Instant::now() + timeout;
+        let (ack, acked) = std::sync::mpsc::sync_channel(1);
+        let mut msg = AccessLogWriterMessage::Flush(ack);
+        loop {
+            match chan.try_send(msg) {
+                Ok(()) => break,
+                Err(TrySendError::Full(retry)) if Instant::now() < deadline => {
+                    msg = retry;
+                    std::thread::sleep(ACCESS_LOG_FLUSH_RETRY_INTERVAL);
+                }
+                Err(_) => return false,
+            }
+        }
+        acked
+            .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            .is_ok()
+    }
+
+    /// Reopens the file, picked up by the writer between entries
+    pub fn reload_file(&self) {
+        let Some(chan) = &self.chan else {
+            return;
+        };
+        self.shared.reload.store(true, Ordering::Relaxed);
+        // If the queue is full the writer is awake anyway
+        let _ = chan.try_send(AccessLogWriterMessage::ReloadFile);
+    }
+
+    fn send(&self, address: Option<SocketAddr>, result: AccessEvent) {
+        for hook in self.hooks.0.iter() {
+            hook.on_event(self.identity.as_deref(), address, &result);
+        }
+        let Some(chan) = &self.chan else {
+            return;
+        };
+        if chan
+            .try_send(AccessLogWriterMessage::IncomingConnection(
+                IncomingConnection {
+                    timestamp: timestamp_try_local_offset(),
+                    identity: self.identity.clone(),
+                    address,
+                    result,
+                },
+            ))
+            .is_err()
+        {
+            self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// It's generally easier to read logs in local offset (in my opinion).
+// So try getting the local offset first, if that doesn't work, use UTC
+fn timestamp_try_local_offset() -> OffsetDateTime {
+    OffsetDateTime::now_local()
+        .ok()
+        .unwrap_or_else(OffsetDateTime::now_utc)
+}
+
+pub struct IncomingConnection {
+    timestamp: time::OffsetDateTime,
+    identity: Option<Arc<str>>,
+    address: Option<SocketAddr>,
+    result: AccessEvent,
+}
+
+/// Something that happened to a connection or stream, the same events that end up in the access log
+pub enum AccessEvent {
+    /// The remote's node id couldn't be extracted, the connection was closed
+    MissingNodeId,
+    Accepted(NodeId),
+    /// The peer sent a header that isn't a valid route name
+    RejectedGarbagePortMapping(NodeId, [u8; 16]),
+    /// The peer asked for a route that doesn't exist
+    RejectedUnknownPortMapping(NodeId, String),
+    /// The peer asked for a route it isn't allowed on
+    RejectedNotAllowedPort(NodeId, String),
+    /// The peer asked for the default route, which doesn't exist or doesn't allow it
+    RejectedDefaultRoute(NodeId),
+    /// The peer asked for a route it was allowed on, until its grant expired
+    RejectedExpiredGrant(NodeId, String),
+    /// The peer asked for a route it's allowed on, but not at this time of the week
+    RejectedOutsideSchedule(NodeId, String),
+    /// A connection or stream from the peer would have exceeded a limit
+    RejectedLimit(NodeId, LimitExceeded),
+    RejectedQuota(NodeId, QuotaExceeded),
+    /// The peer, or its address, is banned
+    RejectedBanned(NodeId, BanKey),
+    /// A rejection of the peer's stream got it, or its address, banned for a while
+    Banned(NodeId, BanKey, Duration),
+    /// An operator lifted the ban of a peer, or an address, before it ran out
+    BanLifted(BanKey),
+    StreamClosed(Box<ClosedStream>),
+}
+
+/// A stream that was routed to a target, logged when it ends
+pub struct ClosedStream {
+    pub node_id: NodeId,
+    pub route: String,
+    /// If the peer asked for the default route rather than naming `route`
+    pub default_route: bool,
+    pub downstream: String,
+    pub bytes_upstream_to_downstream: u64,
+    pub bytes_downstream_to_upstream: u64,
+    pub duration: Duration,
+    pub reason: StreamCloseReason,
+}
+
+/// Why a proxied stream ended
+#[derive(Debug, Clone)]
+pub enum StreamCloseReason {
+    /// The downstream target closed its end
+    DownstreamEof,
+    /// The peer finished or closed the stream
+    UpstreamClosed,
+    /// The peer reset the stream with an error code
+    UpstreamError,
+    /// Closed through the admin socket
+    Kicked,
+    /// Went without traffic for its idle timeout
+    IdleTimeout,
+    /// Was open for the route's maximum lifetime
+    MaxLifetime,
+    /// The peer used up its quota while the stream was open
+    QuotaExceeded,
+    /// The server side is shutting down
+    Shutdown,
+    Error(String),
+}
+
+impl StreamCloseReason {
+    #[must_use]
+    pub fn from_buf_copy_error(e: &BufCopyError) -> Self {
+        match e {
+            BufCopyError::TCPEoF => Self::DownstreamEof,
+            BufCopyError::QuicClosed(_) | BufCopyError::QuicEoF => Self::UpstreamClosed,
+            BufCopyError::QuicConnectionForbidden
+            | BufCopyError::QuicStreamForbidden
+            | BufCopyError::QuicInternal
+            | BufCopyError::QuicLimited
+            | BufCopyError::QuicBanned(_) => Self::UpstreamError,
+            BufCopyError::QuicQuotaExceeded => Self::QuotaExceeded,
+            BufCopyError::QuicKicked => Self::Kicked,
+            BufCopyError::QuicIdleTimeout => Self::IdleTimeout,
+            BufCopyError::QuicMaxLifetime => Self::MaxLifetime,
+            BufCopyError::QuicServerShutdown => Self::Shutdown,
+            BufCopyError::Unactionable(e) => Self::Error(display_chain(&**e).to_string()),
+        }
+    }
+}
+
+impl Display for StreamCloseReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::DownstreamEof => f.write_str("downstream-eof"),
+            Self::UpstreamClosed => f.write_str("upstream-closed"),
+            Self::UpstreamError => f.write_str("upstream-error"),
+            Self::Kicked => f.write_str("kicked"),
+            Self::IdleTimeout => f.write_str("idle-timeout"),
+            Self::MaxLifetime => f.write_str("max-lifetime"),
+            Self::QuotaExceeded => f.write_str("quota-exceeded"),
+            Self::Shutdown => f.write_str("shutdown"),
+            Self::Error(e) => write!(f, "error: {e}"),
+        }
+    }
+}
+
+impl IncomingConnection {
+    fn render(&self, format: AccessLogFormat) -> anyhow::Result<String> {
+        let timestamp = self
+            .timestamp
+            .format(&Rfc3339)
+            .context("failed to format timestamp")?;
+        match format {
+            AccessLogFormat::Tsv => Ok(self.render_tsv(&timestamp)),
+            AccessLogFormat::Json => {
+                serde_json::to_string(&self.fields(timestamp)).context("failed to serialize json")
+            }
+            AccessLogFormat::Logfmt => Ok(self.fields(timestamp).to_logfmt()),
+        }
+    }
+
+    fn render_tsv(&self, timestamp: &str) -> String {
+        // Only handles made with `for_identity` add the identity column, the layout is unchanged without one
+        let timestamp = match &self.identity {
+            Some(identity) => format!("{timestamp}\t{identity}"),
+            None => timestamp.to_string(),
+        };
+        let address = self
+            .address
+            .map_or_else(|| "-".to_string(), |address| address.to_string());
+        match &self.result {
+            AccessEvent::MissingNodeId => {
+                format!("{timestamp}\t[{address}]\tREJECTED\tCould not extract node id")
+            }
+            AccessEvent::RejectedGarbagePortMapping(node, port_mapping) => format!(
+                "{timestamp}\t[{address}]\t{node}\tREJECTED\tNode attempted un-parseable port mapping: '{}'",
+                String::from_utf8_lossy(port_mapping)
+            ),
+            AccessEvent::RejectedUnknownPortMapping(node, port_mapping) => format!(
+                "{timestamp}\t[{address}]\t{node}\tREJECTED\tNode attempted missing port map: '{port_mapping}'"
+            ),
+            AccessEvent::RejectedNotAllowedPort(node, port_mapping) => format!(
+                "{timestamp}\t[{address}]\t{node}\tREJECTED\tNode not approved for port map: '{port_mapping}'"
+            ),
+            AccessEvent::RejectedDefaultRoute(node) => {
+                format!(
+                    "{timestamp}\t[{address}]\t{node}\tREJECTED\tNode wanted missing default route"
+                )
+            }
+            AccessEvent::RejectedExpiredGrant(node, port_mapping) => format!(
+                "{timestamp}\t[{address}]\t{node}\tREJECTED\tNode's grant for port map expired: '{port_mapping}'"
+            ),
+            AccessEvent::RejectedOutsideSchedule(node, port_mapping) => format!(
+                "{timestamp}\t[{address}]\t{node}\tREJECTED\tNode outside its schedule for port map: '{port_mapping}'"
+            ),
+            AccessEvent::RejectedLimit(node, limit) => {
+                format!("{timestamp}\t[{address}]\t{node}\tREJECTED\tLimit exceeded: {limit}")
+            }
+            AccessEvent::RejectedQuota(node, quota) => {
+                format!("{timestamp}\t[{address}]\t{node}\tREJECTED\tQuota exceeded: {quota}")
+            }
+            AccessEvent::RejectedBanned(node, ban) => {
+                format!("{timestamp}\t[{address}]\t{node}\tREJECTED\tBanned: {ban}")
+            }
+            AccessEvent::Banned(node, ban, ban_time) => format!(
+                "{timestamp}\t[{address}]\t{node}\tBANNED\t{ban} banned for {}s after repeated rejections",
+                ban_time.as_secs()
+            ),
+            AccessEvent::BanLifted(ban) => {
+                format!("{timestamp}\t[{address}]\t-\tUNBANNED\t{ban} ban lifted by an operator")
+            }
+            AccessEvent::StreamClosed(stream) => format!(
+                "{timestamp}\t[{address}]\t{}\tCLOSED\troute='{}'{}\tdownstream={}\tto_downstream={}B\tto_upstream={}B\tduration={:.3}s\treason={}",
+                stream.node_id,
+                stream.route,
+                if stream.default_route {
+                    " (default)"
+                } else {
+                    ""
+                },
+                stream.downstream,
+                stream.bytes_upstream_to_downstream,
+                stream.bytes_downstream_to_upstream,
+                stream.duration.as_secs_f64(),
+                stream.reason,
+            ),
+            AccessEvent::Accepted(node) => {
+                format!("{timestamp}\t[{address}]\t{node}\tACCEPTED\tNode connected")
+            }
+        }
+    }
+
+    fn fields(&self, timestamp: String) -> Fields {
+        let mut fields = Fields(vec![("timestamp", FieldValue::Str(timestamp))]);
+        if let Some(identity) = &self.identity {
+            fields.push("identity", identity.to_string());
+        }
+        if let Some(address) = self.address {
+            fields.push("address", address.to_string());
+        }
+        match &self.result {
+            AccessEvent::MissingNodeId => {
+                fields.push("event", "rejected");
+                fields.push("reason", "missing-node-id");
+            }
+            AccessEvent::RejectedGarbagePortMapping(node, port_mapping) => {
+                fields.push("event", "rejected");
+                fields.push("node_id", node.to_string());
+                fields.push("reason", "garbage-port-mapping");
+                fields.push(
+                    "port_mapping",
+                    String::from_utf8_lossy(port_mapping).into_owned(),
+                );
+            }
+            AccessEvent::RejectedUnknownPortMapping(node, port_mapping) => {
+                fields.push("event", "rejected");
+                fields.push("node_id", node.to_string());
+                fields.push("reason", "unknown-port-mapping");
+                fields.push("port_mapping", port_mapping.clone());
+            }
+            AccessEvent::RejectedNotAllowedPort(node, port_mapping) => {
+                fields.push("event", "rejected");
+                fields.push("node_id", node.to_string());
+                fields.push("reason", "not-allowed-port");
+                fields.push("port_mapping", port_mapping.clone());
+            }
+            AccessEvent::RejectedDefaultRoute(node) => {
+                fields.push("event", "rejected");
+                fields.push("node_id", node.to_string());
+                fields.push("reason", "default-route-missing");
+            }
+            AccessEvent::RejectedExpiredGrant(node, port_mapping) => {
+                fields.push("event", "rejected");
+                fields.push("node_id", node.to_string());
+                fields.push("reason", "grant-expired");
+                fields.push("port_mapping", port_mapping.clone());
+            }
+            AccessEvent::RejectedOutsideSchedule(node, port_mapping) => {
+                fields.push("event", "rejected");
+                fields.push("node_id", node.to_string());
+                fields.push("reason", "outside-schedule");
+                fields.push("port_mapping", port_mapping.clone());
+            }
+            AccessEvent::RejectedLimit(node, limit) => {
+                fields.push("event", "rejected");
+                fields.push("node_id", node.to_string());
+                fields.push("reason", "limit-exceeded");
+                fields.push("limit", limit.kind());
+                match limit {
+                    LimitExceeded::ConnectionsPerPeer(max)
+                    | LimitExceeded::StreamsPerPeer(max)
+                    | LimitExceeded::Streams(max) => {
+                        fields.push("max", FieldValue::U64(*max as u64));
+                    }
+                    LimitExceeded::StreamsPerRoute(route, max) => {
+                        fields.push("route", route.clone());
+                        fields.push("max", FieldValue::U64(*max as u64));
+                    }
+                    LimitExceeded::HeaderTimeout(timeout) => {
+                        fields.push("timeout_secs", FieldValue::F64(timeout.as_secs_f64()));
+                    }
+                }
+            }
+            AccessEvent::RejectedQuota(node, quota) => {
+                fields.push("event", "rejected");
+                fields.push("node_id", node.to_string());
+                fields.push("reason", "quota-exceeded");
+                fields.push("period", quota.period());
+                fields.push("used_bytes", FieldValue::U64(quota.used()));
+                fields.push("quota_bytes", FieldValue::U64(quota.quota()));
+            }
+            AccessEvent::RejectedBanned(node, ban) => {
+                fields.push("event", "rejected");
+                fields.push("node_id", node.to_string());
+                fields.push("reason", "banned");
+                fields.push("ban", ban.kind());
+                fields.push("banned", ban.target());
+            }
+            AccessEvent::Banned(node, ban, ban_time) => {
+                fields.push("event", "banned");
+                fields.push("node_id", node.to_string());
+                fields.push("ban", ban.kind());
+                fields.push("banned", ban.target());
+                fields.push("ban_secs", FieldValue::U64(ban_time.as_secs()));
+            }
+            AccessEvent::BanLifted(ban) => {
+                fields.push("event", "unbanned");
+                fields.push("ban", ban.kind());
+                fields.push("banned", ban.target());
+            }
+            AccessEvent::StreamClosed(stream) => {
+                fields.push("event", "closed");
+                fields.push("node_id", stream.node_id.to_string());
+                fields.push("route", stream.route.clone());
+                fields.push("default_route", FieldValue::Bool(stream.default_route));
+                fields.push("downstream", stream.downstream.clone());
+                fields.push(
+                    "bytes_to_downstream",
+                    FieldValue::U64(stream.bytes_upstream_to_downstream),
+                );
+                fields.push(
+                    "bytes_to_upstream",
+                    FieldValue::U64(stream.bytes_downstream_to_upstream),
+                );
+                fields.push(
+                    "duration_secs",
+                    FieldValue::F64(stream.duration.as_secs_f64()),
+                );
+                fields.push("reason", stream.reason.to_string());
+            }
+            AccessEvent::Accepted(node) => {
+                fields.push("event", "accepted");
+                fields.push("node_id", node.to_string());
+            }
+        }
+        fields
+    }
+}
+
+/// An entry as ordered key-value pairs, for the structured formats
+struct Fields(Vec<(&'static str, FieldValue)>);
+
+enum FieldValue {
+    Str(String),
+    U64(u64),
+    F64(f64),
+    Bool(bool),
+}
+
+impl From<&'static str> for FieldValue {
+    fn from(value: &'static str) -> Self {
+        Self::Str(value.to_string())
+    }
+}
+
+impl From<String> for FieldValue {
+    fn from(value: String) -> Self {
+        Self::Str(value)
+    }
+}
+
+impl Fields {
+    #[inline]
+    fn push(&mut self, key: &'static str, value: impl Into<FieldValue>) {
+        self.0.push((key, value.into()));
+    }
+
+    fn to_logfmt(&self) -> String {
+        use std::fmt::Write as _;
+        let mut out = String::new();
+        for (key, value) in &self.0 {
+            if !out.is_empty() {
+                out.push(' ');
+            }
+            let _ = match value {
+                FieldValue::Str(s) if needs_logfmt_quoting(s) => {
+                    write!(
+                        out,
+                        "{key}=\"{}\"",
+                        s.replace('\\', "\\\\").replace('"', "\\\"")
+                    )
+                }
+                FieldValue::Str(s) => write!(out, "{key}={s}"),
+                FieldValue::U64(n) => write!(out, "{key}={n}"),
+                FieldValue::F64(n) => write!(out, "{key}={n:.3}"),
+                FieldValue::Bool(b) => write!(out, "{key}={b}"),
+            };
+        }
+        out
+    }
+}
+
+fn needs_logfmt_quoting(s: &str) -> bool {
+    s.is_empty()
+        || s.chars()
+            .any(|c| c.is_whitespace() || c.is_control() || matches!(c, '=' | '"' | '\\'))
+}
+
+impl serde::Serialize for Fields {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (key, value) in &self.0 {
+            match value {
+                FieldValue::Str(s) => map.serialize_entry(key, s)?,
+                FieldValue::U64(n) => map.serialize_entry(key, n)?,
+                FieldValue::F64(n) => map.serialize_entry(key, n)?,
+                FieldValue::Bool(b) => map.serialize_entry(key, b)?,
+            }
+        }
+        map.end()
+    }
+}
+
+fn render_dropped(format: AccessLogFormat, count: u64) -> anyhow::Result<String> {
+    let timestamp = timestamp_try_local_offset()
+        .format(&Rfc3339)
+        .context("failed to format timestamp")?;
+    if format == AccessLogFormat::Tsv {
+        return Ok(format!(
+            "{timestamp}\tDROPPED\t{count} entries dropped, the access log could not keep up"
+        ));
+    }
+    let fields = Fields(vec![
+        ("timestamp", FieldValue::Str(timestamp)),
+        ("event", "dropped".into()),
+        ("count", FieldValue::U64(count)),
+    ]);
+    if format == AccessLogFormat::Json {
+        serde_json::to_string(&fields).context("failed to serialize json")
+    } else {
+        Ok(fields.to_logfmt())
+    }
+}
+
+/// An opened sink, lines are written without a trailing newline
+enum SinkWriter {
+    File(RotatingFile),
+    Stdout(std::io::Stdout),
+    #[cfg(unix)]
+    Syslog(std::os::unix::net::UnixDatagram),
+}
+
+impl SinkWriter {
+    fn write_line(&mut self, line: &str) -> anyhow::Result<()> {
+        match self {
+            Self::File(file) => file.write_line(line),
+            Self::Stdout(stdout) => stdout
+                .lock()
+                .write_fmt(format_args!("{line}\n"))
+                .context("Failed to write to stdout"),
+            #[cfg(unix)]
+            Self::Syslog(socket) => socket
+                .send(
+                    format!(
+                        "<{SYSLOG_PRIORITY}>p2proxyd[{}]: {line}",
+                        std::process::id()
+                    )
+                    .as_bytes(),
+                )
+                .map(drop)
+                .context("Failed to send to syslog"),
+        }
+    }
+}
+
+/// The access log file, rotated according to its configuration before a line is written
+struct RotatingFile {
+    path: PathBuf,
+    file: std::fs::File,
+    size: u64,
+    /// The day the file last got an entry, kept from its modification time across reopens and restarts
+    written_on: time::Date,
+    rotation: Option<AccessLogRotation>,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, rotation: Option<AccessLogRotation>) -> anyhow::Result<Self> {
+        let file = try_file(path)?;
+        let metadata = file
+            .metadata()
+            .with_context(|| format!("Failed to stat access log file: {}", path.display()))?;
+        let now = timestamp_try_local_offset();
+        let written_on = metadata
+            .modified()
+            .ok()
+            .filter(|_| metadata.len() > 0)
+            .map_or(now, |modified| {
+                OffsetDateTime::from(modified).to_offset(now.offset())
+            })
+            .date();
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            size: metadata.len(),
+            written_on,
+            rotation,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> anyhow::Result<()> {
+        let line_len = line.len() as u64 + 1;
+        let today = timestamp_try_local_offset().date();
+        if let Some(rotation) = self.rotation {
+            let too_large = rotation
+                .max_bytes
+                .is_some_and(|max| self.size > 0 && self.size + line_len > max);
+            let yesterdays = rotation.daily && self.size > 0 && today != self.written_on;
+            if too_large || yesterdays {
+                self.rotate(rotation.retain)?;
+                *self = Self::open(&self.path, self.rotation)?;
+            }
+        }
+        self.file
+            .write_fmt(format_args!("{line}\n"))
+            .with_context(|| format!("Failed to write to {}", self.path.display()))?;
+        self.size += line_len;
+        self.written_on = today;
+        Ok(())
+    }
+
+    fn rotate(&self, retain: usize) -> anyhow::Result<()> {
+        tracing::info!("Rotating access log file: {}", self.path.display());
+        let oldest = rotated_path(&self.path, retain);
+        if oldest.exists() {
+            std::fs::remove_file(&oldest)
+                .with_context(|| format!("Failed to remove {}", oldest.display()))?;
+        }
+        for i in (1..retain).rev() {
+            let from = rotated_path(&self.path, i);
+            if from.exists() {
+                let to = rotated_path(&self.path, i + 1);
+                std::fs::rename(&from, &to).with_context(|| {
+                    format!("Failed to rename {} to {}", from.display(), to.display())
+                })?;
+            }
+        }
+        let newest = rotated_path(&self.path, 1);
+        std::fs::rename(&self.path, &newest).with_context(|| {
+            format!(
+                "Failed to rename {} to {}",
+                self.path.display(),
+                newest.display()
+            )
+        })
+    }
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{index}"));
+    PathBuf::from(rotated)
+}
+
+/// Writes entries until the sink fails or a reload is requested, the caller then reopens the sink.
+/// Entries that can't be written are counted as dropped, so they show up once the sink works again.
+/// Why the writer stopped writing to its sink
+enum WriterStop {
+    /// After a reload or a failed write
+    Reopen,
+    /// Every handle is dropped, and everything queued is written
+    Closed,
+}
+
+fn access_log_writer(
+    chan: &std::sync::mpsc::Receiver<AccessLogWriterMessage>,
+    shared: &WriterShared,
+    format: AccessLogFormat,
+    mut sink: SinkWriter,
+) -> WriterStop {
+    loop {
+        let msg = match chan.recv_timeout(ACCESS_LOG_WAKE_INTERVAL) {
+            Ok(msg) => Some(msg),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => return WriterStop::Closed,
+        };
+        let dropped = shared.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            tracing::warn!("access log dropped {dropped} entries");
+            let written = render_dropped(format, dropped).and_then(|line| sink.write_line(&line));
+            if let Err(e) = written {
+                tracing::error!("Failed to write to access log: {}", display_chain(&*e));
+                shared.dropped.fetch_add(dropped, Ordering::Relaxed);
+                return WriterStop::Reopen;
+            }
+        }
+        match msg {
+            Some(AccessLogWriterMessage::IncomingConnection(conn)) => match conn.render(format) {
+                Ok(line) => {
+                    if let Err(e) = sink.write_line(&line) {
+                        tracing::error!("Failed to write to access log: {}", display_chain(&*e));
+                        shared.dropped.fetch_add(1, Ordering::Relaxed);
+                        return WriterStop::Reopen;
+                    }
+                }
+                Err(e) => {
+                    tracing::error!("Failed to render access log entry: {}", display_chain(&*e));
+                    shared.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            },
+            Some(AccessLogWriterMessage::Flush(ack)) => {
+                // The flusher may have given up waiting already
+                let _ = ack.try_send(());
+            }
+            Some(AccessLogWriterMessage::ReloadFile) | None => {}
+        }
+        if shared.reload.swap(false, Ordering::Relaxed) {
+            tracing::info!("Reloading access log file");
+            return WriterStop::Reopen;
+        }
+    }
+}
+
+fn access_log_writer_outer_loop(
+    chan: &std::sync::mpsc::Receiver<AccessLogWriterMessage>,
+    shared: &WriterShared,
+    cfg: &AccessLogConfig,
+) {
+    loop {
+        match cfg.open() {
+            Ok(o) => match access_log_writer(chan, shared, cfg.format, o) {
+                WriterStop::Reopen => {}
+                WriterStop::Closed => return,
+            },
+            Err(e) => {
+                tracing::error!("Failed to open access log: {}", display_chain(&*e));
+                std::thread::sleep(std::time::Duration::from_secs(15));
+            }
+        }
+    }
+}
+
+impl AccessLogConfig {
+    fn open(&self) -> anyhow::Result<SinkWriter> {
+        match &self.sink {
+            AccessLogSink::File(path) => {
+                RotatingFile::open(path, self.rotation).map(SinkWriter::File)
+            }
+            AccessLogSink::Stdout => Ok(SinkWriter::Stdout(std::io::stdout())),
+            #[cfg(unix)]
+            AccessLogSink::Syslog => {
+                let socket = std::os::unix::net::UnixDatagram::unbound()
+                    .context("Failed to create syslog socket")?;
+                socket
+                    .connect(SYSLOG_SOCKET)
+                    .with_context(|| format!("Failed to connect to syslog at {SYSLOG_SOCKET}"))?;
+                Ok(SinkWriter::Syslog(socket))
+            }
+        }
+    }
+}
+
+fn try_file(path: &Path) -> anyhow::Result<std::fs::File> {
+    tracing::debug!("Opening access log file: {}", path.display());
+    std::fs::File::options()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open access log file: {}", path.display()))
+}
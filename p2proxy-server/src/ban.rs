@@ -0,0 +1,247 @@
+#[cfg(test)]
+mod test;
+
+use crate::registry::format_timestamp;
+use iroh::NodeId;
+use rustc_hash::FxHashMap;
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use time::OffsetDateTime;
+
+/// Longer bans are cut short to this, a year
+pub const MAX_BAN_TIME: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// When repeated rejections get a peer, or an address, banned
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct BanPolicy {
+    /// Rejections within `window` that trigger a ban
+    pub max_rejections: usize,
+    pub window: Duration,
+    /// How long connections are refused once banned, at most [`MAX_BAN_TIME`]
+    pub ban_time: Duration,
+}
+
+/// What a ban applies to. Rejections are counted for the peer's node id and its address separately,
+/// so that neither a fresh key nor a fresh address is enough to keep probing.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BanKey {
+    Peer(NodeId),
+    Address(IpAddr),
+}
+
+impl BanKey {
+    /// A stable name for what's banned, for structured logs
+    #[must_use]
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Peer(_) => "peer",
+            Self::Address(_) => "address",
+        }
+    }
+
+    /// The node id or ip, without the kind
+    #[must_use]
+    pub fn target(&self) -> String {
+        match self {
+            Self::Peer(node_id) => node_id.to_string(),
+            Self::Address(ip) => ip.to_string(),
+        }
+    }
+}
+
+impl Display for BanKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Peer(node_id) => write!(f, "peer {node_id}"),
+            Self::Address(ip) => write!(f, "address {ip}"),
+        }
+    }
+}
+
+/// Either an ip address or a node id
+impl FromStr for BanKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(ip) = s.parse::<IpAddr>() {
+            return Ok(Self::Address(ip));
+        }
+        let node_id = s
+            .parse::<NodeId>()
+            .map_err(|e| anyhow::anyhow!("'{s}' is neither an ip address nor a node id: {e}"))?;
+        Ok(Self::Peer(node_id))
+    }
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct BanInfo {
+    pub banned: BanKey,
+    /// Rfc3339
+    pub since: String,
+    /// Rfc3339
+    pub until: String,
+}
+
+/// Bans peers and addresses that keep getting their streams rejected, fail2ban-style.
+/// Nothing is ever banned without a [`BanPolicy`].
+#[derive(Debug)]
+pub struct Bans {
+    policy: Option<BanPolicy>,
+    inner: Mutex<BansInner>,
+}
+
+#[derive(Debug, Default)]
+struct BansInner {
+    /// When each recent rejection happened, oldest first
+    rejections: FxHashMap<BanKey, VecDeque<Instant>>,
+    banned: FxHashMap<BanKey, Ban>,
+}
+
+#[derive(Debug)]
+struct Ban {
+    expires: Instant,
+    since: OffsetDateTime,
+    until: OffsetDateTime,
+}
+
+impl Bans {
+    #[must_use]
+    pub fn new(policy: Option<BanPolicy>) -> Self {
+        Self {
+            policy: policy.map(|policy| BanPolicy {
+                ban_time: policy.ban_time.min(MAX_BAN_TIME),
+                ..policy
+            }),
+            inner: Mutex::new(BansInner::default()),
+        }
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn policy(&self) -> Option<BanPolicy> {
+        self.policy
+    }
+
+    /// Counts a rejection against both the peer and its address, returns what got banned because of it
+    #[inline]
+    pub(crate) fn record_rejection(&self, peer: NodeId, addr: IpAddr) -> Vec<BanKey> {
+        self.record_rejection_at(peer, addr, Instant::now())
+    }
+
+    /// As [`Bans::record_rejection`], for a rejection at `now`
+    fn record_rejection_at(&self, peer: NodeId, addr: IpAddr, now: Instant) -> Vec<BanKey> {
+        let Some(policy) = self.policy else {
+            return Vec::new();
+        };
+        let mut inner = self.lock();
+        inner.prune(now, policy.window);
+        let mut banned = Vec::new();
+        for key in [BanKey::Peer(peer), BanKey::Address(addr)] {
+            if inner.banned.contains_key(&key) {
+                continue;
+            }
+            let rejections = inner.rejections.entry(key).or_default();
+            rejections.push_back(now);
+            if rejections.len() < policy.max_rejections {
+                continue;
+            }
+            inner.rejections.remove(&key);
+            let since = OffsetDateTime::now_utc();
+            inner.banned.insert(
+                key,
+                Ban {
+                    expires: now + policy.ban_time,
+                    since,
+                    until: since + policy.ban_time,
+                },
+            );
+            banned.push(key);
+        }
+        banned
+    }
+
+    /// The ban that applies to a connection from `peer` at `addr`, if any
+    #[must_use]
+    pub fn is_banned(&self, peer: NodeId, addr: IpAddr) -> Option<BanKey> {
+        if self.policy.is_none() {
+            return None;
+        }
+        let now = Instant::now();
+        let inner = self.lock();
+        [BanKey::Peer(peer), BanKey::Address(addr)]
+            .into_iter()
+            .find(|key| inner.banned.get(key).is_some_and(|ban| ban.expires > now))
+    }
+
+    /// When the ban on `key` runs out, if it's banned
+    #[must_use]
+    pub fn banned_until(&self, key: &BanKey) -> Option<OffsetDateTime> {
+        let now = Instant::now();
+        self.lock()
+            .banned
+            .get(key)
+            .filter(|ban| ban.expires > now)
+            .map(|ban| ban.until)
+    }
+
+    /// Banned peers are closed with this reason, when the ban on `key` runs out, so they know when to come back
+    pub(crate) fn close_reason(&self, key: &BanKey) -> String {
+        self.banned_until(key)
+            .map(format_timestamp)
+            .unwrap_or_default()
+    }
+
+    #[must_use]
+    pub fn list(&self) -> Vec<BanInfo> {
+        let now = Instant::now();
+        let mut inner = self.lock();
+        inner.banned.retain(|_, ban| ban.expires > now);
+        let mut bans = inner.banned.iter().collect::<Vec<_>>();
+        bans.sort_by_key(|(_, ban)| ban.since);
+        bans.into_iter()
+            .map(|(key, ban)| BanInfo {
+                banned: *key,
+                since: format_timestamp(ban.since),
+                until: format_timestamp(ban.until),
+            })
+            .collect()
+    }
+
+    /// Lifts a ban before it runs out, its rejections are forgotten too. Returns whether it was banned.
+    #[must_use]
+    pub fn lift(&self, key: &BanKey) -> bool {
+        let now = Instant::now();
+        let mut inner = self.lock();
+        inner.rejections.remove(key);
+        inner
+            .banned
+            .remove(key)
+            .is_some_and(|ban| ban.expires > now)
+    }
+
+    #[inline]
+    fn lock(&self) -> std::sync::MutexGuard<'_, BansInner> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl BansInner {
+    /// Drops what no longer matters, so that probing from many keys or addresses doesn't pile up
+    fn prune(&mut self, now: Instant, window: Duration) {
+        self.banned.retain(|_, ban| ban.expires > now);
+        self.rejections.retain(|_, rejections| {
+            while rejections
+                .front()
+                .is_some_and(|at| now.duration_since(*at) >= window)
+            {
+                rejections.pop_front();
+            }
+            !rejections.is_empty()
+        });
+    }
+}
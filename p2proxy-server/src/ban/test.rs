@@ -0,0 +1,146 @@
+use crate::ban::{BanKey, BanPolicy, Bans, MAX_BAN_TIME};
+use iroh::{NodeId, SecretKey};
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, Instant};
+
+const POLICY: BanPolicy = BanPolicy {
+    max_rejections: 3,
+    window: Duration::from_secs(60),
+    ban_time: Duration::from_secs(600),
+};
+
+fn node_id(seed: u8) -> NodeId {
+    SecretKey::from_bytes(&[seed; 32]).public()
+}
+
+fn ip(last: u8) -> IpAddr {
+    IpAddr::V4(Ipv4Addr::new(192, 0, 2, last))
+}
+
+fn secs(secs: u64) -> Duration {
+    Duration::from_secs(secs)
+}
+
+#[test]
+fn test_ban_on_nth_rejection_in_window() {
+    let bans = Bans::new(Some(POLICY));
+    let peer = node_id(1);
+    let start = Instant::now();
+    assert!(bans.record_rejection_at(peer, ip(1), start).is_empty());
+    assert!(
+        bans.record_rejection_at(peer, ip(1), start + secs(10))
+            .is_empty()
+    );
+    assert_eq!(None, bans.is_banned(peer, ip(1)));
+    assert_eq!(
+        vec![BanKey::Peer(peer), BanKey::Address(ip(1))],
+        bans.record_rejection_at(peer, ip(1), start + secs(20))
+    );
+    // Either one is enough to be refused
+    assert_eq!(Some(BanKey::Peer(peer)), bans.is_banned(peer, ip(2)));
+    assert_eq!(
+        Some(BanKey::Address(ip(1))),
+        bans.is_banned(node_id(2), ip(1))
+    );
+    assert_eq!(None, bans.is_banned(node_id(2), ip(2)));
+    assert_eq!(2, bans.list().len());
+    // Already banned, nothing new to ban
+    assert!(
+        bans.record_rejection_at(peer, ip(1), start + secs(30))
+            .is_empty()
+    );
+
+    // Rejections are counted for the address, whichever peer they're from
+    let bans = Bans::new(Some(POLICY));
+    assert!(
+        bans.record_rejection_at(node_id(1), ip(1), start)
+            .is_empty()
+    );
+    assert!(
+        bans.record_rejection_at(node_id(2), ip(1), start)
+            .is_empty()
+    );
+    assert_eq!(
+        vec![BanKey::Address(ip(1))],
+        bans.record_rejection_at(node_id(3), ip(1), start)
+    );
+    assert_eq!(None, bans.is_banned(node_id(1), ip(2)));
+}
+
+#[test]
+fn test_rejections_outside_window() {
+    let bans = Bans::new(Some(POLICY));
+    let peer = node_id(1);
+    let start = Instant::now();
+    assert!(bans.record_rejection_at(peer, ip(1), start).is_empty());
+    assert!(
+        bans.record_rejection_at(peer, ip(1), start + secs(40))
+            .is_empty()
+    );
+    // The first one is out of the window by now
+    assert!(
+        bans.record_rejection_at(peer, ip(1), start + secs(80))
+            .is_empty()
+    );
+    // And the second one, exactly a window old
+    assert!(
+        bans.record_rejection_at(peer, ip(1), start + secs(100))
+            .is_empty()
+    );
+    assert_eq!(None, bans.is_banned(peer, ip(1)));
+    assert_eq!(
+        vec![BanKey::Peer(peer), BanKey::Address(ip(1))],
+        bans.record_rejection_at(peer, ip(1), start + secs(110))
+    );
+
+    // Without a policy nothing is ever banned
+    let bans = Bans::new(None);
+    for _ in 0..10 {
+        assert!(bans.record_rejection_at(peer, ip(1), start).is_empty());
+    }
+    assert_eq!(None, bans.is_banned(peer, ip(1)));
+    assert!(bans.list().is_empty());
+}
+
+#[test]
+fn test_lift() {
+    let bans = Bans::new(Some(POLICY));
+    let peer = node_id(1);
+    let start = Instant::now();
+    assert!(bans.record_rejection_at(peer, ip(1), start).is_empty());
+    assert!(bans.record_rejection_at(peer, ip(2), start).is_empty());
+    // Not banned, but its pending rejections are forgotten
+    assert!(!bans.lift(&BanKey::Peer(peer)));
+    assert!(bans.record_rejection_at(peer, ip(3), start).is_empty());
+    assert!(bans.record_rejection_at(peer, ip(4), start).is_empty());
+    assert_eq!(
+        vec![BanKey::Peer(peer)],
+        bans.record_rejection_at(peer, ip(5), start)
+    );
+    assert_eq!(Some(BanKey::Peer(peer)), bans.is_banned(peer, ip(6)));
+
+    assert!(bans.banned_until(&BanKey::Peer(peer)).is_some());
+    assert!(!bans.close_reason(&BanKey::Peer(peer)).is_empty());
+
+    assert!(bans.lift(&BanKey::Peer(peer)));
+    assert_eq!(None, bans.is_banned(peer, ip(6)));
+    assert_eq!(None, bans.banned_until(&BanKey::Peer(peer)));
+    assert!(bans.list().is_empty());
+    assert!(!bans.lift(&BanKey::Peer(peer)));
+    // Starts counting from nothing again
+    assert!(bans.record_rejection_at(peer, ip(7), start).is_empty());
+    assert!(bans.record_rejection_at(peer, ip(8), start).is_empty());
+    assert_eq!(None, bans.is_banned(peer, ip(9)));
+}
+
+#[test]
+fn test_ban_time_capped() {
+    let bans = Bans::new(Some(BanPolicy {
+        ban_time: MAX_BAN_TIME * 2,
+        ..POLICY
+    }));
+    assert_eq!(
+        Some(MAX_BAN_TIME),
+        bans.policy().map(|policy| policy.ban_time)
+    );
+}
@@ -0,0 +1,69 @@
+//! The p2proxy server, as run by `p2proxyd`.
+//!
+//! Build [`routes::Routes`] with a [`routes::RoutesBuilder`], create the shared [`ProxyState`],
+//! and mount a [`P2ProxyProto`] on an iroh router under [`p2proxy_lib::proto::ALPN`],
+//! next to any other protocols the endpoint serves.
+//!
+//! ```no_run
+//! use iroh::protocol::Router;
+//! use p2proxy_lib::proto::ALPN;
+//! use p2proxy_server::access_log::{AccessEvent, AccessLogHandle};
+//! use p2proxy_server::limits::Limits;
+//! use p2proxy_server::routes::{RouteTarget, RoutesBuilder};
+//! use p2proxy_server::{P2ProxyProto, ProxyState};
+//! use std::time::Duration;
+//! # use iroh::endpoint::Connection;
+//! # use iroh::protocol::{AcceptError, ProtocolHandler};
+//! # const MY_ALPN: &[u8] = b"my-protocol/0";
+//! # #[derive(Debug)]
+//! # struct MyProtocol;
+//! # impl ProtocolHandler for MyProtocol {
+//! #     async fn accept(&self, _connection: Connection) -> Result<(), AcceptError> {
+//! #         Ok(())
+//! #     }
+//! # }
+//! # async fn serve(endpoint: iroh::Endpoint, friend_node_id: iroh::NodeId) -> anyhow::Result<()> {
+//! # let my_protocol = MyProtocol;
+//!
+//! let routes = RoutesBuilder::new()
+//!     .default_route("web")
+//!     .route("web", RouteTarget::Tcp("127.0.0.1:8080".parse()?))
+//!     .public_route("status", RouteTarget::Tcp("127.0.0.1:8081".parse()?))
+//!     .allow_peer(friend_node_id, ["web"])
+//!     .build()?;
+//! // `None` means no access log is written, hooks are still called
+//! let access_log = AccessLogHandle::maybe_spawn(None).with_hook(
+//!     |identity: Option<&str>, address, event: &AccessEvent| {
+//!         if let AccessEvent::Accepted(node_id) = event {
+//!             tracing::info!("{node_id} connected to {identity:?} from {address:?}");
+//!         }
+//!     },
+//! );
+//! let limits = Limits {
+//!     max_streams_per_peer: Some(64),
+//!     ..Limits::default()
+//! };
+//! // `None` never bans, `Some(BanPolicy { .. })` bans peers and addresses after repeated rejections
+//! let state = ProxyState::new(access_log, limits, None);
+//! let router = Router::builder(endpoint)
+//!     .accept(ALPN, P2ProxyProto::new(None, routes, &state))
+//!     .accept(MY_ALPN, my_protocol)
+//!     .spawn();
+//! // ...
+//! // Stop accepting new streams, give open ones 30 seconds to finish
+//! state.shutdown(Duration::from_secs(30)).await;
+//! router.shutdown().await?;
+//! # Ok(())
+//! # }
+//! ```
+pub mod access_log;
+pub mod ban;
+pub mod limits;
+pub mod metrics;
+mod proto;
+pub mod quota;
+pub mod registry;
+pub mod routes;
+pub mod schedule;
+
+pub use proto::{P2ProxyProto, ProxyState, ShutdownSignal};
@@ -0,0 +1,71 @@
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+/// How long a peer gets to send the route header of a stream, if not configured
+pub const DEFAULT_HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Bounds on what peers can keep open, so that a misbehaving (or malicious) allowed peer can't exhaust
+/// the server's memory. Counted across all identities, `None` is unlimited.
+/// Limits on single routes are part of the [`crate::routes::Routes`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Limits {
+    pub max_connections_per_peer: Option<usize>,
+    pub max_streams_per_peer: Option<usize>,
+    /// Streams open across all peers
+    pub max_streams: Option<usize>,
+    /// How long a peer gets to send the route header after opening a stream
+    pub header_timeout: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_connections_per_peer: None,
+            max_streams_per_peer: None,
+            max_streams: None,
+            header_timeout: DEFAULT_HEADER_TIMEOUT,
+        }
+    }
+}
+
+/// The limit that made the server refuse a connection or stream
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum LimitExceeded {
+    ConnectionsPerPeer(usize),
+    StreamsPerPeer(usize),
+    /// The route, and its limit
+    StreamsPerRoute(String, usize),
+    Streams(usize),
+    /// The peer didn't send a route header in time
+    HeaderTimeout(Duration),
+}
+
+impl LimitExceeded {
+    /// A stable name for the limit, for structured logs
+    #[must_use]
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::ConnectionsPerPeer(_) => "connections-per-peer",
+            Self::StreamsPerPeer(_) => "streams-per-peer",
+            Self::StreamsPerRoute(..) => "streams-per-route",
+            Self::Streams(_) => "streams",
+            Self::HeaderTimeout(_) => "header-timeout",
+        }
+    }
+}
+
+impl Display for LimitExceeded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ConnectionsPerPeer(max) => write!(f, "more than {max} connections from the peer"),
+            Self::StreamsPerPeer(max) => write!(f, "more than {max} streams from the peer"),
+            Self::StreamsPerRoute(route, max) => {
+                write!(f, "more than {max} streams on route '{route}'")
+            }
+            Self::Streams(max) => write!(f, "more than {max} streams in total"),
+            Self::HeaderTimeout(timeout) => {
+                write!(f, "no route header within {:.3}s", timeout.as_secs_f64())
+            }
+        }
+    }
+}
@@ -0,0 +1,399 @@
+use crate::registry::StreamStats;
+use anyhow::Context;
+use iroh::NodeId;
+use p2proxy_lib::display_chain;
+use rustc_hash::FxHashMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Instant;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Upper bounds of the stream duration histogram, in seconds
+const DURATION_BUCKETS: [f64; 9] = [
+    1.0, 10.0, 60.0, 300.0, 1800.0, 3600.0, 14400.0, 43200.0, 86400.0,
+];
+
+/// Outcome of an incoming connection, or of routing a stream on it
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub(crate) enum ConnectionOutcome {
+    Accepted,
+    MissingNodeId,
+    GarbagePortMapping,
+    UnknownPortMapping,
+    NotAllowedPort,
+    DefaultRouteMissing,
+    LimitExceeded,
+    QuotaExceeded,
+    Banned,
+    GrantExpired,
+    OutsideSchedule,
+}
+
+impl ConnectionOutcome {
+    fn label(self) -> &'static str {
+        match self {
+            ConnectionOutcome::Accepted => "accepted",
+            ConnectionOutcome::MissingNodeId => "missing_node_id",
+            ConnectionOutcome::GarbagePortMapping => "garbage_port_mapping",
+            ConnectionOutcome::UnknownPortMapping => "unknown_port_mapping",
+            ConnectionOutcome::NotAllowedPort => "not_allowed_port",
+            ConnectionOutcome::DefaultRouteMissing => "default_route_missing",
+            ConnectionOutcome::LimitExceeded => "limit_exceeded",
+            ConnectionOutcome::QuotaExceeded => "quota_exceeded",
+            ConnectionOutcome::Banned => "banned",
+            ConnectionOutcome::GrantExpired => "grant_expired",
+            ConnectionOutcome::OutsideSchedule => "outside_schedule",
+        }
+    }
+}
+
+/// Counters for the prometheus endpoint. Bytes of streams that are still open are read from their counters
+/// when scraped, the totals only contain closed streams. A closing stream moves its bytes from one to the other
+/// under the same lock a scrape takes, so that no scrape sees them twice, or not at all.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    inner: Mutex<MetricsInner>,
+}
+
+#[derive(Debug, Default)]
+struct MetricsInner {
+    connections: FxHashMap<ConnectionOutcome, u64>,
+    routes: FxHashMap<String, RouteMetrics>,
+    peers: FxHashMap<NodeId, ByteCounts>,
+    open_streams: FxHashMap<u64, OpenStream>,
+    next_stream_id: u64,
+}
+
+#[derive(Debug)]
+struct OpenStream {
+    route: String,
+    peer: NodeId,
+    stats: StreamStats,
+}
+
+#[derive(Debug, Default)]
+struct RouteMetrics {
+    active_streams: u64,
+    connect_failures: u64,
+    bytes: ByteCounts,
+    duration_buckets: [u64; DURATION_BUCKETS.len()],
+    duration_count: u64,
+    duration_sum: f64,
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+struct ByteCounts {
+    upstream_to_downstream: u64,
+    downstream_to_upstream: u64,
+}
+
+impl Metrics {
+    pub(crate) fn record_connection(&self, outcome: ConnectionOutcome) {
+        *self.lock().connections.entry(outcome).or_default() += 1;
+    }
+
+    pub(crate) fn record_connect_failure(&self, route: &str) {
+        self.lock()
+            .routes
+            .entry(route.to_string())
+            .or_default()
+            .connect_failures += 1;
+    }
+
+    /// Counts the stream as active until the returned guard is dropped
+    pub(crate) fn stream_started(
+        &self,
+        route: &str,
+        peer: NodeId,
+        stats: &StreamStats,
+    ) -> StreamMetricsGuard<'_> {
+        let mut inner = self.lock();
+        inner
+            .routes
+            .entry(route.to_string())
+            .or_default()
+            .active_streams += 1;
+        let id = inner.next_stream_id;
+        inner.next_stream_id += 1;
+        inner.open_streams.insert(
+            id,
+            OpenStream {
+                route: route.to_string(),
+                peer,
+                stats: stats.clone(),
+            },
+        );
+        StreamMetricsGuard {
+            metrics: self,
+            id,
+            started: Instant::now(),
+        }
+    }
+
+    #[inline]
+    fn lock(&self) -> std::sync::MutexGuard<'_, MetricsInner> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    #[allow(clippy::too_many_lines)]
+    fn render(&self) -> String {
+        let inner = self.lock();
+        let mut route_bytes: FxHashMap<String, ByteCounts> = inner
+            .routes
+            .iter()
+            .map(|(route, m)| (route.clone(), m.bytes))
+            .collect();
+        let mut peer_bytes = inner.peers.clone();
+        for stream in inner.open_streams.values() {
+            let open = stream.stats.counts();
+            for counts in [
+                route_bytes.entry(stream.route.clone()).or_default(),
+                peer_bytes.entry(stream.peer).or_default(),
+            ] {
+                counts.upstream_to_downstream += open.upstream_to_downstream;
+                counts.downstream_to_upstream += open.downstream_to_upstream;
+            }
+        }
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# HELP p2proxyd_connections_total Incoming connections and routed streams by outcome"
+        );
+        let _ = writeln!(out, "# TYPE p2proxyd_connections_total counter");
+        let mut connections = inner.connections.iter().collect::<Vec<_>>();
+        connections.sort_by_key(|(outcome, _)| outcome.label());
+        for (outcome, count) in connections {
+            let _ = writeln!(
+                out,
+                "p2proxyd_connections_total{{result=\"{}\"}} {count}",
+                outcome.label()
+            );
+        }
+
+        let mut routes = inner.routes.iter().collect::<Vec<_>>();
+        routes.sort_by(|a, b| a.0.cmp(b.0));
+        let _ = writeln!(
+            out,
+            "# HELP p2proxyd_route_active_streams Streams currently proxied per route"
+        );
+        let _ = writeln!(out, "# TYPE p2proxyd_route_active_streams gauge");
+        for (route, m) in &routes {
+            let _ = writeln!(
+                out,
+                "p2proxyd_route_active_streams{{route=\"{}\"}} {}",
+                escape_label(route),
+                m.active_streams
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP p2proxyd_route_connect_failures_total Failed connections to a route's target"
+        );
+        let _ = writeln!(out, "# TYPE p2proxyd_route_connect_failures_total counter");
+        for (route, m) in &routes {
+            let _ = writeln!(
+                out,
+                "p2proxyd_route_connect_failures_total{{route=\"{}\"}} {}",
+                escape_label(route),
+                m.connect_failures
+            );
+        }
+
+        let mut route_bytes = route_bytes.into_iter().collect::<Vec<_>>();
+        route_bytes.sort_by(|a, b| a.0.cmp(&b.0));
+        let _ = writeln!(
+            out,
+            "# HELP p2proxyd_route_bytes_total Bytes proxied per route and direction"
+        );
+        let _ = writeln!(out, "# TYPE p2proxyd_route_bytes_total counter");
+        for (route, counts) in &route_bytes {
+            write_directions(
+                &mut out,
+                "p2proxyd_route_bytes_total",
+                &format!("route=\"{}\"", escape_label(route)),
+                *counts,
+            );
+        }
+        let mut peer_bytes = peer_bytes.into_iter().collect::<Vec<_>>();
+        peer_bytes.sort_by(|a, b| a.0.as_bytes().cmp(b.0.as_bytes()));
+        let _ = writeln!(
+            out,
+            "# HELP p2proxyd_peer_bytes_total Bytes proxied per peer and direction"
+        );
+        let _ = writeln!(out, "# TYPE p2proxyd_peer_bytes_total counter");
+        for (peer, counts) in &peer_bytes {
+            write_directions(
+                &mut out,
+                "p2proxyd_peer_bytes_total",
+                &format!("peer=\"{peer}\""),
+                *counts,
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP p2proxyd_stream_duration_seconds Duration of closed streams per route"
+        );
+        let _ = writeln!(out, "# TYPE p2proxyd_stream_duration_seconds histogram");
+        for (route, m) in &routes {
+            let route = escape_label(route);
+            for (bound, count) in DURATION_BUCKETS.iter().zip(m.duration_buckets) {
+                let _ = writeln!(
+                    out,
+                    "p2proxyd_stream_duration_seconds_bucket{{route=\"{route}\",le=\"{bound}\"}} {count}"
+                );
+            }
+            let _ = writeln!(
+                out,
+                "p2proxyd_stream_duration_seconds_bucket{{route=\"{route}\",le=\"+Inf\"}} {}",
+                m.duration_count
+            );
+            let _ = writeln!(
+                out,
+                "p2proxyd_stream_duration_seconds_sum{{route=\"{route}\"}} {}",
+                m.duration_sum
+            );
+            let _ = writeln!(
+                out,
+                "p2proxyd_stream_duration_seconds_count{{route=\"{route}\"}} {}",
+                m.duration_count
+            );
+        }
+        out
+    }
+}
+
+fn write_directions(out: &mut String, name: &str, labels: &str, counts: ByteCounts) {
+    let _ = writeln!(
+        out,
+        "{name}{{{labels},direction=\"upstream_to_downstream\"}} {}",
+        counts.upstream_to_downstream
+    );
+    let _ = writeln!(
+        out,
+        "{name}{{{labels},direction=\"downstream_to_upstream\"}} {}",
+        counts.downstream_to_upstream
+    );
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl StreamStats {
+    fn counts(&self) -> ByteCounts {
+        ByteCounts {
+            upstream_to_downstream: self.upstream_to_downstream.load(Ordering::Relaxed),
+            downstream_to_upstream: self.downstream_to_upstream.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Moves a stream's bytes and duration into the totals when dropped
+pub(crate) struct StreamMetricsGuard<'a> {
+    metrics: &'a Metrics,
+    id: u64,
+    started: Instant,
+}
+
+impl Drop for StreamMetricsGuard<'_> {
+    fn drop(&mut self) {
+        let duration = self.started.elapsed().as_secs_f64();
+        let mut inner = self.metrics.lock();
+        let Some(stream) = inner.open_streams.remove(&self.id) else {
+            return;
+        };
+        let counts = stream.stats.counts();
+        let route = inner.routes.entry(stream.route).or_default();
+        route.active_streams = route.active_streams.saturating_sub(1);
+        route.bytes.upstream_to_downstream += counts.upstream_to_downstream;
+        route.bytes.downstream_to_upstream += counts.downstream_to_upstream;
+        for (bound, count) in DURATION_BUCKETS
+            .iter()
+            .zip(route.duration_buckets.iter_mut())
+        {
+            if duration <= *bound {
+                *count += 1;
+            }
+        }
+        route.duration_count += 1;
+        route.duration_sum += duration;
+        let peer = inner.peers.entry(stream.peer).or_default();
+        peer.upstream_to_downstream += counts.upstream_to_downstream;
+        peer.downstream_to_upstream += counts.downstream_to_upstream;
+    }
+}
+
+pub fn spawn_metrics_server(listen: SocketAddr, metrics: Arc<Metrics>) {
+    tokio::task::spawn(async move {
+        if let Err(e) = run_metrics_server(listen, metrics).await {
+            tracing::error!("metrics server error: {}", display_chain(&*e));
+        }
+    });
+}
+
+async fn run_metrics_server(listen: SocketAddr, metrics: Arc<Metrics>) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(listen)
+        .await
+        .with_context(|| format!("failed to bind metrics listener at {listen}"))?;
+    tracing::info!("serving metrics at http://{listen}/metrics");
+    loop {
+        let (stream, _addr) = listener
+            .accept()
+            .await
+            .context("failed to accept metrics connection")?;
+        let metrics = metrics.clone();
+        tokio::task::spawn(async move {
+            if let Err(e) = serve_scrape(stream, &metrics).await {
+                tracing::debug!("metrics scrape failed: {}", display_chain(&*e));
+            }
+        });
+    }
+}
+
+// A scraper sends a single GET, so a full http implementation isn't necessary.
+// Read the request head, answer, and close.
+async fn serve_scrape(mut stream: TcpStream, metrics: &Metrics) -> anyhow::Result<()> {
+    let mut head = Vec::with_capacity(1024);
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > 16 * 1024 {
+            anyhow::bail!("metrics request head too large");
+        }
+        let read = tokio::time::timeout(std::time::Duration::from_secs(5), stream.read(&mut buf))
+            .await
+            .context("timed out reading metrics request")?
+            .context("failed to read metrics request")?;
+        if read == 0 {
+            anyhow::bail!("metrics client disconnected before sending a request");
+        }
+        head.extend_from_slice(&buf[..read]);
+    }
+    let request_line = head
+        .split(|b| *b == b'\r')
+        .next()
+        .map(String::from_utf8_lossy)
+        .unwrap_or_default();
+    let path = request_line.split(' ').nth(1).unwrap_or_default();
+    let response = if path == "/metrics" || path.starts_with("/metrics?") {
+        let body = metrics.render();
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+    stream
+        .write_all(response.as_bytes())
+        .await
+        .context("failed to write metrics response")?;
+    let _ = stream.shutdown().await;
+    Ok(())
+}
@@ -0,0 +1,295 @@
+mod connection;
+mod udp;
+
+use crate::access_log::AccessLogHandle;
+use crate::ban::{BanPolicy, Bans};
+use crate::limits::Limits;
+use crate::metrics::{ConnectionOutcome, Metrics};
+use crate::proto::connection::{spawn_client_connection, spawn_redirect};
+use crate::quota::Usage;
+use crate::registry::ActiveConnections;
+use crate::routes::{Routes, SharedRoutes};
+use iroh::NodeId;
+use iroh::endpoint::Connection;
+use iroh::protocol::{AcceptError, ProtocolHandler};
+use p2proxy_lib::display_chain;
+use p2proxy_lib::proto::SHUTDOWN_QUIC_ERROR_CODE;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How often to check if the open streams have finished while draining
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Triggered once when the daemon starts shutting down,
+/// from then on no new connections or streams are accepted.
+#[derive(Debug)]
+pub struct ShutdownSignal {
+    sender: tokio::sync::watch::Sender<bool>,
+}
+
+impl ShutdownSignal {
+    fn new() -> Self {
+        Self {
+            sender: tokio::sync::watch::Sender::new(false),
+        }
+    }
+
+    pub fn trigger(&self) {
+        self.sender.send_replace(true);
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn is_triggered(&self) -> bool {
+        *self.sender.borrow()
+    }
+
+    /// Completes once the shutdown has been triggered
+    pub async fn triggered(&self) {
+        let mut recv = self.sender.subscribe();
+        // The sender is owned by self, so it can't be dropped while waiting
+        let _ = recv.wait_for(|triggered| *triggered).await;
+    }
+}
+
+/// State shared by every identity served by the daemon, cheap to clone.
+/// Released once the last clone, and every protocol and connection using it, is dropped.
+#[derive(Debug, Clone)]
+pub struct ProxyState {
+    access_log_handle: AccessLogHandle,
+    limits: Arc<Limits>,
+    active: Arc<ActiveConnections>,
+    metrics: Arc<Metrics>,
+    usage: Arc<Usage>,
+    bans: Arc<Bans>,
+    shutdown: Arc<ShutdownSignal>,
+}
+
+impl ProxyState {
+    #[must_use]
+    pub fn new(
+        access_log_handle: AccessLogHandle,
+        limits: Limits,
+        ban_policy: Option<BanPolicy>,
+    ) -> Self {
+        Self {
+            access_log_handle,
+            limits: Arc::new(limits),
+            active: Arc::new(ActiveConnections::default()),
+            metrics: Arc::new(Metrics::default()),
+            usage: Arc::new(Usage::new()),
+            bans: Arc::new(Bans::new(ban_policy)),
+            shutdown: Arc::new(ShutdownSignal::new()),
+        }
+    }
+
+    /// The connections and streams currently served, across all identities
+    #[inline]
+    #[must_use]
+    pub fn active_connections(&self) -> &Arc<ActiveConnections> {
+        &self.active
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    /// Traffic of peers with a quota, to persist it across restarts
+    #[inline]
+    #[must_use]
+    pub fn usage(&self) -> &Arc<Usage> {
+        &self.usage
+    }
+
+    /// Peers and addresses banned after repeated rejections, to list and lift them
+    #[inline]
+    #[must_use]
+    pub fn bans(&self) -> &Arc<Bans> {
+        &self.bans
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn shutdown_signal(&self) -> &Arc<ShutdownSignal> {
+        &self.shutdown
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn access_log_handle(&self) -> &AccessLogHandle {
+        &self.access_log_handle
+    }
+
+    /// Stops accepting connections and streams, then waits for open streams to finish,
+    /// for at most `timeout`, before closing all connections.
+    /// Connections without open streams are closed as soon as possible, so that peers reconnect elsewhere
+    /// (or wait for the restart) instead of opening streams that won't be accepted.
+    pub async fn shutdown(&self, timeout: Duration) {
+        self.shutdown.trigger();
+        let active = &self.active;
+        let deadline = Instant::now() + timeout;
+        tracing::info!(
+            "shutting down, draining {} open streams for at most {timeout:?}",
+            active.stream_count()
+        );
+        loop {
+            active.close_idle(SHUTDOWN_QUIC_ERROR_CODE, b"shutting down");
+            let open = active.stream_count();
+            if open == 0 {
+                tracing::info!("all streams drained");
+                break;
+            }
+            if Instant::now() >= deadline {
+                tracing::warn!("drain timeout reached, closing {open} open streams");
+                break;
+            }
+            tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+        active.close_all(SHUTDOWN_QUIC_ERROR_CODE, b"shutting down");
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(super) struct DownstreamConnectionInheritedState {
+    pub(super) routes: Arc<SharedRoutes>,
+    /// Set when this protocol serves a key that's being rotated out, the node id that replaces it
+    pub(super) successor: Option<NodeId>,
+    pub(super) access_log_handle: AccessLogHandle,
+    pub(super) limits: Arc<Limits>,
+    pub(super) active: Arc<ActiveConnections>,
+    pub(super) metrics: Arc<Metrics>,
+    pub(super) usage: Arc<Usage>,
+    pub(super) bans: Arc<Bans>,
+    pub(super) shutdown: Arc<ShutdownSignal>,
+}
+
+/// Serves a single identity, its route table is its own, everything else is shared through [`ProxyState`]
+#[derive(Debug)]
+pub struct P2ProxyProto {
+    inherited: Arc<DownstreamConnectionInheritedState>,
+}
+impl P2ProxyProto {
+    /// `identity` names the identity in the access log, to tell apart identities that share a [`ProxyState`].
+    /// Without one, entries are logged as they are for a single identity.
+    #[must_use]
+    pub fn new(identity: Option<&str>, routes: Routes, state: &ProxyState) -> Self {
+        let access_log_handle = match identity {
+            Some(identity) => state.access_log_handle.for_identity(identity),
+            None => state.access_log_handle.clone(),
+        };
+        let inherited = DownstreamConnectionInheritedState {
+            routes: Arc::new(SharedRoutes::new(routes)),
+            successor: None,
+            access_log_handle,
+            limits: state.limits.clone(),
+            active: state.active.clone(),
+            metrics: state.metrics.clone(),
+            usage: state.usage.clone(),
+            bans: state.bans.clone(),
+            shutdown: state.shutdown.clone(),
+        };
+        Self {
+            inherited: Arc::new(inherited),
+        }
+    }
+
+    /// Serves the same routes as this protocol, for a previous key of the same identity.
+    /// Every connection is told that `successor` replaces the node id it connected to.
+    #[must_use]
+    pub fn retired(&self, successor: NodeId) -> Self {
+        let inherited = DownstreamConnectionInheritedState {
+            successor: Some(successor),
+            ..(*self.inherited).clone()
+        };
+        Self {
+            inherited: Arc::new(inherited),
+        }
+    }
+
+    /// A handle to the route table used by this protocol, to be able to swap it out at runtime
+    #[inline]
+    #[must_use]
+    pub fn shared_routes(&self) -> Arc<SharedRoutes> {
+        self.inherited.routes.clone()
+    }
+}
+
+impl ProtocolHandler for P2ProxyProto {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        let addr = connection.remote_address();
+        let nid = match connection.remote_node_id() {
+            Ok(nid) => nid,
+            Err(e) => {
+                self.inherited
+                    .access_log_handle
+                    .log_rejected_missing_node_id(addr);
+                self.inherited
+                    .metrics
+                    .record_connection(ConnectionOutcome::MissingNodeId);
+                tracing::warn!("unknown remote node connected: {}", display_chain(&e));
+                connection.close(
+                    p2proxy_lib::proto::GENERIC_QUIC_ERROR_CODE,
+                    b"missing node id",
+                );
+                return Err(AcceptError::NotAllowed {});
+            }
+        };
+        if let Some(ban) = self.inherited.bans.is_banned(nid, addr.ip()) {
+            tracing::debug!("refusing connection from {nid}: {ban} is banned");
+            self.inherited
+                .metrics
+                .record_connection(ConnectionOutcome::Banned);
+            self.inherited
+                .access_log_handle
+                .log_rejected_banned(addr, nid, ban);
+            connection.close(
+                p2proxy_lib::proto::BANNED_QUIC_ERROR_CODE,
+                self.inherited.bans.close_reason(&ban).as_bytes(),
+            );
+            return Err(AcceptError::NotAllowed {});
+        }
+        if self.inherited.shutdown.is_triggered() {
+            tracing::debug!("shutting down, refusing connection from {nid}");
+            connection.close(
+                p2proxy_lib::proto::SHUTDOWN_QUIC_ERROR_CODE,
+                b"shutting down",
+            );
+            return Err(AcceptError::NotAllowed {});
+        }
+        let registered = match self.inherited.active.register_connection(
+            nid,
+            addr,
+            connection.clone(),
+            self.inherited.routes.clone(),
+            &self.inherited.limits,
+        ) {
+            Ok(registered) => registered,
+            Err(limit) => {
+                tracing::debug!("refusing connection from {nid}: {limit}");
+                self.inherited
+                    .metrics
+                    .record_connection(ConnectionOutcome::LimitExceeded);
+                self.inherited
+                    .access_log_handle
+                    .log_rejected_limit(addr, nid, limit);
+                connection.close(
+                    p2proxy_lib::proto::LIMITED_QUIC_ERROR_CODE,
+                    b"too many connections",
+                );
+                return Err(AcceptError::NotAllowed {});
+            }
+        };
+        self.inherited.access_log_handle.log_accepted(addr, nid);
+        self.inherited
+            .metrics
+            .record_connection(ConnectionOutcome::Accepted);
+        if let Some(successor) = self.inherited.successor {
+            spawn_redirect(nid, connection.clone(), successor);
+        }
+        spawn_client_connection(nid, addr, connection, registered, self.inherited.clone());
+        tracing::debug!("accepted connection from {nid}");
+        Ok(())
+    }
+}
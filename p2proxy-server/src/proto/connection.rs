@@ -0,0 +1,695 @@
+use crate::access_log::{ClosedStream, StreamCloseReason};
+use crate::limits::LimitExceeded;
+use crate::metrics::ConnectionOutcome;
+use crate::proto::DownstreamConnectionInheritedState;
+use crate::proto::udp::run_proxied_udp;
+use crate::quota::{PeerUsage, Quota, QuotaExceeded, Usage};
+use crate::registry::{ConnectionGuard, PendingStream, StreamGuard};
+use crate::routes::{PortConfig, RouteLimits, RouteTarget, SocketAddrGetResult};
+use anyhow::{Context, bail};
+use iroh::NodeId;
+use iroh::endpoint::{Connection, ConnectionError, RecvStream, SendStream};
+use p2proxy_lib::datagram::FlowActivity;
+use p2proxy_lib::display_chain;
+use p2proxy_lib::proto::{HEADER_LENGTH, encode_redirect};
+use p2proxy_lib::proxy_copy_buf::{BufCopyError, BufferedCopy, TcpOrQuicRead, TcpOrQuicWrite};
+use p2proxy_lib::rate_limit::Throttle;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// How often an open stream checks whether its peer has used up its quota
+const QUOTA_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+pub fn spawn_client_connection(
+    peer: NodeId,
+    remote_addr: SocketAddr,
+    upstream_connection: Connection,
+    registered: ConnectionGuard,
+    downstream_connection_inherited_state: Arc<DownstreamConnectionInheritedState>,
+) {
+    let registered = Arc::new(registered);
+    tokio::task::spawn(async move {
+        if let Err(e) = run_client_connection(
+            peer,
+            remote_addr,
+            upstream_connection,
+            registered,
+            downstream_connection_inherited_state,
+        )
+        .await
+        {
+            tracing::error!("client connection error: {}", display_chain(&*e));
+        }
+    });
+}
+
+/// Tells the peer which node id replaces the one it connected to, the connection keeps working as usual.
+/// Clients that don't know about redirects never accept the stream, and aren't affected.
+pub fn spawn_redirect(peer: NodeId, upstream_connection: Connection, successor: NodeId) {
+    tokio::task::spawn(async move {
+        let res = async {
+            let mut send = upstream_connection
+                .open_uni()
+                .await
+                .context("failed to open redirect stream")?;
+            send.write_all(&encode_redirect(&successor))
+                .await
+                .context("failed to write redirect")?;
+            send.finish().context("failed to finish redirect stream")?;
+            anyhow::Ok(())
+        }
+        .await;
+        match res {
+            Ok(()) => tracing::debug!("redirected {peer} to {successor}"),
+            Err(e) => tracing::debug!("failed to redirect {peer}: {}", display_chain(&*e)),
+        }
+    });
+}
+
+async fn run_client_connection(
+    peer: NodeId,
+    remote_addr: SocketAddr,
+    upstream_connection: Connection,
+    registered: Arc<ConnectionGuard>,
+    downstream_connection_inherited_state: Arc<DownstreamConnectionInheritedState>,
+) -> anyhow::Result<()> {
+    loop {
+        // For each unique incoming connection, spawn a new TCP connection downstream
+        let res = tokio::select! {
+            res = upstream_connection.accept_bi() => res,
+            () = downstream_connection_inherited_state.shutdown.triggered() => {
+                // Streams that are already open keep running until drained, the connection stays
+                // registered until it's closed by the drain
+                tracing::debug!("shutting down, no longer accepting streams from {peer}");
+                upstream_connection.closed().await;
+                return Ok(());
+            }
+        };
+        let (mut upstream_write, mut upstream_read) = match res {
+            Ok(o) => o,
+            Err(e) => match map_con_err(&e) {
+                Ok(s) => {
+                    tracing::debug!("{s}");
+                    return Ok(());
+                }
+                Err(e) => {
+                    bail!(
+                        "failed to accept upstream connection: {}",
+                        display_chain(&*e)
+                    );
+                }
+            },
+        };
+        // Counted right away, so that streams parked before sending their route header count towards
+        // the limits as well, and a drain waits for them
+        let pending =
+            match registered.stream_accepted(&downstream_connection_inherited_state.limits) {
+                Ok(pending) => pending,
+                Err(limit) => {
+                    tracing::debug!("refusing stream from {peer}: {limit}");
+                    reject_limit(
+                        peer,
+                        remote_addr,
+                        limit,
+                        &mut upstream_write,
+                        &mut upstream_read,
+                        &downstream_connection_inherited_state,
+                    );
+                    continue;
+                }
+            };
+        let registered = registered.clone();
+        let inherited = downstream_connection_inherited_state.clone();
+        tokio::task::spawn(async move {
+            // The connection stays registered for as long as any of its streams run
+            let _registered = registered;
+            if let Err(e) = run_proxied_tcp(
+                peer,
+                remote_addr,
+                pending,
+                upstream_write,
+                upstream_read,
+                &inherited,
+            )
+            .await
+            {
+                tracing::error!("proxied tcp connection error: {}", display_chain(&*e));
+            }
+        });
+    }
+}
+
+fn map_con_err(e: &ConnectionError) -> anyhow::Result<&'static str> {
+    match e {
+        ConnectionError::VersionMismatch
+        | ConnectionError::TransportError(_)
+        | ConnectionError::Reset
+        | ConnectionError::TimedOut
+        | ConnectionError::LocallyClosed
+        | ConnectionError::CidsExhausted => {
+            bail!("connection error: {}", display_chain(&e));
+        }
+        ConnectionError::ConnectionClosed(_) | ConnectionError::ApplicationClosed(_) => {
+            Ok("connection closed")
+        }
+    }
+}
+
+#[allow(clippy::too_many_lines)]
+async fn run_proxied_tcp(
+    peer: NodeId,
+    remote_addr: SocketAddr,
+    pending: PendingStream,
+    mut upstream_write: SendStream,
+    mut upstream_read: RecvStream,
+    downstream_connection_inherited_state: &DownstreamConnectionInheritedState,
+) -> anyhow::Result<()> {
+    let mut buf = [0u8; HEADER_LENGTH];
+    let header_timeout = downstream_connection_inherited_state.limits.header_timeout;
+    let Ok(res) = tokio::time::timeout(header_timeout, upstream_read.read_exact(&mut buf)).await
+    else {
+        reject_limit(
+            peer,
+            remote_addr,
+            LimitExceeded::HeaderTimeout(header_timeout),
+            &mut upstream_write,
+            &mut upstream_read,
+            downstream_connection_inherited_state,
+        );
+        bail!("peer did not send a route header within {header_timeout:?}");
+    };
+    res.context("failed to write hello to upstream")?;
+    let routes = downstream_connection_inherited_state.routes.load();
+    let port_config = match &buf {
+        p2proxy_lib::proto::PING => {
+            tracing::debug!("received ping from upstream");
+            upstream_write.write_all(b"PONG").await?;
+            let _ = upstream_write.finish();
+            let _ = upstream_read.stop(p2proxy_lib::proto::QUIC_OK_ERROR_CODE);
+            return Ok(());
+        }
+        p2proxy_lib::proto::DEFAULT_ROUTE => match routes.default_route(&peer) {
+            SocketAddrGetResult::Allowed(a) => a,
+            SocketAddrGetResult::NotAllowed => {
+                downstream_connection_inherited_state
+                    .metrics
+                    .record_connection(ConnectionOutcome::NotAllowedPort);
+                downstream_connection_inherited_state
+                    .access_log_handle
+                    .log_rejected_not_allowed_at(
+                        remote_addr,
+                        peer,
+                        "default-route-unconfigured".to_string(),
+                    );
+                record_probe(peer, remote_addr, downstream_connection_inherited_state);
+                let _ = upstream_write.reset(p2proxy_lib::proto::FORBIDDEN_QUIC_ERROR_CODE);
+                let _ = upstream_read.stop(p2proxy_lib::proto::FORBIDDEN_QUIC_ERROR_CODE);
+                anyhow::bail!("peer not allowed to connect to port at default route");
+            }
+            SocketAddrGetResult::NotPresent => {
+                downstream_connection_inherited_state
+                    .metrics
+                    .record_connection(ConnectionOutcome::DefaultRouteMissing);
+                downstream_connection_inherited_state
+                    .access_log_handle
+                    .log_rejected_default_not_present(remote_addr, peer);
+                let _ = upstream_write.reset(p2proxy_lib::proto::FORBIDDEN_QUIC_ERROR_CODE);
+                let _ = upstream_read.stop(p2proxy_lib::proto::FORBIDDEN_QUIC_ERROR_CODE);
+                bail!("no default route configured");
+            }
+            SocketAddrGetResult::Expired(cfg) => {
+                downstream_connection_inherited_state
+                    .metrics
+                    .record_connection(ConnectionOutcome::GrantExpired);
+                downstream_connection_inherited_state
+                    .access_log_handle
+                    .log_rejected_expired_grant(remote_addr, peer, cfg.name.clone());
+                let _ = upstream_write.reset(p2proxy_lib::proto::FORBIDDEN_QUIC_ERROR_CODE);
+                let _ = upstream_read.stop(p2proxy_lib::proto::FORBIDDEN_QUIC_ERROR_CODE);
+                bail!(
+                    "peer's grant for the default route {} has expired",
+                    cfg.name
+                );
+            }
+            SocketAddrGetResult::OutsideSchedule(cfg) => {
+                downstream_connection_inherited_state
+                    .metrics
+                    .record_connection(ConnectionOutcome::OutsideSchedule);
+                downstream_connection_inherited_state
+                    .access_log_handle
+                    .log_rejected_outside_schedule(remote_addr, peer, cfg.name.clone());
+                let _ = upstream_write.reset(p2proxy_lib::proto::FORBIDDEN_QUIC_ERROR_CODE);
+                let _ = upstream_read.stop(p2proxy_lib::proto::FORBIDDEN_QUIC_ERROR_CODE);
+                bail!(
+                    "peer's grant for the default route {} doesn't apply at this time",
+                    cfg.name
+                );
+            }
+        },
+        any => {
+            let Ok(utf8_port_map) = core::str::from_utf8(any) else {
+                downstream_connection_inherited_state
+                    .metrics
+                    .record_connection(ConnectionOutcome::GarbagePortMapping);
+                downstream_connection_inherited_state
+                    .access_log_handle
+                    .log_rejected_garbage_port_mapping(remote_addr, peer, *any);
+                record_probe(peer, remote_addr, downstream_connection_inherited_state);
+                let _ = upstream_write.reset(p2proxy_lib::proto::FORBIDDEN_QUIC_ERROR_CODE);
+                let _ = upstream_read.stop(p2proxy_lib::proto::FORBIDDEN_QUIC_ERROR_CODE);
+                anyhow::bail!(
+                    "peer attempted to connect at a non-utf8 port {}",
+                    String::from_utf8_lossy(any)
+                );
+            };
+            match routes.get(&peer, utf8_port_map) {
+                SocketAddrGetResult::Allowed(a) => a,
+                SocketAddrGetResult::NotAllowed => {
+                    downstream_connection_inherited_state
+                        .metrics
+                        .record_connection(ConnectionOutcome::NotAllowedPort);
+                    downstream_connection_inherited_state
+                        .access_log_handle
+                        .log_rejected_not_allowed_at(remote_addr, peer, utf8_port_map.to_string());
+                    record_probe(peer, remote_addr, downstream_connection_inherited_state);
+                    let _ = upstream_write.reset(p2proxy_lib::proto::FORBIDDEN_QUIC_ERROR_CODE);
+                    let _ = upstream_read.stop(p2proxy_lib::proto::FORBIDDEN_QUIC_ERROR_CODE);
+                    anyhow::bail!("peer not allowed to connect to port at {utf8_port_map}");
+                }
+                SocketAddrGetResult::NotPresent => {
+                    downstream_connection_inherited_state
+                        .metrics
+                        .record_connection(ConnectionOutcome::UnknownPortMapping);
+                    downstream_connection_inherited_state
+                        .access_log_handle
+                        .log_rejected_unknown_port_mapping(
+                            remote_addr,
+                            peer,
+                            utf8_port_map.to_string(),
+                        );
+                    record_probe(peer, remote_addr, downstream_connection_inherited_state);
+                    let _ = upstream_write.reset(p2proxy_lib::proto::FORBIDDEN_QUIC_ERROR_CODE);
+                    let _ = upstream_read.stop(p2proxy_lib::proto::FORBIDDEN_QUIC_ERROR_CODE);
+                    anyhow::bail!("peer attempted to access missing port {utf8_port_map}");
+                }
+                SocketAddrGetResult::Expired(cfg) => {
+                    downstream_connection_inherited_state
+                        .metrics
+                        .record_connection(ConnectionOutcome::GrantExpired);
+                    downstream_connection_inherited_state
+                        .access_log_handle
+                        .log_rejected_expired_grant(remote_addr, peer, cfg.name.clone());
+                    let _ = upstream_write.reset(p2proxy_lib::proto::FORBIDDEN_QUIC_ERROR_CODE);
+                    let _ = upstream_read.stop(p2proxy_lib::proto::FORBIDDEN_QUIC_ERROR_CODE);
+                    anyhow::bail!("peer's grant for port {utf8_port_map} has expired");
+                }
+                SocketAddrGetResult::OutsideSchedule(cfg) => {
+                    downstream_connection_inherited_state
+                        .metrics
+                        .record_connection(ConnectionOutcome::OutsideSchedule);
+                    downstream_connection_inherited_state
+                        .access_log_handle
+                        .log_rejected_outside_schedule(remote_addr, peer, cfg.name.clone());
+                    let _ = upstream_write.reset(p2proxy_lib::proto::FORBIDDEN_QUIC_ERROR_CODE);
+                    let _ = upstream_read.stop(p2proxy_lib::proto::FORBIDDEN_QUIC_ERROR_CODE);
+                    anyhow::bail!(
+                        "peer's grant for port {utf8_port_map} doesn't apply at this time"
+                    );
+                }
+            }
+        }
+    };
+    let (usage, quota) = match routes.peer_quota(&peer) {
+        None => (None, None),
+        Some(quota) => match downstream_connection_inherited_state
+            .usage
+            .check(peer, quota)
+        {
+            Ok(usage) => (
+                Some(usage),
+                Some(StreamQuota {
+                    usage: downstream_connection_inherited_state.usage.clone(),
+                    peer,
+                    quota: *quota,
+                }),
+            ),
+            Err(exceeded) => {
+                let msg = format!("refusing stream from {peer}: {exceeded}");
+                downstream_connection_inherited_state
+                    .metrics
+                    .record_connection(ConnectionOutcome::QuotaExceeded);
+                downstream_connection_inherited_state
+                    .access_log_handle
+                    .log_rejected_quota(remote_addr, peer, exceeded);
+                let _ = upstream_write.reset(p2proxy_lib::proto::QUOTA_QUIC_ERROR_CODE);
+                let _ = upstream_read.stop(p2proxy_lib::proto::QUOTA_QUIC_ERROR_CODE);
+                bail!(msg);
+            }
+        },
+    };
+    let stream = match pending.register_stream(
+        port_config.name.clone(),
+        port_config.target.to_string(),
+        port_config.limits.max_streams,
+    ) {
+        Ok(stream) => stream,
+        Err(limit) => {
+            let msg = format!("refusing stream from {peer}: {limit}");
+            reject_limit(
+                peer,
+                remote_addr,
+                limit,
+                &mut upstream_write,
+                &mut upstream_read,
+                downstream_connection_inherited_state,
+            );
+            bail!(msg);
+        }
+    };
+    let _stream_metrics = downstream_connection_inherited_state
+        .metrics
+        .stream_started(&port_config.name, peer, &stream.stats);
+    let (upstream_to_downstream, downstream_to_upstream) = routes.throttles(port_config, &peer);
+    let traffic = Traffic {
+        upstream_to_downstream,
+        downstream_to_upstream,
+        usage,
+        quota,
+    };
+    let started = Instant::now();
+    let res = proxy_to_target(
+        port_config,
+        upstream_write,
+        upstream_read,
+        &stream,
+        traffic,
+        downstream_connection_inherited_state,
+    )
+    .await;
+    let reason = match &res {
+        Ok(reason) => reason.clone(),
+        Err(e) => StreamCloseReason::Error(display_chain(&**e).to_string()),
+    };
+    downstream_connection_inherited_state
+        .access_log_handle
+        .log_stream_closed(
+            remote_addr,
+            ClosedStream {
+                node_id: peer,
+                route: port_config.name.clone(),
+                default_route: &buf == p2proxy_lib::proto::DEFAULT_ROUTE,
+                downstream: port_config.target.to_string(),
+                bytes_upstream_to_downstream: stream
+                    .stats
+                    .upstream_to_downstream
+                    .load(Ordering::Relaxed),
+                bytes_downstream_to_upstream: stream
+                    .stats
+                    .downstream_to_upstream
+                    .load(Ordering::Relaxed),
+                duration: started.elapsed(),
+                reason,
+            },
+        );
+    res.map(drop)
+}
+
+/// How a stream's traffic is throttled, and accounted for besides its own counters
+pub(super) struct Traffic {
+    pub(super) upstream_to_downstream: Throttle,
+    pub(super) downstream_to_upstream: Throttle,
+    /// Set if the peer has a quota
+    pub(super) usage: Option<Arc<PeerUsage>>,
+    pub(super) quota: Option<StreamQuota>,
+}
+
+/// The peer's quota, so that a stream notices when it's used up while the stream is open
+pub(super) struct StreamQuota {
+    pub(super) usage: Arc<Usage>,
+    pub(super) peer: NodeId,
+    pub(super) quota: Quota,
+}
+
+/// Completes once the peer has used up its quota, never without one.
+/// Checked on an interval, so a stream can go a little over before it's closed.
+pub(super) async fn quota_used_up(quota: Option<&StreamQuota>) -> QuotaExceeded {
+    let Some(quota) = quota else {
+        return std::future::pending().await;
+    };
+    let mut interval = tokio::time::interval(QUOTA_CHECK_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        // Goes through the shared counters, so that they start over when the day or month does
+        if let Err(exceeded) = quota.usage.check(quota.peer, &quota.quota) {
+            return exceeded;
+        }
+    }
+}
+
+fn buffered_copy(
+    counter: Arc<AtomicU64>,
+    throttle: Throttle,
+    usage: Option<&PeerUsage>,
+) -> BufferedCopy<{ 1024 * 64 }> {
+    let copy = BufferedCopy::new_counted(counter).with_throttle(throttle);
+    match usage {
+        Some(usage) => copy
+            .with_counter(usage.today.clone())
+            .with_counter(usage.this_month.clone()),
+        None => copy,
+    }
+}
+
+/// Completes once there was no traffic for `idle_timeout`, never without one
+async fn went_idle(activity: &FlowActivity, idle_timeout: Option<Duration>) -> Duration {
+    match idle_timeout {
+        Some(idle_timeout) => {
+            activity.idle(idle_timeout).await;
+            idle_timeout
+        }
+        None => std::future::pending().await,
+    }
+}
+
+pub(super) fn lifetime_deadline(max_lifetime: Option<Duration>) -> Option<tokio::time::Instant> {
+    max_lifetime.map(|max_lifetime| tokio::time::Instant::now() + max_lifetime)
+}
+
+/// Completes at the `deadline`, never without one
+pub(super) async fn lifetime_over(deadline: Option<tokio::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+fn reject_limit(
+    peer: NodeId,
+    remote_addr: SocketAddr,
+    limit: LimitExceeded,
+    upstream_write: &mut SendStream,
+    upstream_read: &mut RecvStream,
+    downstream_connection_inherited_state: &DownstreamConnectionInheritedState,
+) {
+    downstream_connection_inherited_state
+        .metrics
+        .record_connection(ConnectionOutcome::LimitExceeded);
+    downstream_connection_inherited_state
+        .access_log_handle
+        .log_rejected_limit(remote_addr, peer, limit);
+    let _ = upstream_write.reset(p2proxy_lib::proto::LIMITED_QUIC_ERROR_CODE);
+    let _ = upstream_read.stop(p2proxy_lib::proto::LIMITED_QUIC_ERROR_CODE);
+}
+
+/// Counts a rejected route header towards a ban, and closes the connections of whatever got banned
+fn record_probe(
+    peer: NodeId,
+    remote_addr: SocketAddr,
+    downstream_connection_inherited_state: &DownstreamConnectionInheritedState,
+) {
+    let bans = &downstream_connection_inherited_state.bans;
+    for ban in bans.record_rejection(peer, remote_addr.ip()) {
+        let ban_time = bans
+            .policy()
+            .map_or(Duration::ZERO, |policy| policy.ban_time);
+        tracing::info!(
+            "banning {ban} for {ban_time:?} after repeated rejections, last from {peer} at {remote_addr}"
+        );
+        downstream_connection_inherited_state
+            .access_log_handle
+            .log_banned(remote_addr, peer, ban, ban_time);
+        downstream_connection_inherited_state
+            .active
+            .close_banned(&ban, &bans.close_reason(&ban));
+    }
+}
+
+async fn proxy_to_target(
+    port_config: &PortConfig,
+    upstream_write: SendStream,
+    upstream_read: RecvStream,
+    stream: &StreamGuard,
+    traffic: Traffic,
+    downstream_connection_inherited_state: &DownstreamConnectionInheritedState,
+) -> anyhow::Result<StreamCloseReason> {
+    let metrics = &downstream_connection_inherited_state.metrics;
+    match &port_config.target {
+        RouteTarget::Tcp(socket_addr) => {
+            let mut tcp = tokio::net::TcpStream::connect(socket_addr)
+                .await
+                .inspect_err(|_| metrics.record_connect_failure(&port_config.name))
+                .context("failed to connect to downstream")?;
+            let (downstream_read, downstream_write) = tcp.split();
+            proxy_streams(
+                upstream_write,
+                upstream_read,
+                downstream_read,
+                downstream_write,
+                stream,
+                traffic,
+                &port_config.limits,
+            )
+            .await
+        }
+        RouteTarget::Udp {
+            socket_addr,
+            idle_timeout,
+        } => {
+            run_proxied_udp(
+                upstream_write,
+                upstream_read,
+                *socket_addr,
+                *idle_timeout,
+                port_config.limits.max_lifetime,
+                stream,
+                traffic,
+            )
+            .await
+        }
+        #[cfg(unix)]
+        RouteTarget::Unix(path) => {
+            let mut unix = tokio::net::UnixStream::connect(path)
+                .await
+                .inspect_err(|_| metrics.record_connect_failure(&port_config.name))
+                .with_context(|| {
+                    format!("failed to connect to downstream at {}", path.display())
+                })?;
+            let (downstream_read, downstream_write) = unix.split();
+            proxy_streams(
+                upstream_write,
+                upstream_read,
+                downstream_read,
+                downstream_write,
+                stream,
+                traffic,
+                &port_config.limits,
+            )
+            .await
+        }
+    }
+}
+
+async fn proxy_streams(
+    mut upstream_write: SendStream,
+    mut upstream_read: RecvStream,
+    mut downstream_read: impl TcpOrQuicRead,
+    mut downstream_write: impl TcpOrQuicWrite,
+    stream: &StreamGuard,
+    traffic: Traffic,
+    limits: &RouteLimits,
+) -> anyhow::Result<StreamCloseReason> {
+    let activity = Arc::new(FlowActivity::new());
+    let deadline = lifetime_deadline(limits.max_lifetime);
+    let mut upstream_to_downstream = buffered_copy(
+        stream.stats.upstream_to_downstream.clone(),
+        traffic.upstream_to_downstream,
+        traffic.usage.as_deref(),
+    )
+    .with_activity(activity.clone());
+    let mut downstream_to_upstream = buffered_copy(
+        stream.stats.downstream_to_upstream.clone(),
+        traffic.downstream_to_upstream,
+        traffic.usage.as_deref(),
+    )
+    .with_activity(activity.clone());
+    // Kept across iterations, the copies complete every time they've moved some bytes
+    let used_up = quota_used_up(traffic.quota.as_ref());
+    tokio::pin!(used_up);
+    loop {
+        tokio::select! {
+            idle_timeout = went_idle(&activity, limits.idle_timeout) => {
+                tracing::info!("stream idle for {idle_timeout:?}, closing");
+                let _ = upstream_write.reset(p2proxy_lib::proto::IDLE_TIMEOUT_QUIC_ERROR_CODE);
+                let _ = upstream_read.stop(p2proxy_lib::proto::IDLE_TIMEOUT_QUIC_ERROR_CODE);
+                return Ok(StreamCloseReason::IdleTimeout);
+            }
+            () = lifetime_over(deadline) => {
+                tracing::info!("stream open for its maximum lifetime, closing");
+                let _ = upstream_write.reset(p2proxy_lib::proto::MAX_LIFETIME_QUIC_ERROR_CODE);
+                let _ = upstream_read.stop(p2proxy_lib::proto::MAX_LIFETIME_QUIC_ERROR_CODE);
+                return Ok(StreamCloseReason::MaxLifetime);
+            }
+            () = stream.kick.notified() => {
+                tracing::info!("stream kicked by operator");
+                let _ = upstream_write.reset(p2proxy_lib::proto::KICKED_QUIC_ERROR_CODE);
+                let _ = upstream_read.stop(p2proxy_lib::proto::KICKED_QUIC_ERROR_CODE);
+                return Ok(StreamCloseReason::Kicked);
+            }
+            exceeded = &mut used_up => {
+                tracing::info!("peer used up its quota, closing stream: {exceeded}");
+                let _ = upstream_write.reset(p2proxy_lib::proto::QUOTA_QUIC_ERROR_CODE);
+                let _ = upstream_read.stop(p2proxy_lib::proto::QUOTA_QUIC_ERROR_CODE);
+                return Ok(StreamCloseReason::QuotaExceeded);
+            }
+            res = upstream_to_downstream.copy(&mut upstream_read, &mut downstream_write) => {
+                if matches!(res, Err(BufCopyError::QuicEoF)) {
+                    let _ = upstream_write.finish();
+                    // Pass the end of the stream on, instead of the target seeing a dropped socket
+                    let _ = downstream_write.shutdown().await;
+                } else if res.is_err() {
+                    let _ = upstream_write.reset(p2proxy_lib::proto::GENERIC_QUIC_ERROR_CODE);
+                    let _ = upstream_read.stop(p2proxy_lib::proto::GENERIC_QUIC_ERROR_CODE);
+                }
+                match res {
+                    Ok(()) => {}
+                    Err(BufCopyError::QuicClosed(c)) => {
+                        tracing::debug!("Quic connection closed with code={c}");
+                        return Ok(StreamCloseReason::UpstreamClosed);
+                    }
+                    Err(BufCopyError::QuicEoF) => {
+                        tracing::debug!("Quic stream finished by the peer");
+                        return Ok(StreamCloseReason::UpstreamClosed);
+                    }
+                    Err(BufCopyError::TCPEoF) => {
+                        tracing::debug!("Tcp connection end of file");
+                        return Ok(StreamCloseReason::DownstreamEof);
+                    }
+                    Err(BufCopyError::Unactionable(e)) => return Err(e),
+                    Err(e) => return Ok(StreamCloseReason::from_buf_copy_error(&e)),
+                }
+            }
+            res = downstream_to_upstream.copy(&mut downstream_read, &mut upstream_write) => {
+                if res.is_err() {
+                    let _ = upstream_write.reset(p2proxy_lib::proto::GENERIC_QUIC_ERROR_CODE);
+                    let _ = upstream_read.stop(p2proxy_lib::proto::GENERIC_QUIC_ERROR_CODE);
+                }
+                match res {
+                    Ok(()) => {}
+                    Err(BufCopyError::QuicClosed(c)) => {
+                        tracing::debug!("Quic connection closed with code={c}");
+                        return Ok(StreamCloseReason::UpstreamClosed);
+                    }
+                    Err(BufCopyError::TCPEoF) => {
+                        tracing::debug!("Tcp connection end of file");
+                        return Ok(StreamCloseReason::DownstreamEof);
+                    }
+                    Err(BufCopyError::Unactionable(e)) => return Err(e),
+                    Err(e) => return Ok(StreamCloseReason::from_buf_copy_error(&e)),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use crate::access_log::StreamCloseReason;
+use crate::proto::connection::{Traffic, lifetime_deadline, lifetime_over, quota_used_up};
+use crate::quota::PeerUsage;
+use crate::registry::StreamGuard;
+use anyhow::Context;
+use iroh::endpoint::{RecvStream, SendStream};
+use p2proxy_lib::datagram::{FlowActivity, MAX_DATAGRAM_SIZE, read_datagram, write_datagram};
+use p2proxy_lib::rate_limit::Throttle;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+/// Relays a single UDP flow, one stream from a peer maps to one local UDP socket
+pub(super) async fn run_proxied_udp(
+    mut upstream_write: SendStream,
+    mut upstream_read: RecvStream,
+    downstream_addr: SocketAddr,
+    idle_timeout: Duration,
+    max_lifetime: Option<Duration>,
+    stream: &StreamGuard,
+    traffic: Traffic,
+) -> anyhow::Result<StreamCloseReason> {
+    let bind_addr = if downstream_addr.is_ipv4() {
+        SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)
+    } else {
+        SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0)
+    };
+    let socket = UdpSocket::bind(bind_addr)
+        .await
+        .context("failed to bind udp socket for downstream")?;
+    socket
+        .connect(downstream_addr)
+        .await
+        .context("failed to connect udp socket to downstream")?;
+    let activity = FlowActivity::new();
+    let res = tokio::select! {
+        res = upstream_to_downstream(&mut upstream_read, &socket, &activity, &stream.stats.upstream_to_downstream, &traffic.upstream_to_downstream, traffic.usage.as_deref()) => res.map(|()| StreamCloseReason::UpstreamClosed),
+        res = downstream_to_upstream(&socket, &mut upstream_write, &activity, &stream.stats.downstream_to_upstream, &traffic.downstream_to_upstream, traffic.usage.as_deref()) => res.map(|()| StreamCloseReason::DownstreamEof),
+        () = stream.kick.notified() => {
+            tracing::info!("udp flow to {downstream_addr} kicked by operator");
+            let _ = upstream_write.reset(p2proxy_lib::proto::KICKED_QUIC_ERROR_CODE);
+            let _ = upstream_read.stop(p2proxy_lib::proto::KICKED_QUIC_ERROR_CODE);
+            return Ok(StreamCloseReason::Kicked);
+        }
+        () = lifetime_over(lifetime_deadline(max_lifetime)) => {
+            tracing::info!("udp flow to {downstream_addr} open for its maximum lifetime, closing");
+            let _ = upstream_write.reset(p2proxy_lib::proto::MAX_LIFETIME_QUIC_ERROR_CODE);
+            let _ = upstream_read.stop(p2proxy_lib::proto::MAX_LIFETIME_QUIC_ERROR_CODE);
+            return Ok(StreamCloseReason::MaxLifetime);
+        }
+        exceeded = quota_used_up(traffic.quota.as_ref()) => {
+            tracing::info!("udp flow to {downstream_addr} closed, peer used up its quota: {exceeded}");
+            let _ = upstream_write.reset(p2proxy_lib::proto::QUOTA_QUIC_ERROR_CODE);
+            let _ = upstream_read.stop(p2proxy_lib::proto::QUOTA_QUIC_ERROR_CODE);
+            return Ok(StreamCloseReason::QuotaExceeded);
+        }
+        () = activity.idle(idle_timeout) => {
+            tracing::debug!("udp flow to {downstream_addr} idle for {idle_timeout:?}, closing");
+            Ok(StreamCloseReason::IdleTimeout)
+        }
+    };
+    if res.is_err() {
+        let _ = upstream_write.reset(p2proxy_lib::proto::GENERIC_QUIC_ERROR_CODE);
+        let _ = upstream_read.stop(p2proxy_lib::proto::GENERIC_QUIC_ERROR_CODE);
+    } else {
+        let _ = upstream_write.finish();
+        let _ = upstream_read.stop(p2proxy_lib::proto::QUIC_OK_ERROR_CODE);
+    }
+    res
+}
+
+async fn upstream_to_downstream(
+    upstream_read: &mut RecvStream,
+    socket: &UdpSocket,
+    activity: &FlowActivity,
+    counter: &AtomicU64,
+    throttle: &Throttle,
+    usage: Option<&PeerUsage>,
+) -> anyhow::Result<()> {
+    let mut buf = Box::new([0u8; MAX_DATAGRAM_SIZE]);
+    while let Some(len) = read_datagram(upstream_read, &mut buf).await? {
+        activity.touch();
+        throttle.acquire_all(len).await;
+        socket
+            .send(&buf[..len])
+            .await
+            .context("failed to send datagram downstream")?;
+        counter.fetch_add(len as u64, Ordering::Relaxed);
+        if let Some(usage) = usage {
+            usage.add(len as u64);
+        }
+    }
+    tracing::debug!("upstream finished udp flow");
+    Ok(())
+}
+
+async fn downstream_to_upstream(
+    socket: &UdpSocket,
+    upstream_write: &mut SendStream,
+    activity: &FlowActivity,
+    counter: &AtomicU64,
+    throttle: &Throttle,
+    usage: Option<&PeerUsage>,
+) -> anyhow::Result<()> {
+    let mut buf = Box::new([0u8; MAX_DATAGRAM_SIZE]);
+    loop {
+        let len = socket
+            .recv(&mut buf[..])
+            .await
+            .context("failed to receive datagram from downstream")?;
+        activity.touch();
+        throttle.acquire_all(len).await;
+        write_datagram(upstream_write, &buf[..len]).await?;
+        counter.fetch_add(len as u64, Ordering::Relaxed);
+        if let Some(usage) = usage {
+            usage.add(len as u64);
+        }
+    }
+}
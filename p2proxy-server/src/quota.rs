@@ -0,0 +1,221 @@
+use anyhow::Context;
+use iroh::NodeId;
+use rustc_hash::FxHashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use time::format_description::well_known::Iso8601;
+use time::{Date, OffsetDateTime};
+
+/// Bytes a peer may move, both directions summed, per day and per month in UTC. `None` is unlimited.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Hash)]
+pub struct Quota {
+    pub bytes_per_day: Option<u64>,
+    pub bytes_per_month: Option<u64>,
+}
+
+/// The quota a peer used up, new streams are refused until its period is over
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum QuotaExceeded {
+    Daily { used: u64, quota: u64 },
+    Monthly { used: u64, quota: u64 },
+}
+
+impl QuotaExceeded {
+    /// A stable name for the period, for structured logs
+    #[must_use]
+    pub fn period(&self) -> &'static str {
+        match self {
+            Self::Daily { .. } => "day",
+            Self::Monthly { .. } => "month",
+        }
+    }
+
+    #[must_use]
+    pub fn used(&self) -> u64 {
+        match self {
+            Self::Daily { used, .. } | Self::Monthly { used, .. } => *used,
+        }
+    }
+
+    #[must_use]
+    pub fn quota(&self) -> u64 {
+        match self {
+            Self::Daily { quota, .. } | Self::Monthly { quota, .. } => *quota,
+        }
+    }
+}
+
+impl Display for QuotaExceeded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Daily { used, quota } => write!(f, "{used} of {quota} bytes used today"),
+            Self::Monthly { used, quota } => write!(f, "{used} of {quota} bytes used this month"),
+        }
+    }
+}
+
+/// A peer's counters for the current day and month, added to by its streams while they copy
+#[derive(Debug, Default)]
+pub struct PeerUsage {
+    pub today: Arc<AtomicU64>,
+    pub this_month: Arc<AtomicU64>,
+}
+
+impl PeerUsage {
+    #[inline]
+    pub fn add(&self, bytes: u64) {
+        self.today.fetch_add(bytes, Ordering::Relaxed);
+        self.this_month.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+/// Traffic of the peers that have a quota, counted per peer across all identities.
+/// The counters start over when the UTC day, or month, changes.
+#[derive(Debug)]
+pub struct Usage {
+    inner: Mutex<UsageInner>,
+}
+
+#[derive(Debug)]
+struct UsageInner {
+    day: Date,
+    peers: FxHashMap<NodeId, Arc<PeerUsage>>,
+}
+
+/// The counters at one point in time, to persist them across restarts
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct UsageSnapshot {
+    /// The UTC day the counters are for, as `YYYY-MM-DD`
+    pub day: String,
+    pub peers: Vec<PeerUsageSnapshot>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct PeerUsageSnapshot {
+    pub node_id: NodeId,
+    pub bytes_today: u64,
+    pub bytes_this_month: u64,
+}
+
+impl Usage {
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            inner: Mutex::new(UsageInner {
+                day: today(),
+                peers: FxHashMap::default(),
+            }),
+        }
+    }
+
+    /// Refuses a stream from `peer` if it used up its quota,
+    /// otherwise returns the counters the stream's traffic should be added to
+    pub fn check(&self, peer: NodeId, quota: &Quota) -> Result<Arc<PeerUsage>, QuotaExceeded> {
+        self.check_on(peer, quota, today())
+    }
+
+    /// As [`Usage::check`], on `day` rather than the current UTC day
+    pub fn check_on(
+        &self,
+        peer: NodeId,
+        quota: &Quota,
+        day: Date,
+    ) -> Result<Arc<PeerUsage>, QuotaExceeded> {
+        let mut inner = self.lock();
+        inner.roll_over(day);
+        let usage = inner.peers.entry(peer).or_default().clone();
+        if let Some(quota) = quota.bytes_per_day {
+            let used = usage.today.load(Ordering::Relaxed);
+            if used >= quota {
+                return Err(QuotaExceeded::Daily { used, quota });
+            }
+        }
+        if let Some(quota) = quota.bytes_per_month {
+            let used = usage.this_month.load(Ordering::Relaxed);
+            if used >= quota {
+                return Err(QuotaExceeded::Monthly { used, quota });
+            }
+        }
+        Ok(usage)
+    }
+
+    #[must_use]
+    pub fn snapshot(&self) -> UsageSnapshot {
+        self.snapshot_on(today())
+    }
+
+    /// As [`Usage::snapshot`], on `day` rather than the current UTC day
+    #[must_use]
+    pub fn snapshot_on(&self, day: Date) -> UsageSnapshot {
+        let mut inner = self.lock();
+        inner.roll_over(day);
+        UsageSnapshot {
+            day: inner.day.to_string(),
+            peers: inner
+                .peers
+                .iter()
+                .map(|(node_id, usage)| PeerUsageSnapshot {
+                    node_id: *node_id,
+                    bytes_today: usage.today.load(Ordering::Relaxed),
+                    bytes_this_month: usage.this_month.load(Ordering::Relaxed),
+                })
+                .collect(),
+        }
+    }
+
+    /// Replaces the counters with previously persisted ones, counters of a day or month that's over are dropped
+    pub fn restore(&self, snapshot: &UsageSnapshot) -> anyhow::Result<()> {
+        self.restore_on(snapshot, today())
+    }
+
+    /// As [`Usage::restore`], on `day` rather than the current UTC day
+    pub fn restore_on(&self, snapshot: &UsageSnapshot, day: Date) -> anyhow::Result<()> {
+        let saved_day = Date::parse(&snapshot.day, &Iso8601::DATE)
+            .with_context(|| format!("invalid usage day '{}'", snapshot.day))?;
+        let mut inner = self.lock();
+        inner.day = saved_day;
+        inner.peers = snapshot
+            .peers
+            .iter()
+            .map(|peer| {
+                let usage = PeerUsage {
+                    today: Arc::new(AtomicU64::new(peer.bytes_today)),
+                    this_month: Arc::new(AtomicU64::new(peer.bytes_this_month)),
+                };
+                (peer.node_id, Arc::new(usage))
+            })
+            .collect();
+        inner.roll_over(day);
+        Ok(())
+    }
+
+    #[inline]
+    fn lock(&self) -> std::sync::MutexGuard<'_, UsageInner> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl UsageInner {
+    fn roll_over(&mut self, day: Date) {
+        if day == self.day {
+            return;
+        }
+        let new_month = (day.year(), day.month()) != (self.day.year(), self.day.month());
+        for usage in self.peers.values() {
+            usage.today.store(0, Ordering::Relaxed);
+            if new_month {
+                usage.this_month.store(0, Ordering::Relaxed);
+            }
+        }
+        if new_month {
+            // Peers that no longer have open streams start from nothing anyway
+            self.peers.retain(|_, usage| Arc::strong_count(usage) > 1);
+        }
+        self.day = day;
+    }
+}
+
+fn today() -> Date {
+    OffsetDateTime::now_utc().date()
+}
@@ -0,0 +1,403 @@
+use crate::ban::BanKey;
+use crate::limits::{LimitExceeded, Limits};
+use crate::routes::SharedRoutes;
+use iroh::NodeId;
+use iroh::endpoint::{Connection, VarInt};
+use rustc_hash::FxHashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+use tokio::sync::Notify;
+
+/// Book-keeping of the connections and streams that are currently open,
+/// so that they can be listed and closed by an operator.
+#[derive(Debug, Default)]
+pub struct ActiveConnections {
+    inner: Mutex<ActiveConnectionsInner>,
+}
+
+#[derive(Debug, Default)]
+struct ActiveConnectionsInner {
+    next_id: u64,
+    connections: FxHashMap<u64, ActiveConnection>,
+}
+
+#[derive(Debug)]
+struct ActiveConnection {
+    peer: NodeId,
+    /// The route table of the identity the peer connected to, per-route limits are per identity
+    identity: Arc<SharedRoutes>,
+    remote_addr: SocketAddr,
+    started: OffsetDateTime,
+    connection: Connection,
+    streams: FxHashMap<u64, ActiveStream>,
+    /// Streams that are accepted, but haven't sent their route header yet
+    pending_streams: usize,
+}
+
+impl ActiveConnection {
+    /// Streams that count towards the global and the peer's limits, whether or not they've sent their route header
+    fn open(&self) -> usize {
+        self.streams.len() + self.pending_streams
+    }
+}
+
+#[derive(Debug)]
+struct ActiveStream {
+    route: String,
+    downstream: String,
+    started: OffsetDateTime,
+    stats: StreamStats,
+    kick: Arc<Notify>,
+}
+
+/// Bytes moved by a stream, updated while it's running
+#[derive(Debug, Default, Clone)]
+pub(crate) struct StreamStats {
+    pub(crate) upstream_to_downstream: Arc<AtomicU64>,
+    pub(crate) downstream_to_upstream: Arc<AtomicU64>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct ConnectionInfo {
+    pub connection_id: u64,
+    pub peer: NodeId,
+    pub remote_addr: SocketAddr,
+    /// Rfc3339
+    pub started: String,
+    pub streams: Vec<StreamInfo>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct StreamInfo {
+    pub stream_id: u64,
+    pub route: String,
+    pub downstream: String,
+    /// Rfc3339
+    pub started: String,
+    pub bytes_upstream_to_downstream: u64,
+    pub bytes_downstream_to_upstream: u64,
+}
+
+impl ActiveConnections {
+    /// Fails without registering if the peer already has as many connections as it's allowed
+    pub(crate) fn register_connection(
+        self: &Arc<Self>,
+        peer: NodeId,
+        remote_addr: SocketAddr,
+        connection: Connection,
+        identity: Arc<SharedRoutes>,
+        limits: &Limits,
+    ) -> Result<ConnectionGuard, LimitExceeded> {
+        let mut inner = self.lock();
+        if let Some(max) = limits.max_connections_per_peer {
+            let open = inner
+                .connections
+                .values()
+                .filter(|con| con.peer == peer)
+                .count();
+            if open >= max {
+                return Err(LimitExceeded::ConnectionsPerPeer(max));
+            }
+        }
+        inner.next_id += 1;
+        let connection_id = inner.next_id;
+        inner.connections.insert(
+            connection_id,
+            ActiveConnection {
+                peer,
+                identity,
+                remote_addr,
+                started: OffsetDateTime::now_utc(),
+                connection,
+                streams: FxHashMap::default(),
+                pending_streams: 0,
+            },
+        );
+        Ok(ConnectionGuard {
+            registry: self.clone(),
+            connection_id,
+        })
+    }
+
+    #[must_use]
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        let inner = self.lock();
+        let mut connections = inner
+            .connections
+            .iter()
+            .map(|(connection_id, con)| {
+                let mut streams = con
+                    .streams
+                    .iter()
+                    .map(|(stream_id, stream)| StreamInfo {
+                        stream_id: *stream_id,
+                        route: stream.route.clone(),
+                        downstream: stream.downstream.clone(),
+                        started: format_timestamp(stream.started),
+                        bytes_upstream_to_downstream: stream
+                            .stats
+                            .upstream_to_downstream
+                            .load(Ordering::Relaxed),
+                        bytes_downstream_to_upstream: stream
+                            .stats
+                            .downstream_to_upstream
+                            .load(Ordering::Relaxed),
+                    })
+                    .collect::<Vec<_>>();
+                streams.sort_by_key(|s| s.stream_id);
+                ConnectionInfo {
+                    connection_id: *connection_id,
+                    peer: con.peer,
+                    remote_addr: con.remote_addr,
+                    started: format_timestamp(con.started),
+                    streams,
+                }
+            })
+            .collect::<Vec<_>>();
+        connections.sort_by_key(|c| c.connection_id);
+        connections
+    }
+
+    /// Closes all connections from `peer`, returns how many were closed
+    #[must_use]
+    pub fn kick_peer(&self, peer: &NodeId) -> usize {
+        let inner = self.lock();
+        let mut kicked = 0;
+        for con in inner.connections.values().filter(|c| &c.peer == peer) {
+            con.connection
+                .close(p2proxy_lib::proto::KICKED_QUIC_ERROR_CODE, b"kicked");
+            kicked += 1;
+        }
+        kicked
+    }
+
+    /// Closes all connections a ban applies to, telling them when it runs out, returns how many were closed
+    pub(crate) fn close_banned(&self, key: &BanKey, until: &str) -> usize {
+        let inner = self.lock();
+        let mut closed = 0;
+        for con in inner.connections.values().filter(|c| match key {
+            BanKey::Peer(peer) => &c.peer == peer,
+            BanKey::Address(ip) => &c.remote_addr.ip() == ip,
+        }) {
+            con.connection
+                .close(p2proxy_lib::proto::BANNED_QUIC_ERROR_CODE, until.as_bytes());
+            closed += 1;
+        }
+        closed
+    }
+
+    /// Closes a single stream, returns whether it was found
+    #[must_use]
+    pub fn kick_stream(&self, stream_id: u64) -> bool {
+        let inner = self.lock();
+        for con in inner.connections.values() {
+            if let Some(stream) = con.streams.get(&stream_id) {
+                stream.kick.notify_one();
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Connections currently open
+    #[must_use]
+    pub fn connection_count(&self) -> usize {
+        self.lock().connections.len()
+    }
+
+    /// Streams currently open across all connections, including those that haven't sent their route header yet
+    #[must_use]
+    pub fn stream_count(&self) -> usize {
+        self.lock()
+            .connections
+            .values()
+            .map(ActiveConnection::open)
+            .sum()
+    }
+
+    /// Closes connections without open streams, returns how many were closed.
+    /// A stream that's still sending its route header keeps its connection open.
+    pub(crate) fn close_idle(&self, code: VarInt, reason: &[u8]) -> usize {
+        let inner = self.lock();
+        let mut closed = 0;
+        for con in inner
+            .connections
+            .values()
+            .filter(|c| c.streams.is_empty() && c.pending_streams == 0)
+        {
+            con.connection.close(code, reason);
+            closed += 1;
+        }
+        closed
+    }
+
+    /// Closes every connection, returns how many were closed
+    pub(crate) fn close_all(&self, code: VarInt, reason: &[u8]) -> usize {
+        let inner = self.lock();
+        for con in inner.connections.values() {
+            con.connection.close(code, reason);
+        }
+        inner.connections.len()
+    }
+
+    #[inline]
+    fn lock(&self) -> std::sync::MutexGuard<'_, ActiveConnectionsInner> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+pub(crate) fn format_timestamp(timestamp: OffsetDateTime) -> String {
+    timestamp
+        .format(&Rfc3339)
+        .unwrap_or_else(|_| timestamp.to_string())
+}
+
+/// Removes the connection from the registry when dropped
+pub(crate) struct ConnectionGuard {
+    registry: Arc<ActiveConnections>,
+    connection_id: u64,
+}
+
+impl ConnectionGuard {
+    /// Counts a stream as open as soon as it's accepted, before its route header is read.
+    /// Fails without counting it if the stream would exceed the global or the peer's limit.
+    pub(crate) fn stream_accepted(&self, limits: &Limits) -> Result<PendingStream, LimitExceeded> {
+        let mut inner = self.registry.lock();
+        if let Some(max) = limits.max_streams {
+            let open: usize = inner.connections.values().map(ActiveConnection::open).sum();
+            if open >= max {
+                return Err(LimitExceeded::Streams(max));
+            }
+        }
+        if let Some(con) = inner.connections.get(&self.connection_id)
+            && let Some(max) = limits.max_streams_per_peer
+        {
+            let open: usize = inner
+                .connections
+                .values()
+                .filter(|c| c.peer == con.peer)
+                .map(ActiveConnection::open)
+                .sum();
+            if open >= max {
+                return Err(LimitExceeded::StreamsPerPeer(max));
+            }
+        }
+        if let Some(con) = inner.connections.get_mut(&self.connection_id) {
+            con.pending_streams += 1;
+        }
+        Ok(PendingStream {
+            registry: self.registry.clone(),
+            connection_id: self.connection_id,
+            pending: true,
+        })
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.registry.lock().connections.remove(&self.connection_id);
+    }
+}
+
+/// A stream that's accepted, but hasn't been registered for a route yet.
+/// It stops counting as open when dropped, or once it's registered.
+pub(crate) struct PendingStream {
+    registry: Arc<ActiveConnections>,
+    connection_id: u64,
+    /// Unset once the stream is registered, it's counted as one of the connection's streams from then on
+    pending: bool,
+}
+
+impl PendingStream {
+    /// Fails without registering if the stream would exceed the route's limit,
+    /// the global and the peer's limits were already checked when it was accepted
+    pub(crate) fn register_stream(
+        mut self,
+        route: String,
+        downstream: String,
+        route_max_streams: Option<usize>,
+    ) -> Result<StreamGuard, LimitExceeded> {
+        let mut inner = self.registry.lock();
+        if let Some(con) = inner.connections.get(&self.connection_id)
+            && let Some(max) = route_max_streams
+        {
+            let open = inner
+                .connections
+                .values()
+                .filter(|c| Arc::ptr_eq(&c.identity, &con.identity))
+                .flat_map(|c| c.streams.values())
+                .filter(|s| s.route == route)
+                .count();
+            if open >= max {
+                return Err(LimitExceeded::StreamsPerRoute(route, max));
+            }
+        }
+        inner.next_id += 1;
+        let stream_id = inner.next_id;
+        let stats = StreamStats::default();
+        let kick = Arc::new(Notify::new());
+        if let Some(con) = inner.connections.get_mut(&self.connection_id) {
+            con.pending_streams = con.pending_streams.saturating_sub(1);
+            self.pending = false;
+            con.streams.insert(
+                stream_id,
+                ActiveStream {
+                    route,
+                    downstream,
+                    started: OffsetDateTime::now_utc(),
+                    stats: stats.clone(),
+                    kick: kick.clone(),
+                },
+            );
+        }
+        drop(inner);
+        Ok(StreamGuard {
+            registry: self.registry.clone(),
+            connection_id: self.connection_id,
+            stream_id,
+            stats,
+            kick,
+        })
+    }
+}
+
+impl Drop for PendingStream {
+    fn drop(&mut self) {
+        if !self.pending {
+            return;
+        }
+        if let Some(con) = self
+            .registry
+            .lock()
+            .connections
+            .get_mut(&self.connection_id)
+        {
+            con.pending_streams = con.pending_streams.saturating_sub(1);
+        }
+    }
+}
+
+/// Removes the stream from the registry when dropped
+pub(crate) struct StreamGuard {
+    registry: Arc<ActiveConnections>,
+    connection_id: u64,
+    stream_id: u64,
+    pub(crate) stats: StreamStats,
+    pub(crate) kick: Arc<Notify>,
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        if let Some(con) = self
+            .registry
+            .lock()
+            .connections
+            .get_mut(&self.connection_id)
+        {
+            con.streams.remove(&self.stream_id);
+        }
+    }
+}
@@ -0,0 +1,642 @@
+#[cfg(test)]
+mod test;
+
+use crate::quota::Quota;
+use crate::schedule::{GrantStatus, TimeBounds};
+use anyhow::{Context, bail};
+use iroh::NodeId;
+use p2proxy_lib::proto::ServerPortMapString;
+use p2proxy_lib::rate_limit::{BandwidthLimit, Throttle, TokenBucket};
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Duration;
+use time::OffsetDateTime;
+
+/// The routes served by one identity, and which peers may use them.
+/// Built with a [`RoutesBuilder`].
+#[derive(Debug)]
+pub struct Routes {
+    default: Option<PortConfig>,
+    inner: FxHashMap<ServerPortMapString, PortConfig>,
+    peer_bandwidth: FxHashMap<NodeId, Arc<Bandwidth>>,
+    peer_quota: FxHashMap<NodeId, Quota>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PortConfig {
+    pub name: String,
+    // An empty here means allow any
+    pub allowed_peers: Option<FxHashSet<NodeId>>,
+    /// Peers that can't use the route, even if `allowed_peers` allows any
+    pub denied_peers: FxHashSet<NodeId>,
+    /// Peers allowed on the route for a while, or at certain times, that aren't in `allowed_peers`.
+    /// Any of a peer's grants that applies when it opens a stream lets it through.
+    pub timed_peers: FxHashMap<NodeId, Vec<TimeBounds>>,
+    pub target: RouteTarget,
+    pub limits: RouteLimits,
+    /// Made from `limits.bandwidth`, shared by all streams on the route
+    pub bandwidth: Option<Arc<Bandwidth>>,
+}
+
+/// Bounds on a single route, `None` is unlimited
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct RouteLimits {
+    /// Streams open on the route across all peers
+    pub max_streams: Option<usize>,
+    /// Bytes moved on the route across all peers, in each direction
+    pub bandwidth: Option<BandwidthLimit>,
+    /// How long a stream can go without moving bytes in either direction.
+    /// UDP flows have their own, in [`RouteTarget::Udp`].
+    pub idle_timeout: Option<Duration>,
+    /// How long a stream can stay open, however busy
+    pub max_lifetime: Option<Duration>,
+}
+
+/// The token buckets of a bandwidth limit, one for each direction,
+/// shared by every stream the limit applies to
+#[derive(Debug)]
+pub struct Bandwidth {
+    pub upstream_to_downstream: Arc<TokenBucket>,
+    pub downstream_to_upstream: Arc<TokenBucket>,
+}
+
+impl Bandwidth {
+    #[must_use]
+    pub fn new(limit: BandwidthLimit) -> Self {
+        Self {
+            upstream_to_downstream: Arc::new(TokenBucket::new(limit)),
+            downstream_to_upstream: Arc::new(TokenBucket::new(limit)),
+        }
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn limit(&self) -> BandwidthLimit {
+        self.upstream_to_downstream.limit()
+    }
+}
+
+/// Where a route's streams are proxied to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouteTarget {
+    Tcp(SocketAddr),
+    /// Each stream is a UDP flow, closed after `idle_timeout` without datagrams in either direction
+    Udp {
+        socket_addr: SocketAddr,
+        idle_timeout: Duration,
+    },
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Display for RouteTarget {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RouteTarget::Tcp(socket_addr) => write!(f, "tcp://{socket_addr}"),
+            RouteTarget::Udp { socket_addr, .. } => write!(f, "udp://{socket_addr}"),
+            #[cfg(unix)]
+            RouteTarget::Unix(path) => write!(f, "unix://{}", path.display()),
+        }
+    }
+}
+
+impl PortConfig {
+    #[must_use]
+    pub fn new(
+        name: String,
+        allowed_peers: Option<FxHashSet<NodeId>>,
+        target: RouteTarget,
+    ) -> Self {
+        Self {
+            name,
+            allowed_peers,
+            denied_peers: FxHashSet::default(),
+            timed_peers: FxHashMap::default(),
+            target,
+            limits: RouteLimits::default(),
+            bandwidth: None,
+        }
+    }
+
+    fn access(&self, nid: &NodeId, at: OffsetDateTime) -> SocketAddrGetResult<'_> {
+        if self.denied_peers.contains(nid) {
+            return SocketAddrGetResult::NotAllowed;
+        }
+        if self
+            .allowed_peers
+            .as_ref()
+            .is_none_or(|allowed_peers| allowed_peers.contains(nid))
+        {
+            return SocketAddrGetResult::Allowed(self);
+        }
+        let Some(grants) = self.timed_peers.get(nid) else {
+            return SocketAddrGetResult::NotAllowed;
+        };
+        let mut expired = true;
+        for grant in grants {
+            match grant.status(at) {
+                GrantStatus::Active => return SocketAddrGetResult::Allowed(self),
+                GrantStatus::Expired => {}
+                GrantStatus::OutsideSchedule => expired = false,
+            }
+        }
+        if expired {
+            SocketAddrGetResult::Expired(self)
+        } else {
+            SocketAddrGetResult::OutsideSchedule(self)
+        }
+    }
+}
+
+pub enum SocketAddrGetResult<'a> {
+    Allowed(&'a PortConfig),
+    NotAllowed,
+    NotPresent,
+    /// The peer was allowed on the route, but every grant that allowed it has expired
+    Expired(&'a PortConfig),
+    /// The peer is allowed on the route, but not at this time of the week
+    OutsideSchedule(&'a PortConfig),
+}
+
+impl Routes {
+    #[inline]
+    #[must_use]
+    pub fn get(&self, node: &NodeId, port: &str) -> SocketAddrGetResult<'_> {
+        self.get_at(node, port, OffsetDateTime::now_utc())
+    }
+
+    /// As [`Routes::get`], with time-bounded grants evaluated at `at` rather than now
+    #[must_use]
+    pub fn get_at(&self, node: &NodeId, port: &str, at: OffsetDateTime) -> SocketAddrGetResult<'_> {
+        let Some(port_cfg) = self.inner.get(port) else {
+            return SocketAddrGetResult::NotPresent;
+        };
+        port_cfg.access(node, at)
+    }
+
+    /// The bandwidth limit shared by all of the peer's streams, if it has one
+    #[inline]
+    #[must_use]
+    pub fn peer_bandwidth(&self, node_id: &NodeId) -> Option<&Arc<Bandwidth>> {
+        self.peer_bandwidth.get(node_id)
+    }
+
+    /// The peer's traffic quota, if it has one
+    #[inline]
+    #[must_use]
+    pub fn peer_quota(&self, node_id: &NodeId) -> Option<&Quota> {
+        self.peer_quota.get(node_id)
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn default_route(&self, node_id: &NodeId) -> SocketAddrGetResult<'_> {
+        self.default_route_at(node_id, OffsetDateTime::now_utc())
+    }
+
+    /// As [`Routes::default_route`], with time-bounded grants evaluated at `at` rather than now
+    #[must_use]
+    pub fn default_route_at(
+        &self,
+        node_id: &NodeId,
+        at: OffsetDateTime,
+    ) -> SocketAddrGetResult<'_> {
+        match &self.default {
+            None => SocketAddrGetResult::NotPresent,
+            Some(cfg) => cfg.access(node_id, at),
+        }
+    }
+
+    /// The throttles for each direction of a stream from `peer` on the route,
+    /// limited by both the route's and the peer's bandwidth, if any
+    #[must_use]
+    pub fn throttles(&self, port_config: &PortConfig, peer: &NodeId) -> (Throttle, Throttle) {
+        let mut upstream_to_downstream = Throttle::default();
+        let mut downstream_to_upstream = Throttle::default();
+        for bandwidth in [
+            port_config.bandwidth.as_ref(),
+            self.peer_bandwidth.get(peer),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            upstream_to_downstream.push(bandwidth.upstream_to_downstream.clone());
+            downstream_to_upstream.push(bandwidth.downstream_to_upstream.clone());
+        }
+        (upstream_to_downstream, downstream_to_upstream)
+    }
+
+    /// Takes over the buckets of bandwidth limits that didn't change,
+    /// so that a reload doesn't hand out a fresh burst, or forget what was used
+    fn keep_buckets(&mut self, previous: &Routes) {
+        for (name, config) in &mut self.inner {
+            keep_bucket(
+                &mut config.bandwidth,
+                previous.inner.get(name).and_then(|c| c.bandwidth.as_ref()),
+            );
+        }
+        if let Some(default) = &mut self.default {
+            // A clone of one of the routes, it should share the route's buckets
+            default.bandwidth = self
+                .inner
+                .values()
+                .find(|c| c.name == default.name)
+                .and_then(|c| c.bandwidth.clone());
+        }
+        for (peer, bandwidth) in &mut self.peer_bandwidth {
+            if let Some(previous) = previous.peer_bandwidth.get(peer)
+                && previous.limit() == bandwidth.limit()
+            {
+                *bandwidth = previous.clone();
+            }
+        }
+    }
+}
+
+fn keep_bucket(bandwidth: &mut Option<Arc<Bandwidth>>, previous: Option<&Arc<Bandwidth>>) {
+    if let (Some(current), Some(previous)) = (bandwidth.as_ref(), previous)
+        && current.limit() == previous.limit()
+    {
+        *bandwidth = Some(previous.clone());
+    }
+}
+
+/// The currently active route table, swapped out when the configuration is reloaded.
+/// Streams take a snapshot when they're routed, a swap only affects streams opened after it.
+#[derive(Debug)]
+pub struct SharedRoutes {
+    inner: RwLock<Arc<Routes>>,
+}
+
+impl SharedRoutes {
+    #[must_use]
+    pub fn new(routes: Routes) -> Self {
+        Self {
+            inner: RwLock::new(Arc::new(routes)),
+        }
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn load(&self) -> Arc<Routes> {
+        self.inner
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    pub fn store(&self, mut routes: Routes) {
+        let mut inner = self.inner.write().unwrap_or_else(PoisonError::into_inner);
+        routes.keep_buckets(&inner);
+        *inner = Arc::new(routes);
+    }
+}
+
+#[derive(Debug)]
+struct RouteSpec {
+    name: String,
+    target: RouteTarget,
+    allow_any_peer: bool,
+}
+
+#[derive(Debug)]
+enum PeerGrant {
+    AllRoutes,
+    Named(Vec<String>),
+}
+
+/// A grant, and when it applies, `None` is always
+type TimedGrant = (NodeId, PeerGrant, Option<TimeBounds>);
+
+/// Collects routes and the peers allowed on them, validated when building.
+/// A route that isn't public needs at least one peer allowed on it.
+/// A deny always wins, over a grant on the route, on all routes, and over a public route.
+#[derive(Debug, Default)]
+pub struct RoutesBuilder {
+    default_route: Option<String>,
+    routes: Vec<RouteSpec>,
+    peers: Vec<TimedGrant>,
+    denied: Vec<(NodeId, Vec<String>)>,
+    route_limits: FxHashMap<String, RouteLimits>,
+    peer_bandwidth: Vec<(NodeId, BandwidthLimit)>,
+    peer_quota: Vec<(NodeId, Quota)>,
+}
+
+impl RoutesBuilder {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The route used when a peer doesn't name one, must also be added as a route
+    #[must_use]
+    pub fn default_route(mut self, name: impl Into<String>) -> Self {
+        self.default_route = Some(name.into());
+        self
+    }
+
+    /// A route that only peers explicitly allowed on it can use
+    #[must_use]
+    pub fn route(mut self, name: impl Into<String>, target: RouteTarget) -> Self {
+        self.routes.push(RouteSpec {
+            name: name.into(),
+            target,
+            allow_any_peer: false,
+        });
+        self
+    }
+
+    /// A route that any peer can use
+    #[must_use]
+    pub fn public_route(mut self, name: impl Into<String>, target: RouteTarget) -> Self {
+        self.routes.push(RouteSpec {
+            name: name.into(),
+            target,
+            allow_any_peer: true,
+        });
+        self
+    }
+
+    #[must_use]
+    pub fn allow_peer_on_all_routes(mut self, peer: NodeId) -> Self {
+        self.peers.push((peer, PeerGrant::AllRoutes, None));
+        self
+    }
+
+    #[must_use]
+    pub fn allow_peer<S: Into<String>>(
+        mut self,
+        peer: NodeId,
+        routes: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.peers.push((
+            peer,
+            PeerGrant::Named(routes.into_iter().map(Into::into).collect()),
+            None,
+        ));
+        self
+    }
+
+    /// As [`Self::allow_peer_on_all_routes`], only while `bounds` hold
+    #[must_use]
+    pub fn allow_peer_on_all_routes_within(mut self, peer: NodeId, bounds: TimeBounds) -> Self {
+        self.peers.push((
+            peer,
+            PeerGrant::AllRoutes,
+            Some(bounds).filter(|b| !b.is_unbounded()),
+        ));
+        self
+    }
+
+    /// As [`Self::allow_peer`], only while `bounds` hold
+    #[must_use]
+    pub fn allow_peer_within<S: Into<String>>(
+        mut self,
+        peer: NodeId,
+        routes: impl IntoIterator<Item = S>,
+        bounds: TimeBounds,
+    ) -> Self {
+        self.peers.push((
+            peer,
+            PeerGrant::Named(routes.into_iter().map(Into::into).collect()),
+            Some(bounds).filter(|b| !b.is_unbounded()),
+        ));
+        self
+    }
+
+    /// Keeps the peer off the routes, whatever allows it on them. The routes need to exist.
+    #[must_use]
+    pub fn deny_peer<S: Into<String>>(
+        mut self,
+        peer: NodeId,
+        routes: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.denied
+            .push((peer, routes.into_iter().map(Into::into).collect()));
+        self
+    }
+
+    /// Limits for a route added with [`Self::route`] or [`Self::public_route`]
+    #[must_use]
+    pub fn route_limits(mut self, name: impl Into<String>, limits: RouteLimits) -> Self {
+        self.route_limits.insert(name.into(), limits);
+        self
+    }
+
+    /// Bytes moved by the peer across all its streams, in each direction
+    #[must_use]
+    pub fn peer_bandwidth(mut self, peer: NodeId, limit: BandwidthLimit) -> Self {
+        self.peer_bandwidth.push((peer, limit));
+        self
+    }
+
+    /// Bytes the peer may move per day or month, across all its streams
+    #[must_use]
+    pub fn peer_quota(mut self, peer: NodeId, quota: Quota) -> Self {
+        self.peer_quota.push((peer, quota));
+        self
+    }
+
+    pub fn build(mut self) -> anyhow::Result<Routes> {
+        let mut paths_unique = FxHashSet::default();
+        let default_route = if let Some(dr_path) = self.default_route {
+            let spm = ServerPortMapString::try_new(dr_path)?;
+            paths_unique.insert(spm.clone());
+            Some(spm)
+        } else {
+            None
+        };
+        let mut denied = denied_peers(&self.denied)?;
+        let mut default_route_hit = None;
+        let mut route_config = FxHashMap::default();
+        for route in self.routes {
+            let server_port_name =
+                ServerPortMapString::try_new(route.name.clone()).with_context(|| {
+                    format!(
+                        "configuration error: server port map string={} is invalid",
+                        route.name
+                    )
+                })?;
+            let is_default_route = if paths_unique.insert(server_port_name.clone()) {
+                false
+            } else if let Some(dr) = &default_route {
+                if default_route_hit.is_some() {
+                    bail!(
+                        "configuration error: server port name duplication on default route {dr}"
+                    );
+                }
+                true
+            } else {
+                bail!("configuration error: server port name {server_port_name} is not unique");
+            };
+            let denied_peers = denied.remove(&server_port_name).unwrap_or_default();
+            let (allowed_peers, timed_peers) = if route.allow_any_peer {
+                (None, FxHashMap::default())
+            } else {
+                let (mut allowed, mut timed) = allowed_peers(&server_port_name, &self.peers)?;
+                allowed.retain(|peer| !denied_peers.contains(peer));
+                timed.retain(|peer, _| !denied_peers.contains(peer) && !allowed.contains(peer));
+                if allowed.is_empty() && timed.is_empty() {
+                    bail!(
+                        "configuration error, every peer allowed on server port {server_port_name} is also denied (cannot be connected to)"
+                    );
+                }
+                (Some(allowed), timed)
+            };
+            let mut config = PortConfig::new(route.name, allowed_peers, route.target);
+            config.denied_peers = denied_peers;
+            config.timed_peers = timed_peers;
+            if let Some(limits) = self.route_limits.remove(&config.name) {
+                if let Some(bandwidth) = limits.bandwidth {
+                    validate_bandwidth(bandwidth).with_context(|| {
+                        format!("configuration error: server port {server_port_name} has an invalid bandwidth limit")
+                    })?;
+                    config.bandwidth = Some(Arc::new(Bandwidth::new(bandwidth)));
+                }
+                config.limits = limits;
+            }
+            if is_default_route {
+                default_route_hit = Some(config.clone());
+            }
+            route_config.insert(server_port_name, config);
+        }
+
+        if let Some((name, peers)) = denied.iter().next() {
+            let peer = peers
+                .iter()
+                .next()
+                .map(ToString::to_string)
+                .unwrap_or_default();
+            bail!(
+                "configuration error: peer={peer} is denied server port {name}, which doesn't exist"
+            );
+        }
+
+        if let Some(name) = self.route_limits.keys().next() {
+            bail!(
+                "configuration error: limits specified for server port {name}, which doesn't exist"
+            );
+        }
+
+        let default_route_spec = match (default_route, default_route_hit) {
+            (None, None) => None,
+            (Some(wants), None) => {
+                bail!(
+                    "configuration error: default route '{wants}' specified, but no server ports expose it"
+                );
+            }
+            (None, Some(hit)) => {
+                bail!(
+                    "configuration error: parse error, no default route specified, but a path for it at '{}', this is a bug",
+                    hit.target
+                );
+            }
+            (Some(_), Some(hit)) => Some(hit),
+        };
+
+        let mut peer_bandwidth = FxHashMap::default();
+        for (peer, limit) in self.peer_bandwidth {
+            validate_bandwidth(limit).with_context(|| {
+                format!("configuration error: peer={peer} has an invalid bandwidth limit")
+            })?;
+            if peer_bandwidth
+                .insert(peer, Arc::new(Bandwidth::new(limit)))
+                .is_some()
+            {
+                bail!("configuration error: peer={peer} has more than one bandwidth limit");
+            }
+        }
+
+        let mut peer_quota = FxHashMap::default();
+        for (peer, quota) in self.peer_quota {
+            if peer_quota.insert(peer, quota).is_some() {
+                bail!("configuration error: peer={peer} has more than one quota");
+            }
+        }
+
+        Ok(Routes {
+            default: default_route_spec,
+            inner: route_config,
+            peer_bandwidth,
+            peer_quota,
+        })
+    }
+}
+
+fn validate_bandwidth(limit: BandwidthLimit) -> anyhow::Result<()> {
+    if limit.bytes_per_sec == 0 || limit.burst_bytes == 0 {
+        bail!("bytes_per_sec and burst_bytes need to be at least 1");
+    }
+    Ok(())
+}
+
+fn denied_peers(
+    denied: &[(NodeId, Vec<String>)],
+) -> anyhow::Result<FxHashMap<ServerPortMapString, FxHashSet<NodeId>>> {
+    let mut by_route: FxHashMap<ServerPortMapString, FxHashSet<NodeId>> = FxHashMap::default();
+    for (node_id, routes) in denied {
+        for route in routes {
+            let spm = ServerPortMapString::try_new(route.clone()).with_context(|| {
+                format!("configuration error, peer={node_id} denied an invalid named port={route}")
+            })?;
+            by_route.entry(spm).or_default().insert(*node_id);
+        }
+    }
+    Ok(by_route)
+}
+
+/// The peers allowed on the route at any time, and those allowed only while their time bounds hold
+fn allowed_peers(
+    server_port_name: &ServerPortMapString,
+    peers: &[TimedGrant],
+) -> anyhow::Result<(FxHashSet<NodeId>, FxHashMap<NodeId, Vec<TimeBounds>>)> {
+    let mut explicit_allow_map = FxHashSet::default();
+    let mut timed_allow_map: FxHashMap<NodeId, Vec<TimeBounds>> = FxHashMap::default();
+    let mut allow = |node_id: &NodeId, bounds: Option<&TimeBounds>| match bounds {
+        None => {
+            explicit_allow_map.insert(*node_id);
+        }
+        Some(bounds) => timed_allow_map
+            .entry(*node_id)
+            .or_default()
+            .push(bounds.clone()),
+    };
+    for (node_id, grant, bounds) in peers {
+        let named_ports = match grant {
+            PeerGrant::AllRoutes => {
+                allow(node_id, bounds.as_ref());
+                continue;
+            }
+            PeerGrant::Named(named_ports) => named_ports,
+        };
+        // Unnecessary double-loop, if someone complains about start-up times and
+        // this is the cause, I'll eat my hat. And then maybe change this to be less wasteful.
+        let mut peer_port_set = FxHashSet::default();
+        for peer_port in named_ports {
+            // Just validation
+            if !peer_port_set.insert(peer_port) {
+                bail!(
+                    "configuration error, peer={node_id} specified a duplicate named port={peer_port}"
+                );
+            }
+            let spm = ServerPortMapString::try_new(peer_port.clone()).with_context(|| {
+                format!(
+                    "configuration error, peer={node_id} specified an invalid named port={peer_port}"
+                )
+            })?;
+            if *server_port_name == spm {
+                allow(node_id, bounds.as_ref());
+            }
+        }
+    }
+    if explicit_allow_map.is_empty() && timed_allow_map.is_empty() {
+        bail!(
+            "configuration error, server port {server_port_name} has no explicit allow list, and does not allow any (cannot be connected to)"
+        );
+    }
+    Ok((explicit_allow_map, timed_allow_map))
+}
@@ -0,0 +1,330 @@
+use crate::routes::{
+    RouteLimits, RouteTarget, Routes, RoutesBuilder, SharedRoutes, SocketAddrGetResult,
+};
+use crate::schedule::{TimeBounds, WeeklySchedule};
+use iroh::{NodeId, SecretKey};
+use p2proxy_lib::proto::ServerPortMapString;
+use p2proxy_lib::rate_limit::BandwidthLimit;
+use std::sync::Arc;
+use time::{Date, Month, OffsetDateTime, Time, UtcOffset, Weekday};
+
+fn node_id(seed: u8) -> NodeId {
+    SecretKey::from_bytes(&[seed; 32]).public()
+}
+
+fn port(name: &str) -> String {
+    ServerPortMapString::try_new(name.to_string())
+        .unwrap()
+        .as_str()
+        .to_string()
+}
+
+fn tcp(port: u16) -> RouteTarget {
+    RouteTarget::Tcp(([127, 0, 0, 1], port).into())
+}
+
+/// 2026-10-12 is a monday
+fn at(day: u8, hour: u8) -> OffsetDateTime {
+    Date::from_calendar_date(2026, Month::October, day)
+        .unwrap()
+        .with_hms(hour, 0, 0)
+        .unwrap()
+        .assume_utc()
+}
+
+fn target(res: &SocketAddrGetResult<'_>) -> Option<RouteTarget> {
+    match res {
+        SocketAddrGetResult::Allowed(cfg) => Some(cfg.target.clone()),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_build_default_route() {
+    let friend = node_id(1);
+    let stranger = node_id(2);
+    let routes = RoutesBuilder::new()
+        .default_route("web")
+        .route("web", tcp(8080))
+        .public_route("status", tcp(8081))
+        .allow_peer(friend, ["web"])
+        .build()
+        .unwrap();
+    assert_eq!(Some(tcp(8080)), target(&routes.default_route(&friend)));
+    assert_eq!(Some(tcp(8080)), target(&routes.get(&friend, &port("web"))));
+    assert!(matches!(
+        routes.default_route(&stranger),
+        SocketAddrGetResult::NotAllowed
+    ));
+    assert_eq!(
+        Some(tcp(8081)),
+        target(&routes.get(&stranger, &port("status")))
+    );
+    assert!(matches!(
+        routes.get(&friend, &port("missing")),
+        SocketAddrGetResult::NotPresent
+    ));
+
+    // A public default route lets anyone through
+    let routes = RoutesBuilder::new()
+        .default_route("status")
+        .public_route("status", tcp(8081))
+        .build()
+        .unwrap();
+    assert_eq!(Some(tcp(8081)), target(&routes.default_route(&stranger)));
+
+    let routes = RoutesBuilder::new()
+        .public_route("status", tcp(8081))
+        .build()
+        .unwrap();
+    assert!(matches!(
+        routes.default_route(&friend),
+        SocketAddrGetResult::NotPresent
+    ));
+
+    // The default route has to be one of the routes
+    assert!(
+        RoutesBuilder::new()
+            .default_route("web")
+            .public_route("status", tcp(8081))
+            .build()
+            .is_err()
+    );
+    assert!(
+        RoutesBuilder::new()
+            .public_route("web", tcp(8080))
+            .public_route("web", tcp(8081))
+            .build()
+            .is_err()
+    );
+    // Nobody could use it
+    assert!(
+        RoutesBuilder::new()
+            .route("web", tcp(8080))
+            .build()
+            .is_err()
+    );
+}
+
+#[test]
+fn test_build_deny() {
+    let friend = node_id(1);
+    let denied = node_id(2);
+    let routes = RoutesBuilder::new()
+        .default_route("status")
+        .route("web", tcp(8080))
+        .public_route("status", tcp(8081))
+        .allow_peer_on_all_routes(friend)
+        .allow_peer_on_all_routes(denied)
+        .deny_peer(denied, ["web", "status"])
+        .build()
+        .unwrap();
+    assert_eq!(Some(tcp(8080)), target(&routes.get(&friend, &port("web"))));
+    assert_eq!(Some(tcp(8081)), target(&routes.default_route(&friend)));
+    // A deny wins over a grant on all routes, and over a public route
+    assert!(matches!(
+        routes.get(&denied, &port("web")),
+        SocketAddrGetResult::NotAllowed
+    ));
+    assert!(matches!(
+        routes.get(&denied, &port("status")),
+        SocketAddrGetResult::NotAllowed
+    ));
+    assert!(matches!(
+        routes.default_route(&denied),
+        SocketAddrGetResult::NotAllowed
+    ));
+
+    // Every peer allowed on the route is denied
+    assert!(
+        RoutesBuilder::new()
+            .route("web", tcp(8080))
+            .allow_peer(denied, ["web"])
+            .deny_peer(denied, ["web"])
+            .build()
+            .is_err()
+    );
+    assert!(
+        RoutesBuilder::new()
+            .public_route("web", tcp(8080))
+            .deny_peer(denied, ["missing"])
+            .build()
+            .is_err()
+    );
+}
+
+#[test]
+fn test_build_timed_grants() {
+    let always = node_id(1);
+    let expiring = node_id(2);
+    let scheduled = node_id(3);
+    let office_hours = WeeklySchedule {
+        days: vec![
+            Weekday::Monday,
+            Weekday::Tuesday,
+            Weekday::Wednesday,
+            Weekday::Thursday,
+            Weekday::Friday,
+        ],
+        from: Time::from_hms(8, 0, 0).unwrap(),
+        until: Time::from_hms(18, 0, 0).unwrap(),
+        offset: UtcOffset::UTC,
+    };
+    let routes = RoutesBuilder::new()
+        .default_route("web")
+        .route("web", tcp(8080))
+        .allow_peer(always, ["web"])
+        .allow_peer_within(
+            expiring,
+            ["web"],
+            TimeBounds {
+                expires_at: Some(at(14, 12)),
+                schedules: Vec::new(),
+            },
+        )
+        .allow_peer_on_all_routes_within(
+            scheduled,
+            TimeBounds {
+                expires_at: None,
+                schedules: vec![office_hours],
+            },
+        )
+        .build()
+        .unwrap();
+    let web = port("web");
+    assert!(matches!(
+        routes.get_at(&always, &web, at(18, 3)),
+        SocketAddrGetResult::Allowed(_)
+    ));
+
+    assert!(matches!(
+        routes.get_at(&expiring, &web, at(14, 11)),
+        SocketAddrGetResult::Allowed(_)
+    ));
+    assert!(matches!(
+        routes.get_at(&expiring, &web, at(14, 12)),
+        SocketAddrGetResult::Expired(_)
+    ));
+    assert!(matches!(
+        routes.default_route_at(&expiring, at(15, 0)),
+        SocketAddrGetResult::Expired(_)
+    ));
+
+    assert!(matches!(
+        routes.get_at(&scheduled, &web, at(12, 8)),
+        SocketAddrGetResult::Allowed(_)
+    ));
+    assert!(matches!(
+        routes.get_at(&scheduled, &web, at(12, 18)),
+        SocketAddrGetResult::OutsideSchedule(_)
+    ));
+    // Saturday
+    assert!(matches!(
+        routes.default_route_at(&scheduled, at(17, 10)),
+        SocketAddrGetResult::OutsideSchedule(_)
+    ));
+
+    // A deny wins over a timed grant as well
+    let routes = RoutesBuilder::new()
+        .public_route("status", tcp(8081))
+        .route("web", tcp(8080))
+        .allow_peer(always, ["web"])
+        .allow_peer_within(
+            expiring,
+            ["web"],
+            TimeBounds {
+                expires_at: Some(at(14, 12)),
+                schedules: Vec::new(),
+            },
+        )
+        .deny_peer(expiring, ["web"])
+        .build()
+        .unwrap();
+    assert!(matches!(
+        routes.get_at(&expiring, &port("web"), at(12, 0)),
+        SocketAddrGetResult::NotAllowed
+    ));
+}
+
+fn bandwidth_routes(route_bytes_per_sec: u64, peer: NodeId, peer_bytes_per_sec: u64) -> Routes {
+    let limit = |bytes_per_sec| BandwidthLimit {
+        bytes_per_sec,
+        burst_bytes: bytes_per_sec,
+    };
+    RoutesBuilder::new()
+        .default_route("web")
+        .public_route("web", tcp(8080))
+        .route_limits(
+            "web",
+            RouteLimits {
+                bandwidth: Some(limit(route_bytes_per_sec)),
+                ..RouteLimits::default()
+            },
+        )
+        .peer_bandwidth(peer, limit(peer_bytes_per_sec))
+        .build()
+        .unwrap()
+}
+
+#[test]
+fn test_shared_routes_store() {
+    let peer = node_id(1);
+    let shared = SharedRoutes::new(bandwidth_routes(1024, peer, 512));
+    let before = shared.load();
+    let SocketAddrGetResult::Allowed(web) = before.get(&peer, &port("web")) else {
+        panic!("web should be allowed");
+    };
+    let route_bucket = web.bandwidth.clone().unwrap();
+    let peer_bucket = before.peer_bandwidth(&peer).unwrap().clone();
+
+    // Unchanged limits keep their buckets, including the default route's copy of the route
+    shared.store(bandwidth_routes(1024, peer, 512));
+    let after = shared.load();
+    assert!(!Arc::ptr_eq(&before, &after));
+    let SocketAddrGetResult::Allowed(web) = after.get(&peer, &port("web")) else {
+        panic!("web should be allowed");
+    };
+    assert!(Arc::ptr_eq(&route_bucket, web.bandwidth.as_ref().unwrap()));
+    let SocketAddrGetResult::Allowed(default) = after.default_route(&peer) else {
+        panic!("the default route should be allowed");
+    };
+    assert!(Arc::ptr_eq(
+        &route_bucket,
+        default.bandwidth.as_ref().unwrap()
+    ));
+    assert!(Arc::ptr_eq(
+        &peer_bucket,
+        after.peer_bandwidth(&peer).unwrap()
+    ));
+
+    // Changed limits start over
+    shared.store(bandwidth_routes(2048, peer, 256));
+    let changed = shared.load();
+    let SocketAddrGetResult::Allowed(web) = changed.get(&peer, &port("web")) else {
+        panic!("web should be allowed");
+    };
+    assert!(!Arc::ptr_eq(&route_bucket, web.bandwidth.as_ref().unwrap()));
+    assert_eq!(2048, web.bandwidth.as_ref().unwrap().limit().bytes_per_sec);
+    assert!(!Arc::ptr_eq(
+        &peer_bucket,
+        changed.peer_bandwidth(&peer).unwrap()
+    ));
+
+    // Snapshots taken before a swap keep the routes they were taken with
+    shared.store(
+        RoutesBuilder::new()
+            .public_route("status", tcp(8081))
+            .build()
+            .unwrap(),
+    );
+    assert!(matches!(
+        before.get(&peer, &port("web")),
+        SocketAddrGetResult::Allowed(_)
+    ));
+    let current = shared.load();
+    assert!(matches!(
+        current.get(&peer, &port("web")),
+        SocketAddrGetResult::NotPresent
+    ));
+    assert!(current.peer_bandwidth(&peer).is_none());
+}
@@ -0,0 +1,76 @@
+use time::{OffsetDateTime, Time, UtcOffset, Weekday};
+
+/// When a grant applies, checked each time a stream is opened. Unbounded if empty.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct TimeBounds {
+    /// The grant no longer applies from then on
+    pub expires_at: Option<OffsetDateTime>,
+    /// The grant only applies while all of these are open, f.e. a peer's schedule and that of one of its routes
+    pub schedules: Vec<WeeklySchedule>,
+}
+
+/// Whether a time-bounded grant applies at some point in time
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum GrantStatus {
+    Active,
+    Expired,
+    OutsideSchedule,
+}
+
+impl TimeBounds {
+    #[inline]
+    #[must_use]
+    pub fn is_unbounded(&self) -> bool {
+        self.expires_at.is_none() && self.schedules.is_empty()
+    }
+
+    /// Bounds that only hold when both `self` and `other` do
+    #[must_use]
+    pub fn and(mut self, other: &TimeBounds) -> Self {
+        self.expires_at = match (self.expires_at, other.expires_at) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        self.schedules.extend(other.schedules.iter().cloned());
+        self
+    }
+
+    #[must_use]
+    pub fn status(&self, at: OffsetDateTime) -> GrantStatus {
+        if self.expires_at.is_some_and(|expires_at| at >= expires_at) {
+            return GrantStatus::Expired;
+        }
+        if self.schedules.iter().all(|schedule| schedule.contains(at)) {
+            GrantStatus::Active
+        } else {
+            GrantStatus::OutsideSchedule
+        }
+    }
+}
+
+/// A window that opens on the same days every week, f.e. weekdays from 08:00 to 18:00
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct WeeklySchedule {
+    /// Days the window opens on
+    pub days: Vec<Weekday>,
+    /// When the window opens, in `offset`
+    pub from: Time,
+    /// When the window closes, in `offset`. The next day, if it isn't after `from`.
+    pub until: Time,
+    pub offset: UtcOffset,
+}
+
+impl WeeklySchedule {
+    #[must_use]
+    pub fn contains(&self, at: OffsetDateTime) -> bool {
+        let local = at.to_offset(self.offset);
+        let (day, time) = (local.weekday(), local.time());
+        if self.from < self.until {
+            self.days.contains(&day) && self.from <= time && time < self.until
+        } else {
+            // The part after midnight belongs to the window that opened the day before
+            (self.days.contains(&day) && time >= self.from)
+                || (self.days.contains(&day.previous()) && time < self.until)
+        }
+    }
+}
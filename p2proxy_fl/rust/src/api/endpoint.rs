@@ -108,12 +108,15 @@ impl InitializedEndpoint {
             }
         };
         match update {
-            // These three are unimportant for the app, no need to
+            // These are unimportant for the app, no need to
             // waste CPU on them
             ServeUpdate::BindingTcp
             | ServeUpdate::AcceptedTcp(_)
+            | ServeUpdate::BindingUdp
+            | ServeUpdate::UdpFlowOpened(_)
+            | ServeUpdate::UdpFlowClosed(_)
             | ServeUpdate::IrohConnecting(_) => {}
-            ServeUpdate::ListeningTcp => {
+            ServeUpdate::ListeningTcp | ServeUpdate::ListeningUdp => {
                 if sink.add("s listening".to_string()).is_err() {
                     return false;
                 }
@@ -123,6 +126,14 @@ impl InitializedEndpoint {
                     return false;
                 }
             }
+            ServeUpdate::PeerRedirected { from, to } => {
+                if sink
+                    .add(format!("s {from} rotated its key, now connecting to {to}"))
+                    .is_err()
+                {
+                    return false;
+                }
+            }
         }
         true
     }
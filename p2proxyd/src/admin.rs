@@ -0,0 +1,266 @@
+use anyhow::Context;
+use iroh::NodeId;
+use p2proxy_lib::display_chain;
+use p2proxy_server::ProxyState;
+use p2proxy_server::ban::{BanInfo, BanKey};
+use p2proxy_server::registry::ConnectionInfo;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+/// Operations on a running daemon, sent as one json object per line over the admin socket
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub(crate) enum AdminRequest {
+    List,
+    KickPeer { node_id: NodeId },
+    KickStream { stream_id: u64 },
+    Bans,
+    Unban { banned: BanKey },
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "response", rename_all = "kebab-case")]
+pub(crate) enum AdminResponse {
+    Connections { connections: Vec<ConnectionInfo> },
+    Kicked { count: usize },
+    Bans { bans: Vec<BanInfo> },
+    Unbanned { lifted: bool },
+    Error { message: String },
+}
+
+/// Administer a running p2proxyd through its admin socket
+#[derive(clap::Parser, Debug)]
+pub struct CtlArgs {
+    /// Path to the daemon's admin socket (`admin_socket_path` in its configuration)
+    #[clap(short, long)]
+    pub socket: PathBuf,
+    #[clap(subcommand)]
+    pub command: CtlCommand,
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum CtlCommand {
+    /// List active connections and their proxied streams
+    List,
+    /// Close all connections from a peer
+    KickPeer {
+        /// The peer's node id
+        node_id: NodeId,
+    },
+    /// Close a single proxied stream
+    KickStream {
+        /// The stream id, as shown by `list`
+        stream_id: u64,
+    },
+    /// List peers and addresses banned after repeated rejections
+    Bans,
+    /// Lift a ban before it runs out
+    Unban {
+        /// The banned node id or ip address
+        banned: BanKey,
+    },
+}
+
+pub(crate) fn spawn_admin_socket(path: PathBuf, state: ProxyState) {
+    tokio::task::spawn(async move {
+        if let Err(e) = run_admin_socket(&path, &state).await {
+            tracing::error!("admin socket error: {}", display_chain(&*e));
+        }
+    });
+}
+
+async fn run_admin_socket(path: &Path, state: &ProxyState) -> anyhow::Result<()> {
+    // A stale socket from a previous run would make the bind fail
+    if path.exists() {
+        std::fs::remove_file(path)
+            .with_context(|| format!("failed to remove stale admin socket {}", path.display()))?;
+    }
+    let listener = bind_private(path)
+        .with_context(|| format!("failed to bind admin socket at {}", path.display()))?;
+    tracing::info!("admin socket listening at {}", path.display());
+    loop {
+        let (stream, _addr) = listener
+            .accept()
+            .await
+            .context("failed to accept admin connection")?;
+        let state = state.clone();
+        tokio::task::spawn(async move {
+            if let Err(e) = serve_admin_client(stream, &state).await {
+                tracing::warn!("admin client error: {}", display_chain(&*e));
+            }
+        });
+    }
+}
+
+/// Binds the socket inside a directory only the daemon's user can enter, and moves it to `path` once it's
+/// restricted to that user as well. Anyone who can connect can kick peers and lift bans, so the socket is never
+/// reachable by others, not even between the bind and restricting it.
+fn bind_private(path: &Path) -> anyhow::Result<UnixListener> {
+    let file_name = path
+        .file_name()
+        .context("admin socket path has no file name")?
+        .to_string_lossy();
+    let dir = path.with_file_name(format!(".{file_name}.bind"));
+    // Left behind if a previous run died in between
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir)
+            .with_context(|| format!("failed to remove stale directory {}", dir.display()))?;
+    }
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .with_context(|| format!("failed to create directory {}", dir.display()))?;
+    let bind = || -> anyhow::Result<UnixListener> {
+        let private = dir.join(&*file_name);
+        let listener = UnixListener::bind(&private).context("failed to bind")?;
+        std::fs::set_permissions(&private, std::fs::Permissions::from_mode(0o600))
+            .context("failed to restrict permissions")?;
+        std::fs::rename(&private, path).context("failed to move into place")?;
+        Ok(listener)
+    };
+    let res = bind();
+    let _ = std::fs::remove_dir_all(&dir);
+    res
+}
+
+async fn serve_admin_client(stream: UnixStream, state: &ProxyState) -> anyhow::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    while let Some(line) = lines
+        .next_line()
+        .await
+        .context("failed to read admin request")?
+    {
+        let response = match serde_json::from_str::<AdminRequest>(&line) {
+            Ok(req) => handle_request(req, state),
+            Err(e) => AdminResponse::Error {
+                message: format!("invalid request: {e}"),
+            },
+        };
+        let mut out =
+            serde_json::to_vec(&response).context("failed to serialize admin response")?;
+        out.push(b'\n');
+        write
+            .write_all(&out)
+            .await
+            .context("failed to write admin response")?;
+    }
+    Ok(())
+}
+
+fn handle_request(req: AdminRequest, state: &ProxyState) -> AdminResponse {
+    let active = state.active_connections();
+    match req {
+        AdminRequest::List => AdminResponse::Connections {
+            connections: active.list(),
+        },
+        AdminRequest::KickPeer { node_id } => {
+            let count = active.kick_peer(&node_id);
+            tracing::info!("admin kicked {count} connections from {node_id}");
+            AdminResponse::Kicked { count }
+        }
+        AdminRequest::KickStream { stream_id } => {
+            let count = usize::from(active.kick_stream(stream_id));
+            tracing::info!("admin kicked stream {stream_id}, found={}", count > 0);
+            AdminResponse::Kicked { count }
+        }
+        AdminRequest::Bans => AdminResponse::Bans {
+            bans: state.bans().list(),
+        },
+        AdminRequest::Unban { banned } => {
+            let lifted = state.bans().lift(&banned);
+            tracing::info!("admin lifted the ban of {banned}, found={lifted}");
+            if lifted {
+                state.access_log_handle().log_ban_lifted(banned);
+            }
+            AdminResponse::Unbanned { lifted }
+        }
+    }
+}
+
+pub(crate) async fn run_ctl(args: CtlArgs) -> anyhow::Result<()> {
+    let req = match args.command {
+        CtlCommand::List => AdminRequest::List,
+        CtlCommand::KickPeer { node_id } => AdminRequest::KickPeer { node_id },
+        CtlCommand::KickStream { stream_id } => AdminRequest::KickStream { stream_id },
+        CtlCommand::Bans => AdminRequest::Bans,
+        CtlCommand::Unban { banned } => AdminRequest::Unban { banned },
+    };
+    let response = send_request(&args.socket, &req).await?;
+    match response {
+        AdminResponse::Connections { connections } => print_connections(&connections),
+        AdminResponse::Kicked { count } => {
+            if count == 0 {
+                anyhow::bail!("nothing matched, no connections or streams were closed");
+            }
+            println!("closed {count}");
+        }
+        AdminResponse::Bans { bans } => print_bans(&bans),
+        AdminResponse::Unbanned { lifted } => {
+            if !lifted {
+                anyhow::bail!("nothing matched, no ban was lifted");
+            }
+            println!("ban lifted");
+        }
+        AdminResponse::Error { message } => {
+            anyhow::bail!("p2proxyd rejected the request: {message}");
+        }
+    }
+    Ok(())
+}
+
+async fn send_request(socket: &Path, req: &AdminRequest) -> anyhow::Result<AdminResponse> {
+    let stream = UnixStream::connect(socket)
+        .await
+        .with_context(|| format!("failed to connect to admin socket {}", socket.display()))?;
+    let (read, mut write) = stream.into_split();
+    let mut out = serde_json::to_vec(req).context("failed to serialize admin request")?;
+    out.push(b'\n');
+    write
+        .write_all(&out)
+        .await
+        .context("failed to write admin request")?;
+    let line = BufReader::new(read)
+        .lines()
+        .next_line()
+        .await
+        .context("failed to read admin response")?
+        .context("admin socket closed without a response")?;
+    serde_json::from_str(&line).context("failed to deserialize admin response")
+}
+
+fn print_connections(connections: &[ConnectionInfo]) {
+    if connections.is_empty() {
+        println!("no active connections");
+        return;
+    }
+    for con in connections {
+        println!(
+            "connection {}\t{}\t[{}]\tsince {}",
+            con.connection_id, con.peer, con.remote_addr, con.started
+        );
+        for stream in &con.streams {
+            println!(
+                "  stream {}\t{}\t{}\tsince {}\tup={}B\tdown={}B",
+                stream.stream_id,
+                stream.route,
+                stream.downstream,
+                stream.started,
+                stream.bytes_upstream_to_downstream,
+                stream.bytes_downstream_to_upstream
+            );
+        }
+    }
+}
+
+fn print_bans(bans: &[BanInfo]) {
+    if bans.is_empty() {
+        println!("no active bans");
+        return;
+    }
+    for ban in bans {
+        println!("{}\tsince {}\tuntil {}", ban.banned, ban.since, ban.until);
+    }
+}
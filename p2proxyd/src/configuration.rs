@@ -1,15 +1,46 @@
+pub mod check;
+pub mod edit;
 #[cfg(test)]
 mod test;
 
-use crate::access_log::AccessLogHandle;
-use crate::proto::{PortConfig, Routes};
 use anyhow::{Context, bail};
-use iroh::SecretKey;
+use iroh::{NodeId, SecretKey};
+use p2proxy_lib::display_chain;
 use p2proxy_lib::proto::ServerPortMapString;
+use p2proxy_lib::rate_limit::BandwidthLimit;
+use p2proxy_server::access_log::{
+    AccessLogConfig, AccessLogFormat, AccessLogHandle, AccessLogRotation, AccessLogSink,
+};
+use p2proxy_server::ban::{BanPolicy, MAX_BAN_TIME};
+use p2proxy_server::limits::{DEFAULT_HEADER_TIMEOUT, Limits};
+use p2proxy_server::quota::Quota;
+use p2proxy_server::routes::{RouteLimits, RouteTarget, Routes, RoutesBuilder};
+use p2proxy_server::schedule::{TimeBounds, WeeklySchedule};
 use rustc_hash::{FxHashMap, FxHashSet};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
+use time::format_description::well_known::Rfc3339;
+use time::{OffsetDateTime, Time, UtcOffset, Weekday};
+
+/// Rotated access log files to keep, if rotation is configured
+const DEFAULT_ACCESS_LOG_RETAIN_FILES: usize = 7;
+
+/// How long open streams get to finish on shutdown, if not configured
+const DEFAULT_SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a UDP flow is kept open without datagrams in either direction, if not configured
+const DEFAULT_UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Rejections within the window that get a peer or address banned, if `[ban]` is configured
+const DEFAULT_BAN_MAX_REJECTIONS: usize = 5;
+
+/// How far back rejections are counted, if `[ban]` is configured
+const DEFAULT_BAN_WINDOW: Duration = Duration::from_secs(600);
+
+/// How long a ban lasts, if `[ban]` is configured
+const DEFAULT_BAN_TIME: Duration = Duration::from_secs(600);
 
 /// Run the p2proxy daemon
 #[derive(clap::Parser, Debug)]
@@ -24,43 +55,367 @@ impl P2proxydCliArgs {
         let toml = P2proxydTomlConfig::from_args(&self)?;
         P2ProxydSetup::from_toml(toml).context("failed to parse p2proxyd config")
     }
+
+    /// Read before anything else, the runtime has to be built before the daemon can start
+    pub fn worker_threads(&self) -> anyhow::Result<Option<usize>> {
+        let worker_threads = P2proxydTomlConfig::from_args(self)?.worker_threads;
+        if worker_threads == Some(0) {
+            bail!("configuration error: worker_threads needs to be at least 1");
+        }
+        Ok(worker_threads)
+    }
 }
 
 #[derive(Default, Debug, serde::Deserialize, serde::Serialize)]
 pub struct P2proxydTomlConfig {
+    /// A file with the raw 32-byte key, or the key as hex
     pub secret_key_path: Option<PathBuf>,
     pub secret_key_hex: Option<String>,
+    /// Name of an environment variable that holds the key as hex
+    pub secret_key_env: Option<String>,
+    /// Name of a systemd credential (`LoadCredential=`), read from `$CREDENTIALS_DIRECTORY`
+    pub secret_key_credential: Option<String>,
+    /// A program and its arguments that print the key to stdout, f.e. `["pass", "show", "p2proxyd"]`
+    pub secret_key_command: Option<Vec<String>>,
     pub peers: Option<Vec<PeerPermission>>,
+    pub groups: Option<Vec<GroupSettings>>,
+    #[serde(default)]
     pub server_ports: Vec<ServerPortSetting>,
+    /// Shorthand for a tsv access log written to this file, can't be combined with `access_log`
     pub access_log_path: Option<PathBuf>,
     pub default_route: Option<String>,
+    /// Unix socket for `p2proxyd ctl`, disabled if not set
+    pub admin_socket_path: Option<PathBuf>,
+    /// Address to serve Prometheus metrics at `/metrics`, disabled if not set
+    pub metrics_listen: Option<SocketAddr>,
+    pub access_log: Option<AccessLogSettings>,
+    /// Seconds open streams get to finish on SIGTERM/SIGINT before their connections are closed
+    pub shutdown_drain_timeout_secs: Option<u64>,
+    /// Run on a multi-threaded runtime with this many threads, on the current thread if not set
+    pub worker_threads: Option<usize>,
+    /// Bounds on the connections and streams peers can keep open
+    pub limits: Option<LimitsSettings>,
+    /// Ban peers and addresses whose streams keep getting rejected, disabled if not set
+    pub ban: Option<BanSettings>,
+    /// JSON file the traffic counters of peers with a quota are kept in, required if any peer has a quota
+    pub quota_state_path: Option<PathBuf>,
+    /// Directory of `*.toml` fragments with more `server_ports`, `peers` and `groups`, relative to this file
+    pub conf_dir: Option<PathBuf>,
+    /// The key this one replaces, served alongside it until the rotation is over
+    pub previous_key: Option<PreviousKeySettings>,
+    /// Several node ids served by one daemon, each with its own key, routes and peers.
+    /// Used instead of the top-level secret key, `server_ports`, `peers`, `groups` and `default_route`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub identities: Vec<IdentitySettings>,
+}
+
+/// A node id served by the daemon, with the routes and peers that can be reached through it
+#[derive(Default, Debug, serde::Deserialize, serde::Serialize)]
+pub struct IdentitySettings {
+    /// Tells identities apart in logs and errors
+    pub name: String,
+    pub secret_key_path: Option<PathBuf>,
+    pub secret_key_hex: Option<String>,
+    pub secret_key_env: Option<String>,
+    pub secret_key_credential: Option<String>,
+    pub secret_key_command: Option<Vec<String>>,
+    pub peers: Option<Vec<PeerPermission>>,
+    pub groups: Option<Vec<GroupSettings>>,
+    #[serde(default)]
+    pub server_ports: Vec<ServerPortSetting>,
+    pub default_route: Option<String>,
+    pub previous_key: Option<PreviousKeySettings>,
+}
+
+/// A key that's being rotated out. Until `until`, its node id is served with the same routes as the new key,
+/// and clients that connect to it are told the new node id.
+#[derive(Default, Debug, serde::Deserialize, serde::Serialize)]
+pub struct PreviousKeySettings {
+    pub secret_key_path: Option<PathBuf>,
+    pub secret_key_hex: Option<String>,
+    pub secret_key_env: Option<String>,
+    pub secret_key_credential: Option<String>,
+    pub secret_key_command: Option<Vec<String>>,
+    /// Rfc3339, f.e. `2025-12-01T00:00:00Z`
+    pub until: String,
+}
+
+/// The name of the identity made from the top-level settings, when no `[[identities]]` are configured
+pub const DEFAULT_IDENTITY_NAME: &str = "default";
+
+/// Node ids that are granted the same routes, instead of repeating the grant for each of them.
+/// A node id can be in several groups, and be listed in `peers` as well, its grants are merged.
+#[derive(Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct GroupSettings {
+    /// Tells groups apart in errors
+    pub name: String,
+    pub members: Vec<NodeId>,
+    #[serde(default)]
+    pub allow_any_port: bool,
+    pub allow_named_ports: Option<Vec<NamedPortGrant>>,
+    /// Routes the members can't use, whatever else allows them on them
+    pub deny_named_ports: Option<Vec<String>>,
+    /// Rfc3339, the group's grants no longer apply from then on
+    pub expires_at: Option<String>,
+    /// The group's grants only apply during these hours
+    pub schedule: Option<ScheduleSettings>,
+}
+
+/// A drop-in file from `conf_dir`, everything else can only be set in the main file
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFragment {
+    /// Which of the `[[identities]]` the routes and peers belong to, if any are configured
+    identity: Option<String>,
+    peers: Option<Vec<PeerPermission>>,
+    groups: Option<Vec<GroupSettings>>,
+    #[serde(default)]
+    server_ports: Vec<ServerPortSetting>,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct AccessLogSettings {
+    /// Defaults to tsv
+    pub format: Option<AccessLogFormat>,
+    /// Defaults to file
+    pub sink: Option<AccessLogSinkKind>,
+    /// Required for, and only used by, the file sink
+    pub path: Option<PathBuf>,
+    /// Rotate the file before it grows past this many bytes
+    pub rotate_size_bytes: Option<u64>,
+    /// Rotate the file when the date changes
+    pub rotate_daily: Option<bool>,
+    /// Rotated files to keep, as `<path>.1` (newest) through `<path>.<n>`, defaults to 7
+    pub retain_files: Option<usize>,
+}
+
+/// Unlimited if not set, except for the header timeout
+#[derive(Default, Debug, serde::Deserialize, serde::Serialize)]
+pub struct LimitsSettings {
+    pub max_connections_per_peer: Option<usize>,
+    pub max_streams_per_peer: Option<usize>,
+    /// Streams open across all peers
+    pub max_streams: Option<usize>,
+    /// Seconds a peer gets to send the route header after opening a stream, defaults to 10
+    pub header_timeout_secs: Option<u64>,
+}
+
+/// Rejected route headers count, those of peers that aren't allowed on a route or ask for one that doesn't exist.
+/// They're counted per node id and per remote ip separately.
+#[derive(Default, Debug, serde::Deserialize, serde::Serialize)]
+pub struct BanSettings {
+    /// Rejections within the window that trigger a ban, defaults to 5
+    pub max_rejections: Option<usize>,
+    /// Seconds rejections are counted for, defaults to 600
+    pub window_secs: Option<u64>,
+    /// Seconds new connections are refused once banned, defaults to 600
+    pub ban_secs: Option<u64>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogSinkKind {
+    File,
+    Stdout,
+    /// The local syslog socket at `/dev/log`
+    Syslog,
 }
 
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
 pub struct ServerPortSetting {
     pub host_ip: Option<IpAddr>,
-    pub port: u16,
+    /// Either a port, optionally with `host_ip`, or `unix_path` needs to be set
+    pub port: Option<u16>,
+    pub unix_path: Option<PathBuf>,
     pub name: String,
     pub allow_any_peer: Option<bool>,
+    /// Defaults to tcp
+    pub protocol: Option<PortProtocol>,
+    /// Seconds without traffic in either direction before a stream is closed,
+    /// unlimited if not set, except for udp flows which default to 60
+    pub idle_timeout_secs: Option<u64>,
+    /// Seconds a stream can stay open, however busy, unlimited if not set
+    pub max_lifetime_secs: Option<u64>,
+    /// Streams open on this route across all peers, unlimited if not set
+    pub max_streams: Option<usize>,
+    /// Bytes moved on this route across all peers, unlimited if not set
+    pub bandwidth: Option<BandwidthSettings>,
+}
+
+/// A limit for each direction separately
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, serde::Deserialize, serde::Serialize)]
+pub struct BandwidthSettings {
+    pub bytes_per_sec: u64,
+    /// Bytes that can go through at once after being idle, defaults to `bytes_per_sec`
+    pub burst_bytes: Option<u64>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PortProtocol {
+    Tcp,
+    Udp,
 }
 
 #[derive(Debug, Eq, PartialEq, Hash, serde::Deserialize, serde::Serialize)]
 pub struct PeerPermission {
     pub node_id: iroh::NodeId,
+    #[serde(default)]
     pub allow_any_port: bool,
-    pub allow_named_ports: Option<Vec<String>>,
+    pub allow_named_ports: Option<Vec<NamedPortGrant>>,
+    /// Routes the peer can't use, whatever else allows it on them
+    pub deny_named_ports: Option<Vec<String>>,
+    /// Rfc3339, f.e. `2025-12-05T18:00:00+01:00`, the peer's grants no longer apply from then on
+    pub expires_at: Option<String>,
+    /// The peer's grants only apply during these hours
+    pub schedule: Option<ScheduleSettings>,
+    /// Bytes moved by the peer across all its streams, unlimited if not set
+    pub bandwidth: Option<BandwidthSettings>,
+    /// Bytes the peer may move per UTC day, both directions summed, unlimited if not set
+    pub quota_bytes_per_day: Option<u64>,
+    /// Bytes the peer may move per UTC month, both directions summed, unlimited if not set
+    pub quota_bytes_per_month: Option<u64>,
+}
+
+/// A route in `allow_named_ports`, either just its name,
+/// or a table with the name and when the grant for that route applies
+#[derive(Debug, Clone, Eq, PartialEq, Hash, serde::Deserialize, serde::Serialize)]
+#[serde(untagged)]
+pub enum NamedPortGrant {
+    Name(String),
+    Bounded {
+        name: String,
+        /// Rfc3339, the grant no longer applies from then on
+        expires_at: Option<String>,
+        schedule: Option<ScheduleSettings>,
+    },
+}
+
+impl NamedPortGrant {
+    #[must_use]
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Name(name) | Self::Bounded { name, .. } => name,
+        }
+    }
+}
+
+/// Hours of the week a grant applies, f.e. weekdays from 08:00 to 18:00
+#[derive(Debug, Clone, Eq, PartialEq, Hash, serde::Deserialize, serde::Serialize)]
+pub struct ScheduleSettings {
+    /// `mon` through `sun`
+    pub days: Vec<String>,
+    /// `HH:MM`
+    pub from: String,
+    /// `HH:MM`, on the next day if it isn't after `from`
+    pub until: String,
+    /// f.e. `+01:00`, required, fixed offsets don't follow daylight saving time
+    pub utc_offset: Option<String>,
 }
 
 impl P2proxydTomlConfig {
+    #[inline]
     pub fn from_args(p2proxyd_cli_args: &P2proxydCliArgs) -> anyhow::Result<Self> {
-        let content = std::fs::read(&p2proxyd_cli_args.cfg_path).with_context(|| {
+        Self::from_path(&p2proxyd_cli_args.cfg_path)
+    }
+
+    pub fn from_path(cfg_path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read(cfg_path).with_context(|| {
             format!(
                 "failed to read p2proxyd config file: {}",
-                p2proxyd_cli_args.cfg_path.display()
+                cfg_path.display()
             )
         })?;
 
-        Self::parse_toml(&content)
+        Self::parse_toml(&content)?.merge_conf_dir(cfg_path)
+    }
+
+    /// Merges the fragments in `conf_dir`, in lexical order of their file names, into this configuration.
+    /// `cfg_path` is where this configuration was read from, a relative `conf_dir` is resolved against it.
+    pub fn merge_conf_dir(mut self, cfg_path: &Path) -> anyhow::Result<Self> {
+        let Some(conf_dir) = &self.conf_dir else {
+            return Ok(self);
+        };
+        let conf_dir = cfg_path
+            .parent()
+            .map_or_else(|| conf_dir.clone(), |parent| parent.join(conf_dir));
+        let mut fragment_paths = std::fs::read_dir(&conf_dir)
+            .with_context(|| format!("failed to read conf_dir {}", conf_dir.display()))?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("failed to read conf_dir {}", conf_dir.display()))?;
+        fragment_paths.retain(|p| p.is_file() && p.extension().is_some_and(|ext| ext == "toml"));
+        fragment_paths.sort();
+
+        let mut route_sources = FxHashMap::default();
+        for port in &self.server_ports {
+            route_sources.insert((None, port.name.clone()), cfg_path.to_path_buf());
+        }
+        for identity in &self.identities {
+            for port in &identity.server_ports {
+                route_sources.insert(
+                    (Some(identity.name.clone()), port.name.clone()),
+                    cfg_path.to_path_buf(),
+                );
+            }
+        }
+        for fragment_path in fragment_paths {
+            let content = std::fs::read(&fragment_path).with_context(|| {
+                format!("failed to read config fragment {}", fragment_path.display())
+            })?;
+            let fragment: ConfigFragment = toml::from_slice(&content).with_context(|| {
+                format!(
+                    "failed to deserialize config fragment {}",
+                    fragment_path.display()
+                )
+            })?;
+            let (server_ports, peers, groups) = match &fragment.identity {
+                Some(name) => {
+                    let identity = self
+                        .identities
+                        .iter_mut()
+                        .find(|identity| &identity.name == name)
+                        .with_context(|| {
+                            format!(
+                                "config fragment {} is for identity {name}, which isn't configured",
+                                fragment_path.display()
+                            )
+                        })?;
+                    (
+                        &mut identity.server_ports,
+                        &mut identity.peers,
+                        &mut identity.groups,
+                    )
+                }
+                None => (&mut self.server_ports, &mut self.peers, &mut self.groups),
+            };
+            for port in fragment.server_ports {
+                let key = (fragment.identity.clone(), port.name.clone());
+                match route_sources.get(&key) {
+                    // Duplicates within one file are reported when the routes are constructed
+                    Some(source) if *source != fragment_path => {
+                        bail!(
+                            "configuration error: server port name {} is defined in both {} and {}",
+                            port.name,
+                            source.display(),
+                            fragment_path.display()
+                        );
+                    }
+                    Some(_) => {}
+                    None => {
+                        route_sources.insert(key, fragment_path.clone());
+                    }
+                }
+                server_ports.push(port);
+            }
+            if let Some(fragment_peers) = fragment.peers {
+                peers.get_or_insert_default().extend(fragment_peers);
+            }
+            if let Some(fragment_groups) = fragment.groups {
+                groups.get_or_insert_default().extend(fragment_groups);
+            }
+        }
+        Ok(self)
     }
 
     pub fn generate_template_to_toml() -> anyhow::Result<String> {
@@ -70,15 +425,36 @@ impl P2proxydTomlConfig {
         let slf = Self {
             secret_key_path: None,
             secret_key_hex: Some(secret_key_hex),
+            secret_key_env: None,
+            secret_key_credential: None,
+            secret_key_command: None,
             peers: None,
+            groups: None,
             server_ports: vec![ServerPortSetting {
                 host_ip: None,
-                port: 8080,
+                port: Some(8080),
+                unix_path: None,
                 name: "my-http".to_string(),
                 allow_any_peer: Some(true),
+                protocol: None,
+                idle_timeout_secs: None,
+                max_lifetime_secs: None,
+                max_streams: None,
+                bandwidth: None,
             }],
             access_log_path: None,
             default_route: Some("my-http".to_string()),
+            admin_socket_path: None,
+            metrics_listen: None,
+            access_log: None,
+            shutdown_drain_timeout_secs: None,
+            worker_threads: None,
+            limits: None,
+            ban: None,
+            quota_state_path: None,
+            conf_dir: None,
+            previous_key: None,
+            identities: Vec::new(),
         };
         toml::to_string(&slf).context("failed to serialize p2proxyd config")
     }
@@ -87,30 +463,384 @@ impl P2proxydTomlConfig {
     pub fn parse_toml(toml: &[u8]) -> anyhow::Result<Self> {
         toml::from_slice(toml).context("failed to deserialize p2proxyd config")
     }
+
+    /// Takes the configured `[[identities]]`, or a single identity made from the top-level settings
+    pub fn take_identities(&mut self) -> anyhow::Result<Vec<IdentitySettings>> {
+        if self.identities.is_empty() {
+            return Ok(vec![IdentitySettings {
+                name: DEFAULT_IDENTITY_NAME.to_string(),
+                secret_key_path: self.secret_key_path.take(),
+                secret_key_hex: self.secret_key_hex.take(),
+                secret_key_env: self.secret_key_env.take(),
+                secret_key_credential: self.secret_key_credential.take(),
+                secret_key_command: self.secret_key_command.take(),
+                peers: self.peers.take(),
+                groups: self.groups.take(),
+                server_ports: std::mem::take(&mut self.server_ports),
+                default_route: self.default_route.take(),
+                previous_key: self.previous_key.take(),
+            }]);
+        }
+        if self.secret_key_path.is_some()
+            || self.secret_key_hex.is_some()
+            || self.secret_key_env.is_some()
+            || self.secret_key_credential.is_some()
+            || self.secret_key_command.is_some()
+            || self.peers.is_some()
+            || self.groups.is_some()
+            || !self.server_ports.is_empty()
+            || self.default_route.is_some()
+            || self.previous_key.is_some()
+        {
+            bail!(
+                "configuration error: with [[identities]], the secret key, previous_key, server_ports, peers, groups and default_route are set for each identity, not at the top level"
+            );
+        }
+        let mut names = FxHashSet::default();
+        for identity in &self.identities {
+            if identity.name.is_empty() {
+                bail!("configuration error: an identity has an empty name");
+            }
+            if !names.insert(identity.name.as_str()) {
+                bail!(
+                    "configuration error: identity name {} is not unique",
+                    identity.name
+                );
+            }
+        }
+        Ok(std::mem::take(&mut self.identities))
+    }
+}
+
+impl IdentitySettings {
+    fn into_setup(self) -> anyhow::Result<IdentitySetup> {
+        let secret_key = ensure_secret_key(&self)
+            .with_context(|| format!("failed to load secret key of identity {}", self.name))?;
+        let previous_key = previous_key(&self, &secret_key)?.filter(|previous| {
+            let still_served = previous.until > OffsetDateTime::now_utc();
+            if !still_served {
+                tracing::warn!(
+                    "the previous key of identity {} was served until {}, it can be removed from the configuration",
+                    self.name,
+                    previous.until
+                );
+            }
+            still_served
+        });
+        let routes = construct_routes(
+            self.default_route,
+            self.server_ports,
+            &self.peers.unwrap_or_default(),
+            &self.groups.unwrap_or_default(),
+        )
+        .with_context(|| format!("invalid routes for identity {}", self.name))?;
+        Ok(IdentitySetup {
+            name: self.name,
+            secret_key,
+            routes,
+            previous_key,
+        })
+    }
 }
 
 pub struct P2ProxydSetup {
+    pub identities: Vec<IdentitySetup>,
+    /// Set when `[[identities]]` are configured, access log entries then name the identity they're for
+    pub named_identities: bool,
+    pub access_log_handle: AccessLogHandle,
+    pub admin_socket_path: Option<PathBuf>,
+    pub metrics_listen: Option<SocketAddr>,
+    pub shutdown_drain_timeout: Duration,
+    pub limits: Limits,
+    pub ban_policy: Option<BanPolicy>,
+    pub quota_state_path: Option<PathBuf>,
+}
+
+pub struct IdentitySetup {
+    pub name: String,
     pub secret_key: SecretKey,
     pub routes: Routes,
-    pub access_log_handle: AccessLogHandle,
+    /// Only set while the rotation is still going on
+    pub previous_key: Option<PreviousKey>,
+}
+
+pub struct PreviousKey {
+    pub secret_key: SecretKey,
+    pub until: OffsetDateTime,
 }
 
 impl P2ProxydSetup {
-    pub fn from_toml(p2proxyd_toml_config: P2proxydTomlConfig) -> anyhow::Result<Self> {
-        let secret_key = ensure_secret_key(&p2proxyd_toml_config)?;
-        let routes = construct_routes(
-            p2proxyd_toml_config.default_route,
-            p2proxyd_toml_config.server_ports,
-            &p2proxyd_toml_config.peers.unwrap_or_default(),
+    pub fn from_toml(mut p2proxyd_toml_config: P2proxydTomlConfig) -> anyhow::Result<Self> {
+        let mut identities = Vec::new();
+        let mut node_ids = FxHashSet::default();
+        let named_identities = !p2proxyd_toml_config.identities.is_empty();
+        let identity_settings = p2proxyd_toml_config.take_identities()?;
+        let quota_state_path = quota_state_path(
+            p2proxyd_toml_config.quota_state_path.take(),
+            &identity_settings,
+        )?;
+        for identity in identity_settings {
+            let identity = identity.into_setup()?;
+            let previous_node_id = identity
+                .previous_key
+                .as_ref()
+                .map(|p| p.secret_key.public());
+            for node_id in std::iter::once(identity.secret_key.public()).chain(previous_node_id) {
+                if !node_ids.insert(node_id) {
+                    bail!(
+                        "configuration error: identity {} uses the same secret key as another identity",
+                        identity.name
+                    );
+                }
+            }
+            identities.push(identity);
+        }
+        let access_log = access_log_config(
+            p2proxyd_toml_config.access_log_path,
+            p2proxyd_toml_config.access_log,
         )?;
-        let access_log_handle = AccessLogHandle::maybe_spawn(p2proxyd_toml_config.access_log_path);
+        if access_log
+            .as_ref()
+            .is_some_and(|access_log| access_log.sink == AccessLogSink::Stdout)
+        {
+            // Entries are meant to be parsed, the daemon's own logs would get in the way
+            crate::observability::log_to_stderr();
+        }
+        let access_log_handle = AccessLogHandle::maybe_spawn(access_log);
 
         Ok(Self {
-            secret_key,
-            routes,
+            identities,
+            named_identities,
             access_log_handle,
+            admin_socket_path: p2proxyd_toml_config.admin_socket_path,
+            metrics_listen: p2proxyd_toml_config.metrics_listen,
+            shutdown_drain_timeout: p2proxyd_toml_config
+                .shutdown_drain_timeout_secs
+                .map_or(DEFAULT_SHUTDOWN_DRAIN_TIMEOUT, Duration::from_secs),
+            limits: limits_config(p2proxyd_toml_config.limits)?,
+            ban_policy: ban_config(p2proxyd_toml_config.ban)?,
+            quota_state_path,
+        })
+    }
+}
+
+/// Quotas are only worth something if their counters survive a restart
+pub(crate) fn quota_state_path(
+    path: Option<PathBuf>,
+    identities: &[IdentitySettings],
+) -> anyhow::Result<Option<PathBuf>> {
+    let has_quotas = identities
+        .iter()
+        .flat_map(|identity| identity.peers.iter().flatten())
+        .any(|peer| peer.quota_bytes_per_day.is_some() || peer.quota_bytes_per_month.is_some());
+    if has_quotas && path.is_none() {
+        bail!(
+            "configuration error: peers have quotas, but no quota_state_path is set to keep their usage in"
+        );
+    }
+    Ok(path)
+}
+
+fn limits_config(settings: Option<LimitsSettings>) -> anyhow::Result<Limits> {
+    let Some(settings) = settings else {
+        return Ok(Limits::default());
+    };
+    for (name, value) in [
+        (
+            "max_connections_per_peer",
+            settings.max_connections_per_peer,
+        ),
+        ("max_streams_per_peer", settings.max_streams_per_peer),
+        ("max_streams", settings.max_streams),
+    ] {
+        if value == Some(0) {
+            bail!("configuration error: limits.{name} needs to be at least 1");
+        }
+    }
+    if settings.header_timeout_secs == Some(0) {
+        bail!("configuration error: limits.header_timeout_secs needs to be at least 1");
+    }
+    Ok(Limits {
+        max_connections_per_peer: settings.max_connections_per_peer,
+        max_streams_per_peer: settings.max_streams_per_peer,
+        max_streams: settings.max_streams,
+        header_timeout: settings
+            .header_timeout_secs
+            .map_or(DEFAULT_HEADER_TIMEOUT, Duration::from_secs),
+    })
+}
+
+pub(crate) fn ban_config(settings: Option<BanSettings>) -> anyhow::Result<Option<BanPolicy>> {
+    let Some(settings) = settings else {
+        return Ok(None);
+    };
+    if settings.max_rejections == Some(0) {
+        bail!("configuration error: ban.max_rejections needs to be at least 1");
+    }
+    for (name, value) in [
+        ("window_secs", settings.window_secs),
+        ("ban_secs", settings.ban_secs),
+    ] {
+        if value == Some(0) {
+            bail!("configuration error: ban.{name} needs to be at least 1");
+        }
+    }
+    let ban_time = settings
+        .ban_secs
+        .map_or(DEFAULT_BAN_TIME, Duration::from_secs);
+    if ban_time > MAX_BAN_TIME {
+        bail!(
+            "configuration error: ban.ban_secs can be at most {}",
+            MAX_BAN_TIME.as_secs()
+        );
+    }
+    Ok(Some(BanPolicy {
+        max_rejections: settings
+            .max_rejections
+            .unwrap_or(DEFAULT_BAN_MAX_REJECTIONS),
+        window: settings
+            .window_secs
+            .map_or(DEFAULT_BAN_WINDOW, Duration::from_secs),
+        ban_time,
+    }))
+}
+
+fn access_log_config(
+    access_log_path: Option<PathBuf>,
+    access_log: Option<AccessLogSettings>,
+) -> anyhow::Result<Option<AccessLogConfig>> {
+    let settings = match (access_log_path, access_log) {
+        (None, None) => return Ok(None),
+        (Some(path), None) => {
+            return Ok(Some(AccessLogConfig {
+                format: AccessLogFormat::Tsv,
+                sink: AccessLogSink::File(path),
+                rotation: None,
+            }));
+        }
+        (None, Some(settings)) => settings,
+        (Some(_), Some(_)) => {
+            bail!("configuration error: both access_log_path and access_log are set, use one");
+        }
+    };
+    let sink = match (
+        settings.sink.unwrap_or(AccessLogSinkKind::File),
+        settings.path,
+    ) {
+        (AccessLogSinkKind::File, Some(path)) => AccessLogSink::File(path),
+        (AccessLogSinkKind::File, None) => {
+            bail!("configuration error: access_log with a file sink requires a path");
+        }
+        (AccessLogSinkKind::Stdout | AccessLogSinkKind::Syslog, Some(path)) => {
+            bail!(
+                "configuration error: access_log path={} is only used by the file sink",
+                path.display()
+            );
+        }
+        (AccessLogSinkKind::Stdout, None) => AccessLogSink::Stdout,
+        #[cfg(unix)]
+        (AccessLogSinkKind::Syslog, None) => AccessLogSink::Syslog,
+        #[cfg(not(unix))]
+        (AccessLogSinkKind::Syslog, None) => {
+            bail!("configuration error: the syslog access log sink is only supported on unix");
+        }
+    };
+    let rotate_daily = settings.rotate_daily.unwrap_or(false);
+    let rotation = if settings.rotate_size_bytes.is_some() || rotate_daily {
+        if !matches!(sink, AccessLogSink::File(_)) {
+            bail!("configuration error: access_log rotation is only supported for the file sink");
+        }
+        let retain = settings
+            .retain_files
+            .unwrap_or(DEFAULT_ACCESS_LOG_RETAIN_FILES);
+        if retain == 0 {
+            bail!("configuration error: access_log retain_files needs to be at least 1");
+        }
+        if settings.rotate_size_bytes == Some(0) {
+            bail!("configuration error: access_log rotate_size_bytes needs to be at least 1");
+        }
+        Some(AccessLogRotation {
+            max_bytes: settings.rotate_size_bytes,
+            daily: rotate_daily,
+            retain,
         })
+    } else {
+        if settings.retain_files.is_some() {
+            bail!(
+                "configuration error: access_log retain_files requires rotate_size_bytes or rotate_daily"
+            );
+        }
+        None
+    };
+    Ok(Some(AccessLogConfig {
+        format: settings.format.unwrap_or_default(),
+        sink,
+        rotation,
+    }))
+}
+
+/// Re-reads the configuration at `cfg_path` and constructs a new route table for each identity, in the order of `running`.
+/// Only `server_ports`, `peers`, `groups` and `default_route` are reloaded, other changes require a restart.
+pub fn reload_routes(cfg_path: &Path, running: &[(String, NodeId)]) -> anyhow::Result<Vec<Routes>> {
+    let mut toml = P2proxydTomlConfig::from_path(cfg_path)?;
+    let identities = toml.take_identities()?;
+    quota_state_path(toml.quota_state_path.take(), &identities)?;
+    let names = identities
+        .iter()
+        .map(|i| i.name.as_str())
+        .collect::<Vec<_>>();
+    let running_names = running
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>();
+    if names != running_names {
+        bail!(
+            "identities changed from {running_names:?} to {names:?} in {}, a restart is required to apply it",
+            cfg_path.display()
+        );
+    }
+    let mut routes = Vec::with_capacity(identities.len());
+    for (identity, (_, running_node_id)) in identities.into_iter().zip(running) {
+        // Only the key sources that are cheap to read, and never prompt, are checked for a change.
+        // Running `secret_key_command` on every reload could ask for a password, or fail, for nothing.
+        let key_sources = SecretKeySources {
+            path: identity.secret_key_path.as_deref(),
+            hex: identity.secret_key_hex.as_deref(),
+            env: None,
+            credential: None,
+            command: None,
+        };
+        if key_sources.path.is_some() || key_sources.hex.is_some() {
+            match load_secret_key(&key_sources) {
+                Ok(secret_key) => {
+                    if secret_key.public() != *running_node_id {
+                        tracing::warn!(
+                            "secret key of identity {} changed in {}, a restart is required to apply it",
+                            identity.name,
+                            cfg_path.display()
+                        );
+                    }
+                }
+                Err(e) => {
+                    tracing::warn!(
+                        "failed to read secret key of identity {} from reloaded config, keeping the running one: {}",
+                        identity.name,
+                        display_chain(&*e)
+                    );
+                }
+            }
+        }
+        routes.push(
+            construct_routes(
+                identity.default_route,
+                identity.server_ports,
+                &identity.peers.unwrap_or_default(),
+                &identity.groups.unwrap_or_default(),
+            )
+            .with_context(|| format!("invalid routes for identity {}", identity.name))?,
+        );
     }
+    Ok(routes)
 }
 
 #[allow(clippy::too_many_lines)]
@@ -118,17 +848,12 @@ fn construct_routes(
     default_route: Option<String>,
     server_ports: Vec<ServerPortSetting>,
     peers: &[PeerPermission],
+    groups: &[GroupSettings],
 ) -> anyhow::Result<Routes> {
-    let mut paths_unique = FxHashSet::default();
-    let default_route = if let Some(dr_path) = default_route {
-        let spm = ServerPortMapString::try_new(dr_path)?;
-        paths_unique.insert(spm.clone());
-        Some(spm)
-    } else {
-        None
-    };
-    let mut default_route_hit = None;
-    let mut route_config = FxHashMap::default();
+    let mut builder = RoutesBuilder::new();
+    if let Some(default_route) = default_route {
+        builder = builder.default_route(default_route);
+    }
     for p in server_ports {
         let server_port_name = ServerPortMapString::try_new(p.name.clone()).with_context(|| {
             format!(
@@ -136,132 +861,483 @@ fn construct_routes(
                 p.name
             )
         })?;
-        let is_default_route = if paths_unique.insert(server_port_name.clone()) {
-            false
-        } else if let Some(dr) = &default_route {
-            if default_route_hit.is_some() {
-                bail!("configuration error: server port name duplication on default route {dr}");
-            }
-            true
+        let target = route_target(&server_port_name, &p)?;
+        if p.max_streams == Some(0) {
+            bail!(
+                "configuration error: server port {server_port_name} max_streams needs to be at least 1"
+            );
+        }
+        if p.max_lifetime_secs == Some(0) {
+            bail!(
+                "configuration error: server port {server_port_name} max_lifetime_secs needs to be at least 1"
+            );
+        }
+        let bandwidth = p
+            .bandwidth
+            .map(|b| bandwidth_limit(b, &format!("server port {server_port_name}")))
+            .transpose()?;
+        let limits = RouteLimits {
+            max_streams: p.max_streams,
+            bandwidth,
+            // Udp flows have their own idle timeout, as part of the target
+            idle_timeout: p
+                .idle_timeout_secs
+                .filter(|_| !matches!(target, RouteTarget::Udp { .. }))
+                .map(Duration::from_secs),
+            max_lifetime: p.max_lifetime_secs.map(Duration::from_secs),
+        };
+        if limits != RouteLimits::default() {
+            builder = builder.route_limits(p.name.clone(), limits);
+        }
+        builder = if p.allow_any_peer == Some(true) {
+            builder.public_route(p.name, target)
         } else {
-            bail!("configuration error: server port name {server_port_name} is not unique");
+            builder.route(p.name, target)
         };
-        let addr = SocketAddr::new(
-            p.host_ip.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
-            p.port,
-        );
-        if p.allow_any_peer == Some(true) {
-            let config = PortConfig::new(None, addr);
-            if is_default_route {
-                default_route_hit = Some(config.clone());
-            }
-            route_config.insert(server_port_name, config);
-            continue;
-        }
-        let mut explicit_allow_map = FxHashSet::default();
-        for peer in peers {
-            if peer.allow_any_port {
-                explicit_allow_map.insert(peer.node_id);
-                continue;
-            }
-            // Unnecessary double-loop, if someone complains about start-up times and
-            // this is the cause, I'll eat my hat. And then maybe change this to be less wasteful.
-            if let Some(named_ports) = &peer.allow_named_ports {
-                let mut peer_port_set = FxHashSet::default();
-                for peer_port in named_ports {
-                    // Just validation
-                    if !peer_port_set.insert(peer_port) {
-                        anyhow::bail!(
-                            "configuration error, peer={} specified a duplicate named port={}",
-                            peer.node_id,
-                            peer_port
-                        );
-                    }
-                    let spm =
-                        ServerPortMapString::try_new(peer_port.clone()).with_context(|| {
-                            format!(
-                                "configuration error, peer={} specified an invalid named port={}",
-                                peer.node_id, peer_port
-                            )
-                        })?;
-                    if server_port_name == spm {
-                        explicit_allow_map.insert(peer.node_id);
-                    }
-                }
+    }
+    for peer in peers {
+        let owner = format!("peer={}", peer.node_id);
+        let bounds = time_bounds(peer.expires_at.as_deref(), peer.schedule.as_ref(), &owner)?;
+        builder = allow(
+            builder,
+            peer.node_id,
+            peer.allow_any_port,
+            peer.allow_named_ports.as_deref(),
+            &bounds,
+            &owner,
+        )?;
+        if let Some(denied_ports) = &peer.deny_named_ports {
+            builder = builder.deny_peer(peer.node_id, denied_ports.iter().cloned());
+        }
+        if let Some(bandwidth) = peer.bandwidth {
+            let limit = bandwidth_limit(bandwidth, &format!("peer={}", peer.node_id))?;
+            builder = builder.peer_bandwidth(peer.node_id, limit);
+        }
+        if peer.quota_bytes_per_day.is_some() || peer.quota_bytes_per_month.is_some() {
+            if peer.quota_bytes_per_day == Some(0) || peer.quota_bytes_per_month == Some(0) {
+                bail!(
+                    "configuration error: peer={} quotas need to be at least 1 byte",
+                    peer.node_id
+                );
             }
+            builder = builder.peer_quota(
+                peer.node_id,
+                Quota {
+                    bytes_per_day: peer.quota_bytes_per_day,
+                    bytes_per_month: peer.quota_bytes_per_month,
+                },
+            );
+        }
+    }
+    let mut group_names = FxHashSet::default();
+    for group in groups {
+        if group.name.is_empty() {
+            bail!("configuration error: a group has an empty name");
         }
-        if explicit_allow_map.is_empty() {
-            anyhow::bail!(
-                "configuration error, server port {} has no explicit allow list, and does not allow any (cannot be connected to)",
-                server_port_name
+        if !group_names.insert(group.name.as_str()) {
+            bail!(
+                "configuration error: group name {} is not unique",
+                group.name
             );
         }
-
-        let config = PortConfig::new(Some(explicit_allow_map), addr);
-        if is_default_route {
-            default_route_hit = Some(config.clone());
+        let owner = format!("group {}", group.name);
+        let bounds = time_bounds(group.expires_at.as_deref(), group.schedule.as_ref(), &owner)?;
+        // Compiled down to grants of each member, the routes only know about node ids
+        for member in &group.members {
+            builder = allow(
+                builder,
+                *member,
+                group.allow_any_port,
+                group.allow_named_ports.as_deref(),
+                &bounds,
+                &owner,
+            )?;
+            if let Some(denied_ports) = &group.deny_named_ports {
+                builder = builder.deny_peer(*member, denied_ports.iter().cloned());
+            }
         }
-        route_config.insert(server_port_name, config);
     }
+    builder.build()
+}
 
-    let default_route_spec = match (default_route, default_route_hit) {
-        (None, None) => None,
-        (Some(wants), None) => {
-            anyhow::bail!(
-                "configuration error: default route '{wants}' specified, but no server ports expose it"
+/// Grants `peer` the routes, a route's own time bounds apply on top of those of the peer (or group)
+fn allow(
+    mut builder: RoutesBuilder,
+    peer: NodeId,
+    allow_any_port: bool,
+    named_ports: Option<&[NamedPortGrant]>,
+    bounds: &TimeBounds,
+    owner: &str,
+) -> anyhow::Result<RoutesBuilder> {
+    if allow_any_port {
+        return Ok(builder.allow_peer_on_all_routes_within(peer, bounds.clone()));
+    }
+    let Some(named_ports) = named_ports else {
+        return Ok(builder);
+    };
+    let mut names = FxHashSet::default();
+    let mut unbounded = Vec::new();
+    for grant in named_ports {
+        if !names.insert(grant.name()) {
+            bail!(
+                "configuration error, {owner} specified a duplicate named port={}",
+                grant.name()
             );
         }
-        (None, Some(hit)) => {
-            anyhow::bail!(
-                "configuration error: parse error, no default route specified, but a path for it at '{}', this is a bug",
-                hit.socket_addr
-            );
+        match grant {
+            NamedPortGrant::Name(name) => unbounded.push(name.clone()),
+            NamedPortGrant::Bounded {
+                name,
+                expires_at,
+                schedule,
+            } => {
+                let route_bounds = time_bounds(
+                    expires_at.as_deref(),
+                    schedule.as_ref(),
+                    &format!("{owner} named port={name}"),
+                )?;
+                builder = builder.allow_peer_within(peer, [name], route_bounds.and(bounds));
+            }
         }
-        (Some(_), Some(hit)) => Some(hit),
+    }
+    if !unbounded.is_empty() {
+        builder = builder.allow_peer_within(peer, unbounded, bounds.clone());
+    }
+    Ok(builder)
+}
+
+fn time_bounds(
+    expires_at: Option<&str>,
+    schedule: Option<&ScheduleSettings>,
+    owner: &str,
+) -> anyhow::Result<TimeBounds> {
+    let expires_at = expires_at
+        .map(|expires_at| {
+            OffsetDateTime::parse(expires_at, &Rfc3339).with_context(|| {
+                format!(
+                    "configuration error: {owner} expires_at={expires_at} is not an Rfc3339 timestamp"
+                )
+            })
+        })
+        .transpose()?;
+    let schedules = schedule
+        .map(|schedule| weekly_schedule(schedule, owner))
+        .transpose()?
+        .into_iter()
+        .collect();
+    Ok(TimeBounds {
+        expires_at,
+        schedules,
+    })
+}
+
+fn weekly_schedule(settings: &ScheduleSettings, owner: &str) -> anyhow::Result<WeeklySchedule> {
+    if settings.days.is_empty() {
+        bail!("configuration error: {owner} schedule has no days");
+    }
+    let mut days = Vec::with_capacity(settings.days.len());
+    for day in &settings.days {
+        let weekday = parse_weekday(day).with_context(|| {
+            format!(
+                "configuration error: {owner} schedule day '{day}' is not one of mon through sun"
+            )
+        })?;
+        if !days.contains(&weekday) {
+            days.push(weekday);
+        }
+    }
+    let from = parse_time_of_day(&settings.from).with_context(|| {
+        format!(
+            "configuration error: {owner} schedule from='{}' is not a time as HH:MM",
+            settings.from
+        )
+    })?;
+    let until = parse_time_of_day(&settings.until).with_context(|| {
+        format!(
+            "configuration error: {owner} schedule until='{}' is not a time as HH:MM",
+            settings.until
+        )
+    })?;
+    if from == until {
+        bail!("configuration error: {owner} schedule starts and ends at the same time");
+    }
+    let offset = match &settings.utc_offset {
+        Some(offset) => parse_utc_offset(offset).with_context(|| {
+            format!(
+                "configuration error: {owner} schedule utc_offset='{offset}' is not an offset as +HH:MM"
+            )
+        })?,
+        None => bail!(
+            "configuration error: {owner} schedule needs a utc_offset as +HH:MM, the times are in that offset"
+        ),
     };
+    Ok(WeeklySchedule {
+        days,
+        from,
+        until,
+        offset,
+    })
+}
 
-    Ok(Routes::new(default_route_spec, route_config))
-}
-
-fn ensure_secret_key(config: &P2proxydTomlConfig) -> anyhow::Result<SecretKey> {
-    match (&config.secret_key_path, &config.secret_key_hex) {
-        (Some(p), Some(hex)) => {
-            tracing::warn!("supplied both a secret key from file and as an argument");
-            // Reading both to ensure there isn't a mismatch which the user should fix
-            let secret_from_hex =
-                SecretKey::from_str(hex).context("failed to parse secret key hex")?;
-            let secret_from_file = read_secret_key_from_file(p)?;
-            if secret_from_hex.to_bytes() != secret_from_file.to_bytes() {
-                return Err(anyhow::anyhow!(
-                    "supplied both a secret key from file and as an argument, and they don't match"
-                ));
-            }
-            Ok(secret_from_file)
+fn parse_weekday(day: &str) -> Option<Weekday> {
+    Some(match day.to_ascii_lowercase().as_str() {
+        "mon" | "monday" => Weekday::Monday,
+        "tue" | "tuesday" => Weekday::Tuesday,
+        "wed" | "wednesday" => Weekday::Wednesday,
+        "thu" | "thursday" => Weekday::Thursday,
+        "fri" | "friday" => Weekday::Friday,
+        "sat" | "saturday" => Weekday::Saturday,
+        "sun" | "sunday" => Weekday::Sunday,
+        _ => return None,
+    })
+}
+
+/// `HH:MM`, f.e. `08:00`
+fn parse_time_of_day(time: &str) -> Option<Time> {
+    let (hour, minute) = time.split_once(':')?;
+    if hour.len() != 2 || minute.len() != 2 {
+        return None;
+    }
+    Time::from_hms(hour.parse().ok()?, minute.parse().ok()?, 0).ok()
+}
+
+/// `+HH:MM` or `-HH:MM`, f.e. `+01:00`
+fn parse_utc_offset(offset: &str) -> Option<UtcOffset> {
+    let (sign, rest) = match offset.split_at_checked(1)? {
+        ("+", rest) => (1, rest),
+        ("-", rest) => (-1, rest),
+        _ => return None,
+    };
+    let (hours, minutes) = rest.split_once(':')?;
+    if hours.len() != 2 || minutes.len() != 2 {
+        return None;
+    }
+    let hours = hours.parse::<i8>().ok()?;
+    let minutes = minutes.parse::<i8>().ok()?;
+    UtcOffset::from_hms(sign * hours, sign * minutes, 0).ok()
+}
+
+fn bandwidth_limit(settings: BandwidthSettings, owner: &str) -> anyhow::Result<BandwidthLimit> {
+    let burst_bytes = settings.burst_bytes.unwrap_or(settings.bytes_per_sec);
+    if settings.bytes_per_sec == 0 || burst_bytes == 0 {
+        bail!(
+            "configuration error: {owner} bandwidth bytes_per_sec and burst_bytes need to be at least 1"
+        );
+    }
+    Ok(BandwidthLimit {
+        bytes_per_sec: settings.bytes_per_sec,
+        burst_bytes,
+    })
+}
+
+fn route_target(
+    server_port_name: &ServerPortMapString,
+    p: &ServerPortSetting,
+) -> anyhow::Result<RouteTarget> {
+    let protocol = p.protocol.unwrap_or(PortProtocol::Tcp);
+    if p.idle_timeout_secs == Some(0) {
+        bail!(
+            "configuration error: server port {server_port_name} idle_timeout_secs needs to be at least 1"
+        );
+    }
+    match (p.port, &p.unix_path) {
+        (Some(port), None) => {
+            let addr =
+                SocketAddr::new(p.host_ip.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)), port);
+            Ok(match protocol {
+                PortProtocol::Tcp => RouteTarget::Tcp(addr),
+                PortProtocol::Udp => RouteTarget::Udp {
+                    socket_addr: addr,
+                    idle_timeout: p
+                        .idle_timeout_secs
+                        .map_or(DEFAULT_UDP_IDLE_TIMEOUT, Duration::from_secs),
+                },
+            })
         }
-        (Some(p), None) => {
-            let secret_from_file = read_secret_key_from_file(p)?;
-            Ok(secret_from_file)
+        (None, Some(path)) => {
+            if p.host_ip.is_some() {
+                bail!(
+                    "configuration error: server port {server_port_name} specified both host_ip and unix_path"
+                );
+            }
+            if protocol == PortProtocol::Udp {
+                bail!(
+                    "configuration error: server port {server_port_name} specified unix_path, which is only supported for tcp"
+                );
+            }
+            #[cfg(unix)]
+            {
+                Ok(RouteTarget::Unix(path.clone()))
+            }
+            #[cfg(not(unix))]
+            {
+                bail!(
+                    "configuration error: server port {server_port_name} specified unix_path {}, unix sockets are not supported on this platform",
+                    path.display()
+                );
+            }
         }
-        (None, Some(hex)) => {
-            let secret_from_hex =
-                SecretKey::from_str(hex).context("failed to parse secret key hex")?;
-            Ok(secret_from_hex)
+        (Some(_), Some(_)) => {
+            bail!(
+                "configuration error: server port {server_port_name} specified both port and unix_path"
+            );
         }
         (None, None) => {
-            bail!("either a secret key from file or hex as an argument must be supplied");
+            bail!(
+                "configuration error: server port {server_port_name} specified neither port nor unix_path"
+            );
         }
     }
 }
 
+/// Where a secret key can be read from, if more than one is set they have to agree on the key
+struct SecretKeySources<'a> {
+    path: Option<&'a Path>,
+    hex: Option<&'a str>,
+    env: Option<&'a str>,
+    credential: Option<&'a str>,
+    command: Option<&'a [String]>,
+}
+
+fn ensure_secret_key(config: &IdentitySettings) -> anyhow::Result<SecretKey> {
+    load_secret_key(&SecretKeySources {
+        path: config.secret_key_path.as_deref(),
+        hex: config.secret_key_hex.as_deref(),
+        env: config.secret_key_env.as_deref(),
+        credential: config.secret_key_credential.as_deref(),
+        command: config.secret_key_command.as_deref(),
+    })
+}
+
+/// Loads and validates the previous key of an identity that's rotating its key, if there is one
+fn previous_key(
+    config: &IdentitySettings,
+    secret_key: &SecretKey,
+) -> anyhow::Result<Option<PreviousKey>> {
+    let Some(previous) = &config.previous_key else {
+        return Ok(None);
+    };
+    let previous_secret_key = load_secret_key(&SecretKeySources {
+        path: previous.secret_key_path.as_deref(),
+        hex: previous.secret_key_hex.as_deref(),
+        env: previous.secret_key_env.as_deref(),
+        credential: previous.secret_key_credential.as_deref(),
+        command: previous.secret_key_command.as_deref(),
+    })
+    .with_context(|| format!("failed to load previous key of identity {}", config.name))?;
+    if previous_secret_key.public() == secret_key.public() {
+        bail!(
+            "configuration error: the previous key of identity {} is the same as its secret key",
+            config.name
+        );
+    }
+    let until = OffsetDateTime::parse(&previous.until, &Rfc3339).with_context(|| {
+        format!(
+            "configuration error: previous_key until={} of identity {} is not an Rfc3339 timestamp",
+            previous.until, config.name
+        )
+    })?;
+    Ok(Some(PreviousKey {
+        secret_key: previous_secret_key,
+        until,
+    }))
+}
+
+fn load_secret_key(config: &SecretKeySources<'_>) -> anyhow::Result<SecretKey> {
+    let mut sources = Vec::new();
+    if let Some(p) = config.path {
+        sources.push((
+            format!("secret_key_path {}", p.display()),
+            read_secret_key_from_file(p),
+        ));
+    }
+    if let Some(hex) = config.hex {
+        sources.push((
+            "secret_key_hex".to_string(),
+            SecretKey::from_str(hex).context("failed to parse secret key hex"),
+        ));
+    }
+    if let Some(var) = config.env {
+        sources.push((
+            format!("secret_key_env {var}"),
+            read_secret_key_from_env(var),
+        ));
+    }
+    if let Some(name) = config.credential {
+        sources.push((
+            format!("secret_key_credential {name}"),
+            read_secret_key_from_credential(name),
+        ));
+    }
+    if let Some(command) = config.command {
+        sources.push((
+            "secret_key_command".to_string(),
+            read_secret_key_from_command(command),
+        ));
+    }
+    let mut sources = sources.into_iter();
+    let Some((first_source, first)) = sources.next() else {
+        bail!(
+            "a secret key must be supplied with one of secret_key_path, secret_key_hex, secret_key_env, secret_key_credential or secret_key_command"
+        );
+    };
+    let secret_key = first?;
+    for (source, other) in sources {
+        tracing::warn!("supplied more than one secret key, {first_source} and {source}");
+        // Reading all of them to ensure there isn't a mismatch which the user should fix
+        if other?.to_bytes() != secret_key.to_bytes() {
+            bail!("supplied a secret key with {first_source} and {source}, and they don't match");
+        }
+    }
+    Ok(secret_key)
+}
+
 fn read_secret_key_from_file(p: &Path) -> anyhow::Result<SecretKey> {
-    let secret_from_file = std::fs::read(p)
-        .with_context(|| format!("failed to read supplied secret key file: {}", p.display()))?
-        .try_into()
-        .map_err(|_e| {
-            anyhow::anyhow!(
-                "failed to parse secret key file: {}, invalid length",
-                p.display()
-            )
-        })?;
-    Ok(SecretKey::from_bytes(&secret_from_file))
+    let key_material = std::fs::read(p)
+        .with_context(|| format!("failed to read supplied secret key file: {}", p.display()))?;
+    parse_secret_key_material(key_material)
+        .with_context(|| format!("failed to parse secret key file: {}", p.display()))
+}
+
+fn read_secret_key_from_env(var: &str) -> anyhow::Result<SecretKey> {
+    let hex = std::env::var(var)
+        .with_context(|| format!("failed to read secret key from environment variable {var}"))?;
+    SecretKey::from_str(hex.trim())
+        .with_context(|| format!("failed to parse secret key hex from environment variable {var}"))
+}
+
+fn read_secret_key_from_credential(name: &str) -> anyhow::Result<SecretKey> {
+    let dir = std::env::var_os("CREDENTIALS_DIRECTORY").with_context(|| {
+        format!(
+            "secret_key_credential {name} is set, but $CREDENTIALS_DIRECTORY isn't, is the unit missing LoadCredential=?"
+        )
+    })?;
+    read_secret_key_from_file(&Path::new(&dir).join(name))
+}
+
+fn read_secret_key_from_command(command: &[String]) -> anyhow::Result<SecretKey> {
+    let Some((program, args)) = command.split_first() else {
+        bail!("configuration error: secret_key_command is empty");
+    };
+    let output = std::process::Command::new(program)
+        .args(args)
+        .stdin(std::process::Stdio::null())
+        .stderr(std::process::Stdio::inherit())
+        .output()
+        .with_context(|| format!("failed to run secret_key_command {program}"))?;
+    if !output.status.success() {
+        bail!("secret_key_command {program} failed with {}", output.status);
+    }
+    parse_secret_key_material(output.stdout)
+        .with_context(|| format!("failed to parse the output of secret_key_command {program}"))
+}
+
+/// Raw 32 bytes, or hex with optional surrounding whitespace, as `p2proxy-cli` accepts its keys
+fn parse_secret_key_material(key_material: Vec<u8>) -> anyhow::Result<SecretKey> {
+    if let Ok(raw) = <[u8; 32]>::try_from(key_material.as_slice()) {
+        return Ok(SecretKey::from_bytes(&raw));
+    }
+    let s = String::from_utf8(key_material)
+        .map_err(|_e| anyhow::anyhow!("invalid key material, expected raw 32 bytes or hex"))?;
+    SecretKey::from_str(s.trim()).context("invalid key material, expected raw 32 bytes or hex")
 }
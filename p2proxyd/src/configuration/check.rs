@@ -0,0 +1,433 @@
+use crate::configuration::{
+    GroupSettings, IdentitySettings, NamedPortGrant, P2proxydTomlConfig, PeerPermission,
+    PreviousKey, access_log_config, ban_config, construct_routes, ensure_secret_key, previous_key,
+    quota_state_path, route_target,
+};
+use anyhow::Context;
+use iroh::NodeId;
+use p2proxy_lib::proto::ServerPortMapString;
+use p2proxy_server::routes::{RouteTarget, Routes, SocketAddrGetResult};
+use rustc_hash::FxHashSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+
+/// How long to wait for a target to accept a connection before warning about it
+const TARGET_CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Fits the longest cell of the matrix, `expired`
+const MATRIX_CELL_WIDTH: usize = 7;
+
+/// Validate a configuration without running it, and show who can reach which route
+#[derive(clap::Parser, Debug)]
+pub struct CheckConfigArgs {
+    /// Path to the configuration file
+    #[clap(short, long)]
+    pub cfg_path: PathBuf,
+    /// Only answer whether this peer can reach `--route`, or the default route if no route is given
+    #[clap(long)]
+    pub peer: Option<NodeId>,
+    /// The named route to check access to for `--peer`
+    #[clap(long, requires = "peer")]
+    pub route: Option<String>,
+}
+
+pub(crate) async fn run_check_config(args: CheckConfigArgs) -> anyhow::Result<()> {
+    let mut toml = P2proxydTomlConfig::from_path(&args.cfg_path)?;
+    access_log_config(toml.access_log_path.clone(), toml.access_log.take())?;
+    let explicit_identities = !toml.identities.is_empty();
+    let identities = toml.take_identities()?;
+    quota_state_path(toml.quota_state_path.take(), &identities)?;
+    ban_config(toml.ban.take())?;
+    let mut checked = Vec::new();
+    for identity in identities {
+        checked.push(CheckedIdentity::new(identity)?);
+    }
+
+    if let Some(peer) = args.peer {
+        for identity in &checked {
+            let prefix = if explicit_identities {
+                format!("identity {}: ", identity.settings.name)
+            } else {
+                String::new()
+            };
+            print!(
+                "{}",
+                dry_run(&identity.routes, peer, args.route.clone(), &prefix)?
+            );
+        }
+        return Ok(());
+    }
+
+    if explicit_identities {
+        println!("configuration {} is valid", args.cfg_path.display());
+    }
+    for identity in &checked {
+        if explicit_identities {
+            println!();
+            println!(
+                "identity {}, node id {}",
+                identity.settings.name, identity.node_id
+            );
+        } else {
+            println!(
+                "configuration {} is valid, node id {}",
+                args.cfg_path.display(),
+                identity.node_id
+            );
+        }
+        let mut warnings = Vec::new();
+        if let Some(previous) = &identity.previous_key {
+            if previous.until > OffsetDateTime::now_utc() {
+                println!(
+                    "previous node id {} is served until {}, and redirects to the new node id",
+                    previous.secret_key.public(),
+                    previous.until
+                );
+            } else {
+                warnings.push(format!(
+                    "previous_key was served until {}, it can be removed",
+                    previous.until
+                ));
+            }
+        }
+        check_key_files(&args.cfg_path, &identity.settings, &mut warnings);
+        check_peers(
+            &identity.peers,
+            &identity.groups,
+            &identity.route_names(),
+            &mut warnings,
+        )?;
+        check_targets(&identity.targets, &mut warnings).await;
+        for warning in &warnings {
+            println!("warning: {warning}");
+        }
+        println!();
+        print!(
+            "{}",
+            access_matrix(
+                &identity.routes,
+                &identity.peers,
+                &identity.groups,
+                identity.settings.default_route.as_deref(),
+                &identity.route_names(),
+            )?
+        );
+    }
+    Ok(())
+}
+
+/// An identity that passed the same validation as when the daemon starts
+struct CheckedIdentity {
+    node_id: NodeId,
+    previous_key: Option<PreviousKey>,
+    routes: Routes,
+    peers: Vec<PeerPermission>,
+    groups: Vec<GroupSettings>,
+    targets: Vec<(String, RouteTarget)>,
+    /// What's left of the settings, the name, key sources and default route
+    settings: IdentitySettings,
+}
+
+impl CheckedIdentity {
+    fn new(mut settings: IdentitySettings) -> anyhow::Result<Self> {
+        let secret_key = ensure_secret_key(&settings)
+            .with_context(|| format!("failed to load secret key of identity {}", settings.name))?;
+        let previous_key = previous_key(&settings, &secret_key)?;
+        let mut targets = Vec::with_capacity(settings.server_ports.len());
+        for p in &settings.server_ports {
+            let name = ServerPortMapString::try_new(p.name.clone()).with_context(|| {
+                format!(
+                    "configuration error: server port name={} is invalid",
+                    p.name
+                )
+            })?;
+            targets.push((p.name.clone(), route_target(&name, p)?));
+        }
+        let peers = settings.peers.take().unwrap_or_default();
+        let groups = settings.groups.take().unwrap_or_default();
+        let routes = construct_routes(
+            settings.default_route.clone(),
+            std::mem::take(&mut settings.server_ports),
+            &peers,
+            &groups,
+        )
+        .with_context(|| format!("invalid routes for identity {}", settings.name))?;
+        Ok(Self {
+            node_id: secret_key.public(),
+            previous_key,
+            routes,
+            peers,
+            groups,
+            targets,
+            settings,
+        })
+    }
+
+    fn route_names(&self) -> Vec<String> {
+        self.targets.iter().map(|(name, _)| name.clone()).collect()
+    }
+}
+
+/// Whether `peer` can reach `route`, or the default route, as a line to print
+pub(super) fn dry_run(
+    routes: &Routes,
+    peer: NodeId,
+    route: Option<String>,
+    prefix: &str,
+) -> anyhow::Result<String> {
+    let (described, res) = match route {
+        Some(route) => {
+            let spm = ServerPortMapString::try_new(route.clone())
+                .with_context(|| format!("invalid route name {route}"))?;
+            (format!("route '{route}'"), routes.get(&peer, spm.as_str()))
+        }
+        None => ("the default route".to_string(), routes.default_route(&peer)),
+    };
+    Ok(match res {
+        SocketAddrGetResult::Allowed(cfg) => format!(
+            "{prefix}ALLOWED: {peer} can reach {described}, proxied to {}\n",
+            cfg.target
+        ),
+        SocketAddrGetResult::NotAllowed => {
+            format!("{prefix}DENIED: {peer} is not allowed on {described}\n")
+        }
+        SocketAddrGetResult::NotPresent => format!("{prefix}DENIED: {described} does not exist\n"),
+        SocketAddrGetResult::Expired(_) => {
+            format!("{prefix}DENIED: {peer}'s grant for {described} expired\n")
+        }
+        SocketAddrGetResult::OutsideSchedule(_) => {
+            format!("{prefix}DENIED: {peer} is outside its schedule for {described}\n")
+        }
+    })
+}
+
+pub(super) fn check_key_files(
+    cfg_path: &Path,
+    identity: &IdentitySettings,
+    warnings: &mut Vec<String>,
+) {
+    if let Some(key_path) = identity
+        .secret_key_path
+        .as_deref()
+        .filter(|p| is_world_readable(p))
+    {
+        warnings.push(format!(
+            "secret key file {} is readable by anyone, restrict it with `chmod 600`",
+            key_path.display()
+        ));
+    }
+    if identity.secret_key_hex.is_some() && is_world_readable(cfg_path) {
+        warnings.push(format!(
+            "configuration {} contains secret_key_hex and is readable by anyone, restrict it with `chmod 600`",
+            cfg_path.display()
+        ));
+    }
+}
+
+#[cfg(unix)]
+fn is_world_readable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::metadata(path).is_ok_and(|meta| meta.permissions().mode() & 0o004 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_world_readable(_path: &Path) -> bool {
+    false
+}
+
+fn check_peers(
+    peers: &[PeerPermission],
+    groups: &[GroupSettings],
+    route_names: &[String],
+    warnings: &mut Vec<String>,
+) -> anyhow::Result<()> {
+    let existing = route_names
+        .iter()
+        .map(|name| ServerPortMapString::try_new(name.clone()))
+        .collect::<anyhow::Result<FxHashSet<_>>>()?;
+    let mut seen = FxHashSet::default();
+    for peer in peers {
+        if !seen.insert(peer.node_id) {
+            warnings.push(format!(
+                "peer {} is listed more than once, its permissions are merged",
+                peer.node_id
+            ));
+        }
+        let named_ports = peer.allow_named_ports.as_deref().unwrap_or_default();
+        let in_group = groups
+            .iter()
+            .any(|group| group.members.contains(&peer.node_id));
+        if !peer.allow_any_port && named_ports.is_empty() && !in_group {
+            warnings.push(format!(
+                "peer {} has neither allow_any_port nor allow_named_ports, and isn't in a group, it can't reach any route",
+                peer.node_id
+            ));
+        }
+        for port in named_ports.iter().map(NamedPortGrant::name) {
+            if !existing.contains(&ServerPortMapString::try_new(port.to_string())?) {
+                warnings.push(format!(
+                    "peer {} is allowed on route '{port}', which doesn't exist",
+                    peer.node_id
+                ));
+            }
+        }
+        check_expiries(
+            &format!("peer {}", peer.node_id),
+            peer.expires_at.as_deref(),
+            named_ports,
+            warnings,
+        );
+    }
+    for group in groups {
+        if group.members.is_empty() {
+            warnings.push(format!("group {} has no members", group.name));
+        }
+        let named_ports = group.allow_named_ports.as_deref().unwrap_or_default();
+        for port in named_ports.iter().map(NamedPortGrant::name) {
+            if !existing.contains(&ServerPortMapString::try_new(port.to_string())?) {
+                warnings.push(format!(
+                    "group {} is allowed on route '{port}', which doesn't exist",
+                    group.name
+                ));
+            }
+        }
+        check_expiries(
+            &format!("group {}", group.name),
+            group.expires_at.as_deref(),
+            named_ports,
+            warnings,
+        );
+    }
+    Ok(())
+}
+
+/// Grants that have run out still parse, but no longer do anything
+fn check_expiries(
+    owner: &str,
+    expires_at: Option<&str>,
+    named_ports: &[NamedPortGrant],
+    warnings: &mut Vec<String>,
+) {
+    let now = OffsetDateTime::now_utc();
+    let expired = |expires_at: Option<&str>| {
+        expires_at
+            .and_then(|expires_at| OffsetDateTime::parse(expires_at, &Rfc3339).ok())
+            .is_some_and(|expires_at| expires_at <= now)
+    };
+    if expired(expires_at) {
+        warnings.push(format!(
+            "{owner}'s grants expired at {}, they can be removed",
+            expires_at.unwrap_or_default()
+        ));
+        return;
+    }
+    for grant in named_ports {
+        if let NamedPortGrant::Bounded {
+            name, expires_at, ..
+        } = grant
+            && expired(expires_at.as_deref())
+        {
+            warnings.push(format!(
+                "{owner}'s grant for route '{name}' expired at {}, it can be removed",
+                expires_at.as_deref().unwrap_or_default()
+            ));
+        }
+    }
+}
+
+async fn check_targets(targets: &[(String, RouteTarget)], warnings: &mut Vec<String>) {
+    for (name, target) in targets {
+        let res = match target {
+            RouteTarget::Tcp(socket_addr) => tokio::time::timeout(
+                TARGET_CONNECT_TIMEOUT,
+                tokio::net::TcpStream::connect(socket_addr),
+            )
+            .await
+            .map(|res| res.map(drop)),
+            // There's no connection to make, a udp target can't be checked without its protocol
+            RouteTarget::Udp { .. } => continue,
+            #[cfg(unix)]
+            RouteTarget::Unix(path) => tokio::time::timeout(
+                TARGET_CONNECT_TIMEOUT,
+                tokio::net::UnixStream::connect(path),
+            )
+            .await
+            .map(|res| res.map(drop)),
+        };
+        match res {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warnings.push(format!(
+                "route '{name}' target {target} is unreachable: {e}"
+            )),
+            Err(_elapsed) => warnings.push(format!(
+                "route '{name}' target {target} did not accept a connection within {TARGET_CONNECT_TIMEOUT:?}"
+            )),
+        }
+    }
+}
+
+/// Which peer can reach which route, a row for each configured peer, and one for everyone else
+pub(super) fn access_matrix(
+    routes: &Routes,
+    peers: &[PeerPermission],
+    groups: &[GroupSettings],
+    default_route: Option<&str>,
+    route_names: &[String],
+) -> anyhow::Result<String> {
+    let mut columns = Vec::with_capacity(route_names.len() + 1);
+    if let Some(default_route) = default_route {
+        columns.push((format!("(default: {default_route})"), None));
+    }
+    for name in route_names {
+        columns.push((
+            name.clone(),
+            Some(ServerPortMapString::try_new(name.clone())?),
+        ));
+    }
+    let mut rows = Vec::with_capacity(peers.len() + 1);
+    let mut seen = FxHashSet::default();
+    let members = groups.iter().flat_map(|group| group.members.iter());
+    for node_id in peers.iter().map(|peer| &peer.node_id).chain(members) {
+        if seen.insert(*node_id) {
+            rows.push((node_id.to_string(), *node_id));
+        }
+    }
+    // A key nobody has configured, stands in for every peer that isn't listed
+    let unlisted = iroh::SecretKey::generate(&mut rand::rngs::OsRng).public();
+    rows.push(("<any other peer>".to_string(), unlisted));
+
+    let first_width = rows.iter().map(|(label, _)| label.len()).max().unwrap_or(0);
+    let mut header = format!("{:first_width$}", "peer \\ route");
+    for (label, _) in &columns {
+        header.push_str(&format!(
+            "  {label:width$}",
+            width = label.len().max(MATRIX_CELL_WIDTH)
+        ));
+    }
+    let mut out = format!("{}\n", header.trim_end());
+    for (label, node_id) in &rows {
+        let mut line = format!("{label:first_width$}");
+        for (column, route) in &columns {
+            let res = match route {
+                Some(route) => routes.get(node_id, route.as_str()),
+                None => routes.default_route(node_id),
+            };
+            let cell = match res {
+                SocketAddrGetResult::Allowed(_) => "allow",
+                SocketAddrGetResult::NotAllowed | SocketAddrGetResult::NotPresent => "deny",
+                SocketAddrGetResult::Expired(_) => "expired",
+                // Allowed at other times of the week
+                SocketAddrGetResult::OutsideSchedule(_) => "sched",
+            };
+            line.push_str(&format!(
+                "  {cell:width$}",
+                width = column.len().max(MATRIX_CELL_WIDTH)
+            ));
+        }
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    Ok(out)
+}
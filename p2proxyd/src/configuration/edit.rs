@@ -0,0 +1,450 @@
+use crate::configuration::{
+    NamedPortGrant, P2proxydTomlConfig, access_log_config, construct_routes, route_target,
+};
+use anyhow::{Context, bail};
+use iroh::NodeId;
+use p2proxy_lib::proto::ServerPortMapString;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use toml_edit::{Array, ArrayOfTables, DocumentMut, Item, Table, Value, value};
+
+/// Edit the peers and server ports of a configuration file, keeping its comments and formatting.
+/// Every change is validated the same way the daemon validates its configuration before it's written.
+#[derive(clap::Parser, Debug)]
+pub struct ConfigArgs {
+    /// Path to the configuration file
+    #[clap(short, long)]
+    pub cfg_path: PathBuf,
+    /// Send SIGHUP to this pid after writing, to make a running daemon reload its routes
+    #[cfg(unix)]
+    #[clap(long)]
+    pub reload_pid: Option<i32>,
+    #[clap(subcommand)]
+    pub command: ConfigCommand,
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum ConfigCommand {
+    /// Manage `[[peers]]`
+    #[clap(subcommand)]
+    Peers(PeersCommand),
+    /// Manage `[[server_ports]]`
+    #[clap(subcommand)]
+    Ports(PortsCommand),
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum PeersCommand {
+    List,
+    /// Add a peer, or extend the permissions of a peer that's already listed
+    Add {
+        node_id: NodeId,
+        /// Allow the peer to reach every route
+        #[clap(long)]
+        allow_any_port: bool,
+        /// A named route the peer can reach, can be repeated
+        #[clap(long = "allow-named-port")]
+        allow_named_ports: Vec<String>,
+    },
+    /// Remove every entry for a peer
+    Remove {
+        node_id: NodeId,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum PortsCommand {
+    List,
+    Add(AddPortArgs),
+    /// Remove a server port by name
+    Remove {
+        name: String,
+    },
+}
+
+#[derive(clap::Args, Debug)]
+pub struct AddPortArgs {
+    /// Name for routing
+    #[clap(long)]
+    name: String,
+    #[clap(long, required_unless_present = "unix_path")]
+    port: Option<u16>,
+    #[clap(long, requires = "port")]
+    host_ip: Option<IpAddr>,
+    #[clap(long, conflicts_with = "port")]
+    unix_path: Option<PathBuf>,
+    #[clap(long, value_enum)]
+    protocol: Option<ProtocolArg>,
+    /// Seconds without traffic in either direction before a stream is closed
+    #[clap(long)]
+    idle_timeout_secs: Option<u64>,
+    /// Seconds a stream can stay open, however busy
+    #[clap(long)]
+    max_lifetime_secs: Option<u64>,
+    /// Allow any peer to connect to this route
+    #[clap(long)]
+    allow_any_peer: bool,
+}
+
+#[derive(clap::ValueEnum, Debug, Copy, Clone)]
+pub enum ProtocolArg {
+    Tcp,
+    Udp,
+}
+
+pub(crate) fn run_config(args: ConfigArgs) -> anyhow::Result<()> {
+    let content = std::fs::read_to_string(&args.cfg_path).with_context(|| {
+        format!(
+            "failed to read p2proxyd config file: {}",
+            args.cfg_path.display()
+        )
+    })?;
+    let mut doc = content
+        .parse::<DocumentMut>()
+        .with_context(|| format!("failed to parse {}", args.cfg_path.display()))?;
+    if doc.contains_key("identities") {
+        bail!(
+            "{} uses [[identities]], which can't be edited with this command yet, edit it by hand",
+            args.cfg_path.display()
+        );
+    }
+    let changed = match args.command {
+        ConfigCommand::Peers(PeersCommand::List) => {
+            list_peers(&content)?;
+            false
+        }
+        ConfigCommand::Peers(PeersCommand::Add {
+            node_id,
+            allow_any_port,
+            allow_named_ports,
+        }) => {
+            add_peer(&mut doc, node_id, allow_any_port, &allow_named_ports)?;
+            true
+        }
+        ConfigCommand::Peers(PeersCommand::Remove { node_id }) => {
+            remove_peer(&mut doc, node_id)?;
+            true
+        }
+        ConfigCommand::Ports(PortsCommand::List) => {
+            list_ports(&content)?;
+            false
+        }
+        ConfigCommand::Ports(PortsCommand::Add(add)) => {
+            add_port(&mut doc, &add.name, port_table(&add)?)?;
+            true
+        }
+        ConfigCommand::Ports(PortsCommand::Remove { name }) => {
+            remove_port(&mut doc, &name)?;
+            true
+        }
+    };
+    if !changed {
+        return Ok(());
+    }
+    let edited = doc.to_string();
+    validate(&edited, &args.cfg_path)?;
+    write_preserving_permissions(&args.cfg_path, &edited)?;
+    println!("wrote {}", args.cfg_path.display());
+    #[cfg(unix)]
+    {
+        if let Some(pid) = args.reload_pid {
+            send_sighup(pid)?;
+            println!("sent SIGHUP to {pid}");
+        }
+    }
+    Ok(())
+}
+
+fn list_peers(content: &str) -> anyhow::Result<()> {
+    let cfg = P2proxydTomlConfig::parse_toml(content.as_bytes())?;
+    let peers = cfg.peers.unwrap_or_default();
+    if peers.is_empty() {
+        println!("no peers");
+    }
+    for peer in peers {
+        if peer.allow_any_port {
+            println!("{}\tany port", peer.node_id);
+        } else {
+            let named_ports = peer.allow_named_ports.unwrap_or_default();
+            println!(
+                "{}\t{}",
+                peer.node_id,
+                named_ports
+                    .iter()
+                    .map(NamedPortGrant::name)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+    }
+    Ok(())
+}
+
+fn list_ports(content: &str) -> anyhow::Result<()> {
+    let cfg = P2proxydTomlConfig::parse_toml(content.as_bytes())?;
+    if cfg.server_ports.is_empty() {
+        println!("no server ports");
+    }
+    for p in &cfg.server_ports {
+        let name = ServerPortMapString::try_new(p.name.clone())?;
+        let target = route_target(&name, p)?;
+        let default = if cfg.default_route.as_deref() == Some(p.name.as_str()) {
+            " (default)"
+        } else {
+            ""
+        };
+        let access = if p.allow_any_peer == Some(true) {
+            "any peer"
+        } else {
+            "listed peers"
+        };
+        println!("{}{default}\t{target}\t{access}", p.name);
+    }
+    Ok(())
+}
+
+fn port_table(add: &AddPortArgs) -> anyhow::Result<Table> {
+    let mut table = Table::new();
+    if let Some(host_ip) = add.host_ip {
+        table["host_ip"] = value(host_ip.to_string());
+    }
+    if let Some(port) = add.port {
+        table["port"] = value(i64::from(port));
+    }
+    if let Some(unix_path) = &add.unix_path {
+        let unix_path = unix_path
+            .to_str()
+            .context("unix_path needs to be valid utf8")?;
+        table["unix_path"] = value(unix_path);
+    }
+    table["name"] = value(add.name.as_str());
+    if add.allow_any_peer {
+        table["allow_any_peer"] = value(true);
+    }
+    if let Some(protocol) = add.protocol {
+        table["protocol"] = value(match protocol {
+            ProtocolArg::Tcp => "tcp",
+            ProtocolArg::Udp => "udp",
+        });
+    }
+    if let Some(idle_timeout_secs) = add.idle_timeout_secs {
+        let idle_timeout_secs =
+            i64::try_from(idle_timeout_secs).context("idle_timeout_secs is too large")?;
+        table["idle_timeout_secs"] = value(idle_timeout_secs);
+    }
+    if let Some(max_lifetime_secs) = add.max_lifetime_secs {
+        let max_lifetime_secs =
+            i64::try_from(max_lifetime_secs).context("max_lifetime_secs is too large")?;
+        table["max_lifetime_secs"] = value(max_lifetime_secs);
+    }
+    Ok(table)
+}
+
+fn array_of_tables<'a>(
+    doc: &'a mut DocumentMut,
+    key: &str,
+) -> anyhow::Result<&'a mut ArrayOfTables> {
+    doc.entry(key)
+        .or_insert_with(|| Item::ArrayOfTables(ArrayOfTables::new()))
+        .as_array_of_tables_mut()
+        .with_context(|| format!("`{key}` is not written as [[{key}]] tables, edit it by hand"))
+}
+
+pub(super) fn add_peer(
+    doc: &mut DocumentMut,
+    node_id: NodeId,
+    allow_any_port: bool,
+    allow_named_ports: &[String],
+) -> anyhow::Result<()> {
+    if !allow_any_port && allow_named_ports.is_empty() {
+        bail!("a peer needs --allow-any-port or at least one --allow-named-port to reach anything");
+    }
+    for name in allow_named_ports {
+        if !has_port(doc, name) {
+            println!("warning: there's no server port named {name}, yet");
+        }
+    }
+    let peers = array_of_tables(doc, "peers")?;
+    let node_id_str = node_id.to_string();
+    if let Some(existing) = peers
+        .iter_mut()
+        .find(|t| t.get("node_id").and_then(Item::as_str) == Some(node_id_str.as_str()))
+    {
+        if allow_any_port {
+            existing["allow_any_port"] = value(true);
+        }
+        if !allow_named_ports.is_empty() {
+            let ports = existing
+                .entry("allow_named_ports")
+                .or_insert_with(|| value(Array::new()))
+                .as_array_mut()
+                .context("allow_named_ports of the existing peer is not an array")?;
+            for name in allow_named_ports {
+                if !ports.iter().any(|p| grant_name(p) == Some(name.as_str())) {
+                    ports.push(name.as_str());
+                }
+            }
+        }
+        println!("updated peer {node_id}");
+        return Ok(());
+    }
+    let mut table = Table::new();
+    table["node_id"] = value(node_id_str);
+    table["allow_any_port"] = value(allow_any_port);
+    if !allow_named_ports.is_empty() {
+        table["allow_named_ports"] = value(allow_named_ports.iter().collect::<Array>());
+    }
+    peers.push(table);
+    println!("added peer {node_id}");
+    Ok(())
+}
+
+pub(super) fn remove_peer(doc: &mut DocumentMut, node_id: NodeId) -> anyhow::Result<()> {
+    let peers = array_of_tables(doc, "peers")?;
+    let node_id_str = node_id.to_string();
+    let before = peers.len();
+    peers.retain(|t| t.get("node_id").and_then(Item::as_str) != Some(node_id_str.as_str()));
+    if peers.len() == before {
+        bail!("no peer with node id {node_id}");
+    }
+    println!("removed peer {node_id}");
+    Ok(())
+}
+
+pub(super) fn add_port(doc: &mut DocumentMut, name: &str, table: Table) -> anyhow::Result<()> {
+    if has_port(doc, name) {
+        bail!("a server port named {name} already exists");
+    }
+    array_of_tables(doc, "server_ports")?.push(table);
+    println!("added server port {name}");
+    Ok(())
+}
+
+pub(super) fn remove_port(doc: &mut DocumentMut, name: &str) -> anyhow::Result<()> {
+    let ports = array_of_tables(doc, "server_ports")?;
+    let before = ports.len();
+    ports.retain(|t| t.get("name").and_then(Item::as_str) != Some(name));
+    if ports.len() == before {
+        bail!("no server port named {name}");
+    }
+    println!("removed server port {name}");
+    let referenced_by = doc
+        .get("peers")
+        .and_then(Item::as_array_of_tables)
+        .into_iter()
+        .flat_map(ArrayOfTables::iter)
+        .filter(|peer| {
+            peer.get("allow_named_ports")
+                .and_then(Item::as_array)
+                .is_some_and(|ports| ports.iter().any(|p| grant_name(p) == Some(name)))
+        })
+        .filter_map(|peer| peer.get("node_id").and_then(Item::as_str));
+    for node_id in referenced_by {
+        println!("warning: peer {node_id} is still allowed on {name}");
+    }
+    Ok(())
+}
+
+/// An entry of `allow_named_ports`, either a name or an inline table with a time-bounded grant
+fn grant_name(grant: &Value) -> Option<&str> {
+    grant.as_str().or_else(|| {
+        grant
+            .as_inline_table()
+            .and_then(|t| t.get("name"))
+            .and_then(Value::as_str)
+    })
+}
+
+fn has_port(doc: &DocumentMut, name: &str) -> bool {
+    doc.get("server_ports")
+        .and_then(Item::as_array_of_tables)
+        .is_some_and(|ports| {
+            ports
+                .iter()
+                .any(|t| t.get("name").and_then(Item::as_str) == Some(name))
+        })
+}
+
+/// Applies the same rules as when the daemon starts, so that an edit can't leave a broken configuration behind.
+/// Fragments in `conf_dir` are merged in, only the main file at `cfg_path` is edited.
+pub(super) fn validate(edited: &str, cfg_path: &Path) -> anyhow::Result<()> {
+    let mut cfg = P2proxydTomlConfig::parse_toml(edited.as_bytes())
+        .and_then(|cfg| cfg.merge_conf_dir(cfg_path))
+        .context("the edited configuration is invalid, nothing was written")?;
+    access_log_config(cfg.access_log_path.take(), cfg.access_log.take())
+        .context("the edited configuration is invalid, nothing was written")?;
+    for identity in cfg
+        .take_identities()
+        .context("the edited configuration is invalid, nothing was written")?
+    {
+        construct_routes(
+            identity.default_route,
+            identity.server_ports,
+            &identity.peers.unwrap_or_default(),
+            &identity.groups.unwrap_or_default(),
+        )
+        .context("the edited configuration is invalid, nothing was written")?;
+    }
+    Ok(())
+}
+
+/// Writes through a temporary file next to the original, so a failed write doesn't truncate it.
+/// The configuration may contain a secret key, so the temporary file is created with the original permissions,
+/// it's never readable by anyone the original isn't. A temporary file left behind is not reused.
+pub(super) fn write_preserving_permissions(path: &Path, content: &str) -> anyhow::Result<()> {
+    let permissions = std::fs::metadata(path)
+        .with_context(|| format!("failed to stat {}", path.display()))?
+        .permissions();
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(permissions.mode());
+    }
+    let mut file = options.open(&tmp).with_context(|| {
+        format!(
+            "failed to create {}, if it's left over from an earlier edit, remove it",
+            tmp.display()
+        )
+    })?;
+    let res = write_and_replace(&mut file, &tmp, path, content, permissions);
+    drop(file);
+    if res.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    res
+}
+
+fn write_and_replace(
+    file: &mut std::fs::File,
+    tmp: &Path,
+    path: &Path,
+    content: &str,
+    permissions: std::fs::Permissions,
+) -> anyhow::Result<()> {
+    file.write_all(content.as_bytes())
+        .with_context(|| format!("failed to write {}", tmp.display()))?;
+    // The mode given on creation is masked by the umask, this puts back what it took away
+    file.set_permissions(permissions)
+        .with_context(|| format!("failed to set permissions on {}", tmp.display()))?;
+    file.sync_all()
+        .with_context(|| format!("failed to sync {}", tmp.display()))?;
+    std::fs::rename(tmp, path)
+        .with_context(|| format!("failed to move {} to {}", tmp.display(), path.display()))
+}
+
+#[cfg(unix)]
+fn send_sighup(pid: i32) -> anyhow::Result<()> {
+    // Safety: kill has no memory safety requirements
+    let res = unsafe { libc::kill(pid, libc::SIGHUP) };
+    if res != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("failed to send SIGHUP to {pid}"));
+    }
+    Ok(())
+}
@@ -1,6 +1,21 @@
-use crate::configuration::{P2ProxydSetup, P2proxydTomlConfig};
-use crate::proto::SocketAddrGetResult;
+use crate::configuration::{
+    IdentitySettings, P2ProxydSetup, P2proxydTomlConfig, access_log_config, check,
+    construct_routes, edit, ensure_secret_key,
+};
+use p2proxy_lib::rate_limit::BandwidthLimit;
+use p2proxy_server::access_log::{
+    AccessLogConfig, AccessLogFormat, AccessLogRotation, AccessLogSink,
+};
+use p2proxy_server::ban::BanPolicy;
+use p2proxy_server::limits::Limits;
+use p2proxy_server::quota::{Quota, QuotaExceeded, Usage};
+use p2proxy_server::routes::{RouteTarget, Routes, SharedRoutes, SocketAddrGetResult};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use time::{Date, Month};
 
 const SIMPLE_CFG: &str = include_str!("../../../assets/config/simple.toml");
 
@@ -24,16 +39,25 @@ fn test_simple_config_parsing() {
     assert!(config.peers.is_none());
     assert_eq!(1, config.server_ports.len());
     let setup = P2ProxydSetup::from_toml(config).unwrap();
+    assert!(!setup.named_identities);
     // Lets anyone through
     let pubk = iroh::SecretKey::from_bytes(&[0u8; 32]).public();
-    let SocketAddrGetResult::Allowed(sr) = setup.routes.default_route(&pubk) else {
+    let SocketAddrGetResult::Allowed(sr) = setup.identities[0].routes.default_route(&pubk) else {
         panic!("Default route should be allowed");
     };
-    assert_eq!(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 4501), sr);
-    let SocketAddrGetResult::Allowed(sr) = setup.routes.get(&pubk, &zero_pad("default")) else {
+    assert_eq!(
+        RouteTarget::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 4501)),
+        sr.target
+    );
+    let SocketAddrGetResult::Allowed(sr) =
+        setup.identities[0].routes.get(&pubk, &zero_pad("default"))
+    else {
         panic!("\"default\" route should be allowed");
     };
-    assert_eq!(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 4501), sr);
+    assert_eq!(
+        RouteTarget::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 4501)),
+        sr.target
+    );
 }
 
 const EXTENSIVE_CFG: &str = include_str!("../../../assets/config/extensive.toml");
@@ -61,24 +85,939 @@ fn test_extensive_config_parsing() {
     let allowed = allowed_secret.public();
     // Lets anyone through
     for pk in [&anyone, &allowed] {
-        let SocketAddrGetResult::Allowed(sr) = setup.routes.default_route(pk) else {
+        let SocketAddrGetResult::Allowed(sr) = setup.identities[0].routes.default_route(pk) else {
             panic!("Default route should be allowed");
         };
-        assert_eq!(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 4502), sr);
-        let SocketAddrGetResult::Allowed(sr) = setup.routes.get(pk, &zero_pad("demo")) else {
+        assert_eq!(
+            RouteTarget::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 4502)),
+            sr.target
+        );
+        let SocketAddrGetResult::Allowed(sr) =
+            setup.identities[0].routes.get(pk, &zero_pad("demo"))
+        else {
             panic!("\"demo\" route should be allowed");
         };
-        assert_eq!(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 4502), sr);
+        assert_eq!(
+            RouteTarget::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 4502)),
+            sr.target
+        );
     }
 
     // Allowed can see private
-    let SocketAddrGetResult::Allowed(sr) = setup.routes.get(&allowed, &zero_pad("private")) else {
+    let SocketAddrGetResult::Allowed(sr) = setup.identities[0]
+        .routes
+        .get(&allowed, &zero_pad("private"))
+    else {
         panic!("\"private\" route should be allowed");
     };
-    assert_eq!(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 4503), sr);
+    assert_eq!(
+        RouteTarget::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 4503)),
+        sr.target
+    );
     // Anyone can't see private
-    let SocketAddrGetResult::NotAllowed = setup.routes.get(&anyone, &zero_pad("private")) else {
+    let SocketAddrGetResult::NotAllowed = setup.identities[0]
+        .routes
+        .get(&anyone, &zero_pad("private"))
+    else {
         panic!("\"private\" route should be disallowed");
     };
     // disallowed writes to access log
 }
+
+#[test]
+fn test_swapped_routes_leave_snapshots_intact() {
+    let mut simple =
+        P2ProxydSetup::from_toml(P2proxydTomlConfig::parse_toml(SIMPLE_CFG.as_ref()).unwrap())
+            .unwrap();
+    let mut extensive =
+        P2ProxydSetup::from_toml(P2proxydTomlConfig::parse_toml(EXTENSIVE_CFG.as_ref()).unwrap())
+            .unwrap();
+    let shared = SharedRoutes::new(simple.identities.remove(0).routes);
+    let before = shared.load();
+    shared.store(extensive.identities.remove(0).routes);
+    let after = shared.load();
+    let anyone = iroh::SecretKey::from_bytes(&[0u8; 32]).public();
+    // Streams routed before the swap keep their view of the old config
+    let SocketAddrGetResult::Allowed(sr) = before.get(&anyone, &zero_pad("default")) else {
+        panic!("\"default\" route should be allowed in the snapshot taken before the swap");
+    };
+    assert_eq!(
+        RouteTarget::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 4501)),
+        sr.target
+    );
+    let SocketAddrGetResult::NotPresent = after.get(&anyone, &zero_pad("default")) else {
+        panic!("\"default\" route should be gone after the swap");
+    };
+    let SocketAddrGetResult::Allowed(sr) = after.get(&anyone, &zero_pad("demo")) else {
+        panic!("\"demo\" route should be allowed after the swap");
+    };
+    assert_eq!(
+        RouteTarget::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 4502)),
+        sr.target
+    );
+}
+
+#[test]
+fn test_udp_port_parsing() {
+    const UDP_CFG: &str = r#"
+secret_key_hex = "8c3981f6f98d0a09f69931549a883d8ce1c37fbf767c28ace12c81ede4713bfc"
+
+[[server_ports]]
+port = 53
+name = "dns"
+protocol = "udp"
+allow_any_peer = true
+
+[[server_ports]]
+port = 51820
+name = "wireguard"
+protocol = "udp"
+idle_timeout_secs = 180
+allow_any_peer = true
+"#;
+    let config = P2proxydTomlConfig::parse_toml(UDP_CFG.as_ref()).unwrap();
+    let setup = P2ProxydSetup::from_toml(config).unwrap();
+    let anyone = iroh::SecretKey::from_bytes(&[0u8; 32]).public();
+    let SocketAddrGetResult::Allowed(sr) =
+        setup.identities[0].routes.get(&anyone, &zero_pad("dns"))
+    else {
+        panic!("\"dns\" route should be allowed");
+    };
+    assert_eq!(
+        RouteTarget::Udp {
+            socket_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 53),
+            idle_timeout: Duration::from_secs(60),
+        },
+        sr.target
+    );
+    let SocketAddrGetResult::Allowed(sr) = setup.identities[0]
+        .routes
+        .get(&anyone, &zero_pad("wireguard"))
+    else {
+        panic!("\"wireguard\" route should be allowed");
+    };
+    assert_eq!(
+        RouteTarget::Udp {
+            socket_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 51820),
+            idle_timeout: Duration::from_secs(180),
+        },
+        sr.target
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn test_unix_port_parsing() {
+    const UNIX_CFG: &str = r#"
+secret_key_hex = "8c3981f6f98d0a09f69931549a883d8ce1c37fbf767c28ace12c81ede4713bfc"
+
+[[server_ports]]
+unix_path = "/run/docker.sock"
+name = "docker"
+allow_any_peer = true
+"#;
+    let config = P2proxydTomlConfig::parse_toml(UNIX_CFG.as_ref()).unwrap();
+    let setup = P2ProxydSetup::from_toml(config).unwrap();
+    let anyone = iroh::SecretKey::from_bytes(&[0u8; 32]).public();
+    let SocketAddrGetResult::Allowed(sr) =
+        setup.identities[0].routes.get(&anyone, &zero_pad("docker"))
+    else {
+        panic!("\"docker\" route should be allowed");
+    };
+    assert_eq!(
+        RouteTarget::Unix(std::path::PathBuf::from("/run/docker.sock")),
+        sr.target
+    );
+
+    let both = UNIX_CFG.replace("name = \"docker\"", "name = \"docker\"\nport = 2375");
+    let config = P2proxydTomlConfig::parse_toml(both.as_ref()).unwrap();
+    assert!(P2ProxydSetup::from_toml(config).is_err());
+}
+
+#[test]
+fn test_access_log_parsing() {
+    const ACCESS_LOG_CFG: &str = r#"
+secret_key_hex = "8c3981f6f98d0a09f69931549a883d8ce1c37fbf767c28ace12c81ede4713bfc"
+server_ports = []
+
+[access_log]
+format = "json"
+sink = "stdout"
+"#;
+    let config = P2proxydTomlConfig::parse_toml(ACCESS_LOG_CFG.as_ref()).unwrap();
+    assert_eq!(
+        Some(AccessLogConfig {
+            format: AccessLogFormat::Json,
+            sink: AccessLogSink::Stdout,
+            rotation: None,
+        }),
+        access_log_config(config.access_log_path, config.access_log).unwrap()
+    );
+
+    let file_without_path = ACCESS_LOG_CFG.replace("sink = \"stdout\"", "sink = \"file\"");
+    let config = P2proxydTomlConfig::parse_toml(file_without_path.as_ref()).unwrap();
+    assert!(access_log_config(config.access_log_path, config.access_log).is_err());
+
+    let file = ACCESS_LOG_CFG.replace("sink = \"stdout\"", "path = \"/tmp/access.log\"");
+    let config = P2proxydTomlConfig::parse_toml(file.as_ref()).unwrap();
+    assert_eq!(
+        Some(AccessLogConfig {
+            format: AccessLogFormat::Json,
+            sink: AccessLogSink::File(std::path::PathBuf::from("/tmp/access.log")),
+            rotation: None,
+        }),
+        access_log_config(config.access_log_path, config.access_log).unwrap()
+    );
+
+    let rotated = file.replace(
+        "format = \"json\"",
+        "format = \"json\"\nrotate_size_bytes = 1048576\nrotate_daily = true\nretain_files = 3",
+    );
+    let config = P2proxydTomlConfig::parse_toml(rotated.as_ref()).unwrap();
+    assert_eq!(
+        Some(AccessLogRotation {
+            max_bytes: Some(1_048_576),
+            daily: true,
+            retain: 3,
+        }),
+        access_log_config(config.access_log_path, config.access_log)
+            .unwrap()
+            .unwrap()
+            .rotation
+    );
+
+    let rotated_stdout = ACCESS_LOG_CFG.replace("format = \"json\"", "rotate_daily = true");
+    let config = P2proxydTomlConfig::parse_toml(rotated_stdout.as_ref()).unwrap();
+    assert!(access_log_config(config.access_log_path, config.access_log).is_err());
+
+    let both = file.replace(
+        "server_ports = []",
+        "server_ports = []\naccess_log_path = \"/tmp/access.log\"",
+    );
+    let config = P2proxydTomlConfig::parse_toml(both.as_ref()).unwrap();
+    assert!(access_log_config(config.access_log_path, config.access_log).is_err());
+}
+
+#[test]
+fn test_config_edit_keeps_formatting() {
+    let mut doc = EXTENSIVE_CFG.parse::<toml_edit::DocumentMut>().unwrap();
+    let new_peer = iroh::SecretKey::from_bytes(&[1u8; 32]).public();
+    edit::add_peer(&mut doc, new_peer, false, &["private".to_string()]).unwrap();
+    let edited = doc.to_string();
+    assert!(edited.contains("# A collection of approved peers"));
+    edit::validate(&edited, "p2proxyd.toml".as_ref()).unwrap();
+    let config = P2proxydTomlConfig::parse_toml(edited.as_ref()).unwrap();
+    let setup = P2ProxydSetup::from_toml(config).unwrap();
+    assert!(matches!(
+        setup.identities[0]
+            .routes
+            .get(&new_peer, &zero_pad("private")),
+        SocketAddrGetResult::Allowed(_)
+    ));
+
+    edit::remove_peer(&mut doc, new_peer).unwrap();
+    assert!(!doc.to_string().contains(&new_peer.to_string()));
+    assert!(edit::remove_peer(&mut doc, new_peer).is_err());
+
+    // The default route can't be removed while it's still the default
+    edit::remove_port(&mut doc, "demo").unwrap();
+    assert!(edit::validate(&doc.to_string(), "p2proxyd.toml".as_ref()).is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_config_edit_keeps_permissions() {
+    use std::os::unix::fs::PermissionsExt;
+    let dir = std::env::temp_dir().join(format!("p2proxyd-edit-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("p2proxyd.toml");
+    std::fs::write(&path, "before").unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+    edit::write_preserving_permissions(&path, "after").unwrap();
+    assert_eq!("after", std::fs::read_to_string(&path).unwrap());
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(0o600, mode & 0o777);
+
+    // A temporary file left behind could have any permissions, it's not written through
+    let tmp = dir.join("p2proxyd.toml.tmp");
+    std::fs::write(&tmp, "stale").unwrap();
+    assert!(edit::write_preserving_permissions(&path, "again").is_err());
+    assert_eq!("after", std::fs::read_to_string(&path).unwrap());
+    assert_eq!("stale", std::fs::read_to_string(&tmp).unwrap());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_conf_dir_merging() {
+    let dir = std::env::temp_dir().join(format!("p2proxyd-conf-dir-test-{}", std::process::id()));
+    let conf_d = dir.join("conf.d");
+    std::fs::create_dir_all(&conf_d).unwrap();
+    let main = dir.join("p2proxyd.toml");
+    std::fs::write(
+        &main,
+        SIMPLE_CFG.replace(
+            "[[server_ports]]",
+            "conf_dir = \"conf.d\"\n\n[[server_ports]]",
+        ),
+    )
+    .unwrap();
+    std::fs::write(
+        conf_d.join("10-private.toml"),
+        r#"
+[[server_ports]]
+port = 4503
+name = "private"
+
+[[peers]]
+node_id = "69a0507ed92bf714b99135024a15628ad508a90db9e142a8518e7a9d939de7ba"
+allow_any_port = false
+allow_named_ports = ["private"]
+"#,
+    )
+    .unwrap();
+    // Not a fragment
+    std::fs::write(conf_d.join("README"), "not toml").unwrap();
+
+    let setup = P2ProxydSetup::from_toml(P2proxydTomlConfig::from_path(&main).unwrap()).unwrap();
+    let allowed = "69a0507ed92bf714b99135024a15628ad508a90db9e142a8518e7a9d939de7ba"
+        .parse()
+        .unwrap();
+    assert!(matches!(
+        setup.identities[0]
+            .routes
+            .get(&allowed, &zero_pad("private")),
+        SocketAddrGetResult::Allowed(_)
+    ));
+    assert!(matches!(
+        setup.identities[0]
+            .routes
+            .get(&allowed, &zero_pad("default")),
+        SocketAddrGetResult::Allowed(_)
+    ));
+
+    std::fs::write(
+        conf_d.join("20-dup.toml"),
+        "[[server_ports]]\nport = 4504\nname = \"private\"\n",
+    )
+    .unwrap();
+    let err = format!("{:#}", P2proxydTomlConfig::from_path(&main).unwrap_err());
+    assert!(err.contains("10-private.toml"), "{err}");
+    assert!(err.contains("20-dup.toml"), "{err}");
+
+    std::fs::write(conf_d.join("20-dup.toml"), "secret_key_hex = \"00\"\n").unwrap();
+    assert!(P2proxydTomlConfig::from_path(&main).is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_secret_key_sources() {
+    const HEX: &str = "8c3981f6f98d0a09f69931549a883d8ce1c37fbf767c28ace12c81ede4713bfc";
+    let expected = iroh::SecretKey::from_str(HEX).unwrap();
+    let key_path = std::env::temp_dir().join(format!("p2proxyd-key-test-{}", std::process::id()));
+    std::fs::write(&key_path, format!("{HEX}\n")).unwrap();
+    let mut config = IdentitySettings {
+        secret_key_path: Some(key_path.clone()),
+        ..IdentitySettings::default()
+    };
+    assert_eq!(
+        expected.to_bytes(),
+        ensure_secret_key(&config).unwrap().to_bytes()
+    );
+
+    std::fs::write(&key_path, expected.to_bytes()).unwrap();
+    assert_eq!(
+        expected.to_bytes(),
+        ensure_secret_key(&config).unwrap().to_bytes()
+    );
+
+    // Every source is read, and they have to agree
+    config.secret_key_hex = Some(HEX.replace('8', "9"));
+    assert!(ensure_secret_key(&config).is_err());
+    std::fs::remove_file(&key_path).unwrap();
+
+    #[cfg(unix)]
+    {
+        let config = IdentitySettings {
+            secret_key_command: Some(vec!["echo".to_string(), HEX.to_string()]),
+            ..IdentitySettings::default()
+        };
+        assert_eq!(
+            expected.to_bytes(),
+            ensure_secret_key(&config).unwrap().to_bytes()
+        );
+        let config = IdentitySettings {
+            secret_key_command: Some(vec!["false".to_string()]),
+            ..IdentitySettings::default()
+        };
+        assert!(ensure_secret_key(&config).is_err());
+    }
+
+    assert!(ensure_secret_key(&IdentitySettings::default()).is_err());
+}
+
+#[test]
+fn test_identities_parsing() {
+    const IDENTITIES_CFG: &str = r#"
+[[identities]]
+name = "family"
+secret_key_hex = "8c3981f6f98d0a09f69931549a883d8ce1c37fbf767c28ace12c81ede4713bfc"
+default_route = "photos"
+
+[[identities.server_ports]]
+port = 2342
+name = "photos"
+allow_any_peer = true
+
+[[identities]]
+name = "work"
+secret_key_hex = "690927f498c370cff79be198b1e6b81e3ec12521d1a76753c8aff67a7bb6f549"
+
+[[identities.server_ports]]
+port = 8080
+name = "wiki"
+
+[[identities.peers]]
+node_id = "69a0507ed92bf714b99135024a15628ad508a90db9e142a8518e7a9d939de7ba"
+allow_any_port = true
+"#;
+    let config = P2proxydTomlConfig::parse_toml(IDENTITIES_CFG.as_ref()).unwrap();
+    let setup = P2ProxydSetup::from_toml(config).unwrap();
+    assert_eq!(2, setup.identities.len());
+    assert!(setup.named_identities);
+    let (family, work) = (&setup.identities[0], &setup.identities[1]);
+    assert_eq!("family", family.name);
+    assert_eq!(
+        "7c32ab7cdd9a4e2651c9eff072958a43a9b411cc5b69603a1dca6d7d843f2406",
+        family.secret_key.public().to_string()
+    );
+    assert_eq!(
+        "f2b1ce018dda1d4e75d97fc9f86ecf30adbb0aba0977445ae85283502a8cc7be",
+        work.secret_key.public().to_string()
+    );
+    let anyone = iroh::SecretKey::from_bytes(&[0u8; 32]).public();
+    let colleague = "69a0507ed92bf714b99135024a15628ad508a90db9e142a8518e7a9d939de7ba"
+        .parse()
+        .unwrap();
+    // Each identity only serves its own routes, to its own peers
+    assert!(matches!(
+        family.routes.get(&anyone, &zero_pad("photos")),
+        SocketAddrGetResult::Allowed(_)
+    ));
+    assert!(matches!(
+        work.routes.get(&anyone, &zero_pad("photos")),
+        SocketAddrGetResult::NotPresent
+    ));
+    assert!(matches!(
+        work.routes.get(&colleague, &zero_pad("wiki")),
+        SocketAddrGetResult::Allowed(_)
+    ));
+    assert!(matches!(
+        work.routes.get(&anyone, &zero_pad("wiki")),
+        SocketAddrGetResult::NotAllowed
+    ));
+
+    let duplicate_name = IDENTITIES_CFG.replace("name = \"work\"", "name = \"family\"");
+    let config = P2proxydTomlConfig::parse_toml(duplicate_name.as_ref()).unwrap();
+    assert!(P2ProxydSetup::from_toml(config).is_err());
+
+    let with_top_level = format!("default_route = \"photos\"\n{IDENTITIES_CFG}");
+    let config = P2proxydTomlConfig::parse_toml(with_top_level.as_ref()).unwrap();
+    assert!(P2ProxydSetup::from_toml(config).is_err());
+}
+
+#[test]
+fn test_previous_key_parsing() {
+    const ROTATING_CFG: &str = r#"
+secret_key_hex = "8c3981f6f98d0a09f69931549a883d8ce1c37fbf767c28ace12c81ede4713bfc"
+default_route = "default"
+
+[previous_key]
+secret_key_hex = "690927f498c370cff79be198b1e6b81e3ec12521d1a76753c8aff67a7bb6f549"
+until = "2999-01-01T00:00:00Z"
+
+[[server_ports]]
+port = 4501
+name = "default"
+allow_any_peer = true
+"#;
+    let config = P2proxydTomlConfig::parse_toml(ROTATING_CFG.as_ref()).unwrap();
+    let setup = P2ProxydSetup::from_toml(config).unwrap();
+    let previous = setup.identities[0].previous_key.as_ref().unwrap();
+    assert_eq!(
+        "f2b1ce018dda1d4e75d97fc9f86ecf30adbb0aba0977445ae85283502a8cc7be",
+        previous.secret_key.public().to_string()
+    );
+
+    // Once the rotation is over, only the new key is served
+    let over = ROTATING_CFG.replace("2999-01-01T00:00:00Z", "2001-01-01T00:00:00Z");
+    let config = P2proxydTomlConfig::parse_toml(over.as_ref()).unwrap();
+    let setup = P2ProxydSetup::from_toml(config).unwrap();
+    assert!(setup.identities[0].previous_key.is_none());
+
+    let not_a_timestamp = ROTATING_CFG.replace("2999-01-01T00:00:00Z", "next week");
+    let config = P2proxydTomlConfig::parse_toml(not_a_timestamp.as_ref()).unwrap();
+    assert!(P2ProxydSetup::from_toml(config).is_err());
+
+    let same_key = ROTATING_CFG.replace(
+        "690927f498c370cff79be198b1e6b81e3ec12521d1a76753c8aff67a7bb6f549",
+        "8c3981f6f98d0a09f69931549a883d8ce1c37fbf767c28ace12c81ede4713bfc",
+    );
+    let config = P2proxydTomlConfig::parse_toml(same_key.as_ref()).unwrap();
+    assert!(P2ProxydSetup::from_toml(config).is_err());
+}
+
+#[test]
+fn test_limits_parsing() {
+    let config = P2proxydTomlConfig::parse_toml(SIMPLE_CFG.as_ref()).unwrap();
+    let setup = P2ProxydSetup::from_toml(config).unwrap();
+    assert_eq!(Limits::default(), setup.limits);
+
+    let limited = format!(
+        "{}\nmax_streams = 4\n\n[limits]\nmax_connections_per_peer = 2\nmax_streams_per_peer = 16\nheader_timeout_secs = 3\n",
+        SIMPLE_CFG.trim_end()
+    );
+    let config = P2proxydTomlConfig::parse_toml(limited.as_ref()).unwrap();
+    let setup = P2ProxydSetup::from_toml(config).unwrap();
+    assert_eq!(
+        Limits {
+            max_connections_per_peer: Some(2),
+            max_streams_per_peer: Some(16),
+            max_streams: None,
+            header_timeout: Duration::from_secs(3),
+        },
+        setup.limits
+    );
+    let pubk = iroh::SecretKey::from_bytes(&[0u8; 32]).public();
+    let SocketAddrGetResult::Allowed(port_config) = setup.identities[0].routes.default_route(&pubk)
+    else {
+        panic!("Default route should be allowed");
+    };
+    assert_eq!(Some(4), port_config.limits.max_streams);
+
+    let zero = limited.replace("max_streams_per_peer = 16", "max_streams_per_peer = 0");
+    let config = P2proxydTomlConfig::parse_toml(zero.as_ref()).unwrap();
+    assert!(P2ProxydSetup::from_toml(config).is_err());
+}
+
+#[test]
+fn test_ban_parsing() {
+    let config = P2proxydTomlConfig::parse_toml(SIMPLE_CFG.as_ref()).unwrap();
+    let setup = P2ProxydSetup::from_toml(config).unwrap();
+    assert_eq!(None, setup.ban_policy);
+
+    let banning = format!("{}\n\n[ban]\nmax_rejections = 3\n", SIMPLE_CFG.trim_end());
+    let config = P2proxydTomlConfig::parse_toml(banning.as_ref()).unwrap();
+    let setup = P2ProxydSetup::from_toml(config).unwrap();
+    assert_eq!(
+        Some(BanPolicy {
+            max_rejections: 3,
+            window: Duration::from_secs(600),
+            ban_time: Duration::from_secs(600),
+        }),
+        setup.ban_policy
+    );
+
+    for invalid in ["max_rejections = 0", "ban_secs = 0", "ban_secs = 100000000"] {
+        let cfg = format!("{}\n\n[ban]\n{invalid}\n", SIMPLE_CFG.trim_end());
+        let config = P2proxydTomlConfig::parse_toml(cfg.as_ref()).unwrap();
+        assert!(P2ProxydSetup::from_toml(config).is_err(), "{invalid}");
+    }
+}
+
+#[test]
+fn test_bandwidth_parsing() {
+    let pubk = iroh::SecretKey::from_bytes(&[0u8; 32]).public();
+    let limited = format!(
+        "{}\nbandwidth = {{ bytes_per_sec = 1000 }}\n\n[[peers]]\nnode_id = \"{pubk}\"\nallow_any_port = true\nbandwidth = {{ bytes_per_sec = 500, burst_bytes = 2000 }}\n",
+        SIMPLE_CFG.trim_end()
+    );
+    let setup = P2ProxydSetup::from_toml(P2proxydTomlConfig::parse_toml(limited.as_ref()).unwrap())
+        .unwrap();
+    let routes = &setup.identities[0].routes;
+    let SocketAddrGetResult::Allowed(port_config) = routes.default_route(&pubk) else {
+        panic!("Default route should be allowed");
+    };
+    // Burst defaults to a second's worth
+    assert_eq!(
+        Some(BandwidthLimit {
+            bytes_per_sec: 1000,
+            burst_bytes: 1000,
+        }),
+        port_config.limits.bandwidth
+    );
+    assert_eq!(
+        BandwidthLimit {
+            bytes_per_sec: 500,
+            burst_bytes: 2000,
+        },
+        routes.peer_bandwidth(&pubk).unwrap().limit()
+    );
+
+    // Unchanged limits keep their buckets across a reload
+    let reload = || {
+        P2ProxydSetup::from_toml(P2proxydTomlConfig::parse_toml(limited.as_ref()).unwrap())
+            .unwrap()
+            .identities
+            .remove(0)
+            .routes
+    };
+    let shared = SharedRoutes::new(reload());
+    let before = shared.load();
+    shared.store(reload());
+    let after = shared.load();
+    assert!(Arc::ptr_eq(
+        before.peer_bandwidth(&pubk).unwrap(),
+        after.peer_bandwidth(&pubk).unwrap()
+    ));
+    let route_bucket = |routes: &Routes| {
+        let SocketAddrGetResult::Allowed(port_config) = routes.default_route(&pubk) else {
+            panic!("Default route should be allowed");
+        };
+        port_config.bandwidth.clone().unwrap()
+    };
+    assert!(Arc::ptr_eq(&route_bucket(&before), &route_bucket(&after)));
+
+    let zero = limited.replace("bytes_per_sec = 500", "bytes_per_sec = 0");
+    let config = P2proxydTomlConfig::parse_toml(zero.as_ref()).unwrap();
+    assert!(P2ProxydSetup::from_toml(config).is_err());
+}
+
+#[test]
+fn test_quota_parsing() {
+    let pubk = iroh::SecretKey::from_bytes(&[0u8; 32]).public();
+    let peer = format!(
+        "\n[[peers]]\nnode_id = \"{pubk}\"\nallow_any_port = true\nquota_bytes_per_day = 1000\n"
+    );
+    // Counters that don't survive a restart aren't much of a quota
+    let unpersisted = format!("{}\n{peer}", SIMPLE_CFG.trim_end());
+    let config = P2proxydTomlConfig::parse_toml(unpersisted.as_ref()).unwrap();
+    assert!(P2ProxydSetup::from_toml(config).is_err());
+
+    let persisted = format!(
+        "quota_state_path = \"/var/lib/p2proxyd/quota.json\"\n{}\n{peer}",
+        SIMPLE_CFG.trim_end()
+    );
+    let setup =
+        P2ProxydSetup::from_toml(P2proxydTomlConfig::parse_toml(persisted.as_ref()).unwrap())
+            .unwrap();
+    assert_eq!(
+        Some(std::path::PathBuf::from("/var/lib/p2proxyd/quota.json")),
+        setup.quota_state_path
+    );
+    let quota = *setup.identities[0].routes.peer_quota(&pubk).unwrap();
+    assert_eq!(
+        Quota {
+            bytes_per_day: Some(1000),
+            bytes_per_month: None,
+        },
+        quota
+    );
+
+    let day = Date::from_calendar_date(2026, Month::October, 17).unwrap();
+    let usage = Usage::new();
+    usage.check_on(pubk, &quota, day).unwrap().add(1000);
+    assert_eq!(
+        Err(QuotaExceeded::Daily {
+            used: 1000,
+            quota: 1000
+        }),
+        usage.check_on(pubk, &quota, day).map(drop)
+    );
+    // Counters survive a restart on the same day, the daily one starts over on the next
+    let restored = Usage::new();
+    restored.restore_on(&usage.snapshot_on(day), day).unwrap();
+    assert!(restored.check_on(pubk, &quota, day).is_err());
+    let next_day = day.next_day().unwrap();
+    let today = restored.check_on(pubk, &quota, next_day).unwrap();
+    assert_eq!(0, today.today.load(Ordering::Relaxed));
+    assert_eq!(1000, today.this_month.load(Ordering::Relaxed));
+
+    let zero = persisted.replace("quota_bytes_per_day = 1000", "quota_bytes_per_day = 0");
+    let config = P2proxydTomlConfig::parse_toml(zero.as_ref()).unwrap();
+    assert!(P2ProxydSetup::from_toml(config).is_err());
+}
+
+#[test]
+fn test_stream_timeouts_parsing() {
+    const TIMEOUTS_CFG: &str = r#"
+secret_key_hex = "8c3981f6f98d0a09f69931549a883d8ce1c37fbf767c28ace12c81ede4713bfc"
+
+[[server_ports]]
+port = 22
+name = "ssh"
+idle_timeout_secs = 600
+max_lifetime_secs = 86400
+allow_any_peer = true
+
+[[server_ports]]
+port = 51820
+name = "wireguard"
+protocol = "udp"
+idle_timeout_secs = 180
+max_lifetime_secs = 3600
+allow_any_peer = true
+"#;
+    let config = P2proxydTomlConfig::parse_toml(TIMEOUTS_CFG.as_ref()).unwrap();
+    let setup = P2ProxydSetup::from_toml(config).unwrap();
+    let anyone = iroh::SecretKey::from_bytes(&[0u8; 32]).public();
+    let SocketAddrGetResult::Allowed(sr) =
+        setup.identities[0].routes.get(&anyone, &zero_pad("ssh"))
+    else {
+        panic!("\"ssh\" route should be allowed");
+    };
+    assert_eq!(Some(Duration::from_secs(600)), sr.limits.idle_timeout);
+    assert_eq!(Some(Duration::from_secs(86400)), sr.limits.max_lifetime);
+    let SocketAddrGetResult::Allowed(sr) = setup.identities[0]
+        .routes
+        .get(&anyone, &zero_pad("wireguard"))
+    else {
+        panic!("\"wireguard\" route should be allowed");
+    };
+    // A udp flow's idle timeout is part of its target
+    assert_eq!(None, sr.limits.idle_timeout);
+    assert_eq!(Some(Duration::from_secs(3600)), sr.limits.max_lifetime);
+
+    let zero = TIMEOUTS_CFG.replace("max_lifetime_secs = 86400", "max_lifetime_secs = 0");
+    let config = P2proxydTomlConfig::parse_toml(zero.as_ref()).unwrap();
+    assert!(P2ProxydSetup::from_toml(config).is_err());
+}
+
+#[test]
+fn test_groups_and_denies_parsing() {
+    let dev = iroh::SecretKey::from_bytes(&[1u8; 32]).public();
+    let contractor = iroh::SecretKey::from_bytes(&[2u8; 32]).public();
+    let anyone = iroh::SecretKey::from_bytes(&[3u8; 32]).public();
+    let cfg = format!(
+        r#"
+secret_key_hex = "8c3981f6f98d0a09f69931549a883d8ce1c37fbf767c28ace12c81ede4713bfc"
+
+[[server_ports]]
+port = 80
+name = "web"
+allow_any_peer = true
+
+[[server_ports]]
+port = 22
+name = "ssh"
+
+[[server_ports]]
+port = 4503
+name = "private"
+
+[[groups]]
+name = "devs"
+members = ["{dev}", "{contractor}"]
+allow_named_ports = ["ssh", "private"]
+
+[[peers]]
+node_id = "{contractor}"
+deny_named_ports = ["private", "web"]
+"#
+    );
+    let config = P2proxydTomlConfig::parse_toml(cfg.as_ref()).unwrap();
+    let setup = P2ProxydSetup::from_toml(config).unwrap();
+    let routes = &setup.identities[0].routes;
+    let allowed = |peer, route: &str| {
+        matches!(
+            routes.get(peer, &zero_pad(route)),
+            SocketAddrGetResult::Allowed(_)
+        )
+    };
+    assert!(allowed(&dev, "ssh"));
+    assert!(allowed(&dev, "private"));
+    assert!(allowed(&dev, "web"));
+    assert!(allowed(&contractor, "ssh"));
+    // A deny wins over the group's grant, and over a public route
+    assert!(!allowed(&contractor, "private"));
+    assert!(!allowed(&contractor, "web"));
+    assert!(allowed(&anyone, "web"));
+    assert!(!allowed(&anyone, "ssh"));
+
+    // Denying a route that doesn't exist is most likely a typo that would leave the route open
+    let typo = cfg.replace(r#"["private", "web"]"#, r#"["privte"]"#);
+    let config = P2proxydTomlConfig::parse_toml(typo.as_ref()).unwrap();
+    assert!(P2ProxydSetup::from_toml(config).is_err());
+
+    // A route everyone allowed on is denied can't be connected to
+    let members = format!(r#"members = ["{dev}", "{contractor}"]"#);
+    let closed = cfg.replace(&members, &format!(r#"members = ["{contractor}"]"#));
+    let config = P2proxydTomlConfig::parse_toml(closed.as_ref()).unwrap();
+    assert!(P2ProxydSetup::from_toml(config).is_err());
+
+    let duplicate = cfg.replace(
+        "[[peers]]",
+        "[[groups]]\nname = \"devs\"\nmembers = []\n\n[[peers]]",
+    );
+    let config = P2proxydTomlConfig::parse_toml(duplicate.as_ref()).unwrap();
+    assert!(P2ProxydSetup::from_toml(config).is_err());
+}
+
+#[test]
+fn test_time_bounded_grants_parsing() {
+    let contractor = iroh::SecretKey::from_bytes(&[1u8; 32]).public();
+    let cfg = format!(
+        r#"
+secret_key_hex = "8c3981f6f98d0a09f69931549a883d8ce1c37fbf767c28ace12c81ede4713bfc"
+
+[[server_ports]]
+port = 22
+name = "ssh"
+
+[[server_ports]]
+port = 9418
+name = "git"
+
+[[peers]]
+node_id = "{contractor}"
+expires_at = "2025-12-31T18:00:00+01:00"
+allow_named_ports = [
+    "git",
+    {{ name = "ssh", schedule = {{ days = ["mon", "tue", "wed", "thu", "fri"], from = "08:00", until = "18:00", utc_offset = "+01:00" }} }},
+]
+"#
+    );
+    let config = P2proxydTomlConfig::parse_toml(cfg.as_ref()).unwrap();
+    let setup = P2ProxydSetup::from_toml(config).unwrap();
+    let routes = &setup.identities[0].routes;
+    // 2025-12-01 is a monday, times are UTC
+    let at = |day: u8, hour: u8| {
+        Date::from_calendar_date(2025, Month::December, day)
+            .unwrap()
+            .with_hms(hour, 0, 0)
+            .unwrap()
+            .assume_utc()
+    };
+    let status = |route: &str, at| match routes.get_at(&contractor, &zero_pad(route), at) {
+        SocketAddrGetResult::Allowed(_) => "allowed",
+        SocketAddrGetResult::NotAllowed => "not allowed",
+        SocketAddrGetResult::NotPresent => "not present",
+        SocketAddrGetResult::Expired(_) => "expired",
+        SocketAddrGetResult::OutsideSchedule(_) => "outside schedule",
+    };
+    assert_eq!("allowed", status("ssh", at(1, 9)));
+    assert_eq!("allowed", status("git", at(1, 9)));
+    // 18:00 at +01:00
+    assert_eq!("outside schedule", status("ssh", at(1, 17)));
+    assert_eq!("outside schedule", status("ssh", at(6, 9)));
+    assert_eq!("allowed", status("git", at(6, 9)));
+    // The peer's expiry applies to the scheduled route as well
+    assert_eq!("expired", status("ssh", at(31, 17)));
+    assert_eq!("expired", status("git", at(31, 17)));
+
+    for (from, to) in [
+        ("2025-12-31T18:00:00+01:00", "2025-12-31"),
+        (r#"from = "08:00""#, r#"from = "8am""#),
+        (r#"from = "08:00""#, r#"from = "18:00""#),
+        (r#""mon""#, r#""monday-ish""#),
+        (r#"utc_offset = "+01:00""#, r#"utc_offset = "CET""#),
+        (r#", utc_offset = "+01:00""#, ""),
+    ] {
+        let invalid = cfg.replace(from, to);
+        let config = P2proxydTomlConfig::parse_toml(invalid.as_ref()).unwrap();
+        assert!(P2ProxydSetup::from_toml(config).is_err(), "{to}");
+    }
+}
+
+#[test]
+fn test_check_config() {
+    let peer = iroh::SecretKey::from_bytes(&[1u8; 32]).public();
+    let anyone = iroh::SecretKey::from_bytes(&[2u8; 32]).public();
+    let cfg = format!(
+        r#"
+secret_key_hex = "8c3981f6f98d0a09f69931549a883d8ce1c37fbf767c28ace12c81ede4713bfc"
+default_route = "web"
+
+[[server_ports]]
+port = 4501
+name = "web"
+allow_any_peer = true
+
+[[server_ports]]
+port = 4502
+name = "ssh"
+
+[[peers]]
+node_id = "{peer}"
+allow_named_ports = ["ssh"]
+"#
+    );
+    let mut config = P2proxydTomlConfig::parse_toml(cfg.as_ref()).unwrap();
+    let mut identity = config.take_identities().unwrap().remove(0);
+    let peers = identity.peers.take().unwrap_or_default();
+    let routes = construct_routes(
+        identity.default_route.clone(),
+        std::mem::take(&mut identity.server_ports),
+        &peers,
+        &[],
+    )
+    .unwrap();
+    let route_names = ["web".to_string(), "ssh".to_string()];
+    let matrix = check::access_matrix(&routes, &peers, &[], Some("web"), &route_names).unwrap();
+    let cells = |label: &str| {
+        let line = matrix.lines().find(|line| line.starts_with(label)).unwrap();
+        line[label.len()..].split_whitespace().collect::<Vec<_>>()
+    };
+    assert_eq!(
+        vec!["(default:", "web)", "web", "ssh"],
+        cells("peer \\ route")
+    );
+    assert_eq!(
+        vec!["allow", "allow", "allow"],
+        cells(peer.to_string().as_str())
+    );
+    assert_eq!(vec!["allow", "allow", "deny"], cells("<any other peer>"));
+
+    let dry_run = |peer, route: Option<&str>| {
+        check::dry_run(&routes, peer, route.map(str::to_string), "").unwrap()
+    };
+    assert!(dry_run(peer, Some("ssh")).starts_with("ALLOWED: "));
+    assert!(dry_run(anyone, Some("ssh")).starts_with("DENIED: "));
+    assert!(dry_run(anyone, None).starts_with("ALLOWED: "));
+    assert_eq!(
+        "DENIED: route 'nope' does not exist\n",
+        dry_run(anyone, Some("nope"))
+    );
+
+    let dir = std::env::temp_dir().join(format!("p2proxyd-check-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let cfg_path = dir.join("p2proxyd.toml");
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let key_path = dir.join("secret.key");
+        std::fs::write(&key_path, "key").unwrap();
+        let identity = IdentitySettings {
+            secret_key_path: Some(key_path.clone()),
+            ..IdentitySettings::default()
+        };
+        std::fs::set_permissions(&key_path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let mut warnings = Vec::new();
+        check::check_key_files(&cfg_path, &identity, &mut warnings);
+        assert_eq!(1, warnings.len());
+        assert!(warnings[0].contains("chmod 600"));
+        std::fs::set_permissions(&key_path, std::fs::Permissions::from_mode(0o600)).unwrap();
+        let mut warnings = Vec::new();
+        check::check_key_files(&cfg_path, &identity, &mut warnings);
+        assert!(warnings.is_empty());
+    }
+
+    // The exit status, warnings don't fail the check, invalid configurations do
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let run = |content: &str, peer: Option<iroh::NodeId>| {
+        std::fs::write(&cfg_path, content).unwrap();
+        runtime.block_on(check::run_check_config(check::CheckConfigArgs {
+            cfg_path: cfg_path.clone(),
+            peer,
+            route: None,
+        }))
+    };
+    assert!(run(&cfg, None).is_ok());
+    assert!(run(&cfg, Some(anyone)).is_ok());
+    assert!(run(&cfg.replace(r#"["ssh"]"#, r#"["ssh", "ssh"]"#), None).is_err());
+    assert!(run(&cfg.replace("port = 4502", "port = 70000"), None).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,8 +1,10 @@
-mod access_log;
+#[cfg(unix)]
+mod admin;
 mod configuration;
 mod observability;
-mod proto;
 mod proxy;
+mod systemd;
+mod usage;
 
 use crate::configuration::{P2proxydCliArgs, P2proxydTomlConfig};
 use crate::observability::setup_observability;
@@ -27,6 +29,27 @@ enum Subcommand {
         #[clap(flatten)]
         args: P2proxydCliArgs,
     },
+    /// Inspect or administer a running daemon through its admin socket
+    #[cfg(unix)]
+    Ctl {
+        #[clap(flatten)]
+        args: admin::CtlArgs,
+    },
+    /// Validate a configuration and show the effective access rules, without running it
+    CheckConfig {
+        #[clap(flatten)]
+        args: configuration::check::CheckConfigArgs,
+    },
+    /// Add, remove or list peers and server ports in a configuration file
+    Config {
+        #[clap(flatten)]
+        args: configuration::edit::ConfigArgs,
+    },
+    /// Show how much of their traffic quotas peers have used
+    Usage {
+        #[clap(flatten)]
+        args: usage::UsageArgs,
+    },
     /// Generate a template configuration
     GenerateTemplateConfiguration {
         /// The path to write the template configuration to
@@ -37,8 +60,19 @@ enum Subcommand {
 
 fn main() -> ExitCode {
     let args = Args::parse();
-    let runtime = LocalRuntime::new().expect("failed to create p2proxyd runtime");
-    match runtime.block_on(run_app(args)) {
+    let res = match runtime_worker_threads(&args) {
+        Ok(None) => LocalRuntime::new()
+            .expect("failed to create p2proxyd runtime")
+            .block_on(run_app(args)),
+        Ok(Some(worker_threads)) => tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(worker_threads)
+            .enable_all()
+            .build()
+            .expect("failed to create p2proxyd runtime")
+            .block_on(run_app(args)),
+        Err(e) => Err(e),
+    };
+    match res {
         Ok(()) => ExitCode::SUCCESS,
         Err(e) => {
             eprintln!("p2proxyd failed -> {}", display_chain(&*e));
@@ -47,13 +81,27 @@ fn main() -> ExitCode {
     }
 }
 
+/// Only a running daemon is worth more than one thread, every other subcommand runs on the current one
+fn runtime_worker_threads(args: &Args) -> anyhow::Result<Option<usize>> {
+    match &args.subcommand {
+        Subcommand::Run { args } => args.worker_threads(),
+        _ => Ok(None),
+    }
+}
+
 async fn run_app(args: Args) -> anyhow::Result<()> {
     setup_observability();
     match args.subcommand {
         Subcommand::Run { args } => {
+            let cfg_path = args.cfg_path.clone();
             let cfg = args.into_cfg()?;
-            proxy::run_proxy(cfg).await
+            proxy::run_proxy(cfg, cfg_path).await
         }
+        #[cfg(unix)]
+        Subcommand::Ctl { args } => admin::run_ctl(args).await,
+        Subcommand::CheckConfig { args } => configuration::check::run_check_config(args).await,
+        Subcommand::Config { args } => configuration::edit::run_config(args),
+        Subcommand::Usage { args } => usage::run_usage(&args),
         Subcommand::GenerateTemplateConfiguration { dest } => generate_template(&dest),
     }
 }
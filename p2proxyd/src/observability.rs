@@ -1,15 +1,33 @@
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
 use tracing::{Level, Metadata, Subscriber};
 use tracing_subscriber::Layer;
 use tracing_subscriber::layer::{Context, SubscriberExt};
 use tracing_subscriber::util::SubscriberInitExt;
 
+/// Set when the access log is written to stdout, which then has to stay parseable
+static LOG_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
 pub(super) fn setup_observability() {
     tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer())
+        .with(tracing_subscriber::fmt::layer().with_writer(log_writer))
         .with(CustomFilterWithBaseline(Level::DEBUG))
         .init();
 }
 
+/// Moves the daemon's own logs from stdout to stderr
+pub(crate) fn log_to_stderr() {
+    LOG_TO_STDERR.store(true, Ordering::Relaxed);
+}
+
+fn log_writer() -> Box<dyn Write> {
+    if LOG_TO_STDERR.load(Ordering::Relaxed) {
+        Box::new(std::io::stderr())
+    } else {
+        Box::new(std::io::stdout())
+    }
+}
+
 struct CustomFilterWithBaseline(Level);
 
 impl<S> Layer<S> for CustomFilterWithBaseline
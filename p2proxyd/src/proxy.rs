@@ -1,62 +1,220 @@
-use crate::access_log::AccessLogHandle;
 use crate::configuration::P2ProxydSetup;
-use crate::proto::P2ProxyProto;
+use crate::systemd::{Notifier, spawn_ready};
 use anyhow::Context;
 use iroh::protocol::Router;
+use iroh::{Endpoint, NodeId, SecretKey};
 use p2proxy_lib::display_chain;
 use p2proxy_lib::proto::ALPN;
+use p2proxy_server::access_log::AccessLogHandle;
+use p2proxy_server::routes::SharedRoutes;
+use p2proxy_server::{P2ProxyProto, ProxyState};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use time::OffsetDateTime;
 
-pub(super) async fn run_proxy(cfg: P2ProxydSetup) -> anyhow::Result<()> {
-    let nid = cfg.secret_key.public();
-    let endpoint = iroh::Endpoint::builder()
+/// How long to wait for the access log to write out the entries of the shutdown
+const ACCESS_LOG_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub(super) async fn run_proxy(cfg: P2ProxydSetup, cfg_path: PathBuf) -> anyhow::Result<()> {
+    let state = ProxyState::new(cfg.access_log_handle, cfg.limits, cfg.ban_policy);
+    if let Some(quota_state_path) = &cfg.quota_state_path {
+        crate::usage::load(state.usage(), quota_state_path)?;
+        crate::usage::spawn_persist(state.usage().clone(), quota_state_path.clone());
+    }
+    if let Some(admin_socket_path) = cfg.admin_socket_path {
+        #[cfg(unix)]
+        {
+            crate::admin::spawn_admin_socket(admin_socket_path, state.clone());
+        }
+        #[cfg(not(unix))]
+        {
+            tracing::warn!(
+                "admin socket {} configured, but admin sockets are only supported on unix",
+                admin_socket_path.display()
+            );
+        }
+    }
+    if let Some(metrics_listen) = cfg.metrics_listen {
+        p2proxy_server::metrics::spawn_metrics_server(metrics_listen, state.metrics().clone());
+    }
+    let notifier = Arc::new(Notifier::from_env());
+    let mut routers = Vec::with_capacity(cfg.identities.len());
+    let mut node_ids = Vec::with_capacity(cfg.identities.len());
+    let mut reload = ConfigReload {
+        cfg_path,
+        identities: Vec::with_capacity(cfg.identities.len()),
+    };
+    for identity in cfg.identities {
+        let nid = identity.secret_key.public();
+        node_ids.push(nid);
+        let endpoint = bind_endpoint(identity.secret_key, &identity.name).await?;
+        let proto = P2ProxyProto::new(
+            cfg.named_identities.then_some(identity.name.as_str()),
+            identity.routes,
+            &state,
+        );
+        reload
+            .identities
+            .push((identity.name.clone(), nid, proto.shared_routes()));
+        if let Some(previous) = identity.previous_key {
+            let previous_nid = previous.secret_key.public();
+            let endpoint = bind_endpoint(previous.secret_key, &identity.name).await?;
+            let router = Router::builder(endpoint)
+                .accept(ALPN, proto.retired(nid))
+                .spawn();
+            tracing::info!(
+                "serving previous node_id={previous_nid} of identity {} until {}, redirecting to node_id={nid}",
+                identity.name,
+                previous.until
+            );
+            spawn_retirement(router.clone(), previous_nid, previous.until);
+            routers.push(router);
+        }
+        tracing::info!(
+            "running service for identity {} with node_id={nid}",
+            identity.name
+        );
+        routers.push(Router::builder(endpoint).accept(ALPN, proto).spawn());
+    }
+    spawn_ready(
+        notifier.clone(),
+        node_ids,
+        state.active_connections().clone(),
+    );
+    if let Err(e) = sighand_loop(state.access_log_handle().clone(), reload).await {
+        tracing::error!("Error in sighand loop: {}", display_chain(&*e));
+    }
+    notifier.notify("STOPPING=1\nSTATUS=shutting down, draining open streams");
+    state.shutdown(cfg.shutdown_drain_timeout).await;
+    if let Some(quota_state_path) = &cfg.quota_state_path
+        && let Err(e) = crate::usage::persist(state.usage().clone(), quota_state_path.clone()).await
+    {
+        tracing::error!("failed to persist quota usage: {}", display_chain(&*e));
+    }
+    for router in routers {
+        router
+            .shutdown()
+            .await
+            .context("failed to shutdown router")?;
+    }
+    let access_log_handle = state.access_log_handle().clone();
+    let flushed =
+        tokio::task::spawn_blocking(move || access_log_handle.flush(ACCESS_LOG_FLUSH_TIMEOUT))
+            .await
+            .unwrap_or(false);
+    if !flushed {
+        tracing::warn!("timed out writing the last access log entries");
+    }
+    tracing::info!("shut down");
+    Ok(())
+}
+
+async fn bind_endpoint(secret_key: SecretKey, identity: &str) -> anyhow::Result<Endpoint> {
+    iroh::Endpoint::builder()
         .alpns(vec![ALPN.to_vec()])
         .discovery_n0()
-        .secret_key(cfg.secret_key)
+        .secret_key(secret_key)
         .bind()
         .await
-        .context("Failed to bind to endpoint")?;
-    let access_log_handle = cfg.access_log_handle;
-    let al_c = access_log_handle.clone();
-    let proto = P2ProxyProto::new(cfg.routes, access_log_handle);
-    tracing::info!("running service with node_id={nid}");
-    let router = Router::builder(endpoint).accept(ALPN, proto).spawn();
-    if let Err(e) = sighand_loop(al_c).await {
-        tracing::error!("Error in sighand loop: {}", display_chain(&*e));
+        .with_context(|| format!("Failed to bind to endpoint for identity {identity}"))
+}
+
+/// Stops serving a previous key once its rotation is over, connections to it are closed
+fn spawn_retirement(router: Router, node_id: NodeId, until: OffsetDateTime) {
+    let remaining = (until - OffsetDateTime::now_utc())
+        .try_into()
+        .unwrap_or(Duration::ZERO);
+    tokio::task::spawn(async move {
+        tokio::time::sleep(remaining).await;
+        tracing::info!("rotation is over, no longer serving previous node_id={node_id}");
+        if let Err(e) = router.shutdown().await {
+            tracing::warn!(
+                "failed to shut down the endpoint of node_id={node_id}: {}",
+                display_chain(&e)
+            );
+        }
+    });
+}
+
+/// What's needed to reload the configuration of a running proxy
+struct ConfigReload {
+    cfg_path: PathBuf,
+    /// Name, node id and route table of each running identity
+    identities: Vec<(String, NodeId, Arc<SharedRoutes>)>,
+}
+
+impl ConfigReload {
+    // An invalid configuration is logged and discarded, the running routes stay as they are
+    fn reload(&self) {
+        tracing::info!("reloading routes from {}", self.cfg_path.display());
+        let running = self
+            .identities
+            .iter()
+            .map(|(name, node_id, _)| (name.clone(), *node_id))
+            .collect::<Vec<_>>();
+        match crate::configuration::reload_routes(&self.cfg_path, &running) {
+            Ok(routes) => {
+                for ((_, _, shared), routes) in self.identities.iter().zip(routes) {
+                    shared.store(routes);
+                }
+                tracing::info!("reloaded routes from {}", self.cfg_path.display());
+            }
+            Err(e) => {
+                tracing::error!(
+                    "failed to reload routes, keeping previous configuration: {}",
+                    display_chain(&*e)
+                );
+            }
+        }
     }
-    router
-        .shutdown()
-        .await
-        .context("failed to shutdown router")?;
-    Ok(())
 }
 
-async fn sighand_loop(al: AccessLogHandle) -> anyhow::Result<()> {
+/// Handles signals until one asks for a shutdown
+async fn sighand_loop(al: AccessLogHandle, reload: ConfigReload) -> anyhow::Result<()> {
     #[cfg(target_family = "unix")]
     {
-        run_sighand_loop(al).await
+        run_sighand_loop(al, reload).await
     }
     #[cfg(not(target_family = "unix"))]
     {
-        async_noop().await;
+        let _ = (al, reload);
+        tokio::signal::ctrl_c()
+            .await
+            .context("Failed to receive ctrl-c signal")?;
+        tracing::info!("received ctrl-c, shutting down");
         Ok(())
     }
 }
 
 #[cfg(target_family = "unix")]
-async fn run_sighand_loop(access_log_handle: AccessLogHandle) -> anyhow::Result<()> {
-    let mut signal = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
-        .context("Failed to create hangup signal")?;
+async fn run_sighand_loop(
+    access_log_handle: AccessLogHandle,
+    reload: ConfigReload,
+) -> anyhow::Result<()> {
+    use tokio::signal::unix::{SignalKind, signal};
+    let mut hangup = signal(SignalKind::hangup()).context("Failed to create hangup signal")?;
+    let mut terminate =
+        signal(SignalKind::terminate()).context("Failed to create terminate signal")?;
+    let mut interrupt =
+        signal(SignalKind::interrupt()).context("Failed to create interrupt signal")?;
     loop {
-        signal
-            .recv()
-            .await
-            .context("Failed to receive hangup signal")?;
-        access_log_handle.reload_file()?;
+        tokio::select! {
+            sig = hangup.recv() => {
+                sig.context("Failed to receive hangup signal")?;
+                access_log_handle.reload_file();
+                reload.reload();
+            }
+            sig = terminate.recv() => {
+                sig.context("Failed to receive terminate signal")?;
+                tracing::info!("received SIGTERM, shutting down");
+                return Ok(());
+            }
+            sig = interrupt.recv() => {
+                sig.context("Failed to receive interrupt signal")?;
+                tracing::info!("received SIGINT, shutting down");
+                return Ok(());
+            }
+        }
     }
 }
-
-#[inline]
-#[cfg(not(target_family = "unix"))]
-async fn async_noop() {
-    futures::future::pending::<()>().await;
-}
@@ -0,0 +1,176 @@
+#[cfg(all(test, unix))]
+mod test;
+
+use iroh::NodeId;
+use p2proxy_server::registry::ActiveConnections;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often the status text is refreshed, unless the watchdog needs more frequent pings
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Notifies the service manager over `$NOTIFY_SOCKET`, as in `sd_notify(3)`, for units with `Type=notify`.
+/// If the daemon wasn't started by a service manager that listens, every notification is a no-op.
+#[derive(Debug)]
+pub(crate) struct Notifier {
+    #[cfg(unix)]
+    target: Option<(std::os::unix::net::UnixDatagram, NotifyAddr)>,
+}
+
+#[cfg(unix)]
+#[derive(Debug)]
+enum NotifyAddr {
+    Path(std::path::PathBuf),
+    #[cfg(target_os = "linux")]
+    Abstract(Vec<u8>),
+}
+
+impl Notifier {
+    pub(crate) fn from_env() -> Self {
+        #[cfg(unix)]
+        {
+            let Some(addr) = std::env::var_os("NOTIFY_SOCKET") else {
+                return Self { target: None };
+            };
+            match Self::connect(&addr) {
+                Ok(notifier) => notifier,
+                Err(e) => {
+                    tracing::warn!(
+                        "not notifying the service manager: {}",
+                        p2proxy_lib::display_chain(&*e)
+                    );
+                    Self { target: None }
+                }
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            Self {}
+        }
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn connect(addr: &std::ffi::OsStr) -> anyhow::Result<Self> {
+        use anyhow::Context;
+        use std::os::unix::ffi::OsStrExt;
+        let addr = match addr.as_bytes() {
+            [b'/', ..] => NotifyAddr::Path(std::path::PathBuf::from(addr)),
+            #[cfg(target_os = "linux")]
+            [b'@', name @ ..] => NotifyAddr::Abstract(name.to_vec()),
+            _ => anyhow::bail!(
+                "unsupported NOTIFY_SOCKET={}, only unix socket paths (and abstract sockets on linux) are supported",
+                addr.to_string_lossy()
+            ),
+        };
+        let socket = std::os::unix::net::UnixDatagram::unbound()
+            .context("failed to create notify socket")?;
+        // A notification is never worth blocking the runtime for
+        socket
+            .set_nonblocking(true)
+            .context("failed to make notify socket non-blocking")?;
+        Ok(Self {
+            target: Some((socket, addr)),
+        })
+    }
+
+    /// Sends newline separated `KEY=VALUE` assignments, failures are logged and otherwise ignored
+    pub(crate) fn notify(&self, state: &str) {
+        #[cfg(unix)]
+        {
+            let Some((socket, addr)) = &self.target else {
+                return;
+            };
+            let res = match addr {
+                NotifyAddr::Path(path) => socket.send_to(state.as_bytes(), path),
+                #[cfg(target_os = "linux")]
+                NotifyAddr::Abstract(name) => {
+                    use std::os::linux::net::SocketAddrExt;
+                    std::os::unix::net::SocketAddr::from_abstract_name(name)
+                        .and_then(|addr| socket.send_to_addr(state.as_bytes(), &addr))
+                }
+            };
+            if let Err(e) = res {
+                tracing::warn!("failed to notify the service manager: {e}");
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = state;
+        }
+    }
+}
+
+/// Tells the service manager the daemon is serving, then keeps the status text up to date,
+/// and pings the watchdog if the unit has one. The pings come from the runtime,
+/// so a runtime that stops making progress gets the daemon restarted.
+pub(crate) fn spawn_ready(
+    notifier: Arc<Notifier>,
+    node_ids: Vec<NodeId>,
+    active: Arc<ActiveConnections>,
+) {
+    notifier.notify(&format!(
+        "READY=1\nSTATUS={}",
+        status_text(&node_ids, &active)
+    ));
+    let watchdog = watchdog_interval(
+        std::env::var("WATCHDOG_USEC").ok().as_deref(),
+        std::env::var("WATCHDOG_PID").ok().as_deref(),
+        std::process::id(),
+    );
+    if let Some(watchdog) = watchdog {
+        tracing::info!("pinging the service manager watchdog every {watchdog:?}");
+    }
+    let period = watchdog.map_or(STATUS_INTERVAL, |watchdog| watchdog.min(STATUS_INTERVAL));
+    tokio::task::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let status = status_text(&node_ids, &active);
+            if watchdog.is_some() {
+                notifier.notify(&format!("WATCHDOG=1\nSTATUS={status}"));
+            } else {
+                notifier.notify(&format!("STATUS={status}"));
+            }
+        }
+    });
+}
+
+/// Half of what the service manager expects, as recommended in `sd_watchdog_enabled(3)`.
+/// Not enabled if the watchdog was meant for another process.
+pub(crate) fn watchdog_interval(
+    usec: Option<&str>,
+    pid: Option<&str>,
+    own_pid: u32,
+) -> Option<Duration> {
+    if let Some(pid) = pid
+        && pid.parse::<u32>().ok() != Some(own_pid)
+    {
+        return None;
+    }
+    let usec = usec?.parse::<u64>().ok().filter(|usec| *usec > 0)?;
+    Some(Duration::from_micros(usec / 2))
+}
+
+fn status_text(node_ids: &[NodeId], active: &ActiveConnections) -> String {
+    let mut status = String::new();
+    if node_ids.len() == 1 {
+        status.push_str("node_id=");
+    } else {
+        status.push_str("node_ids=");
+    }
+    for (i, node_id) in node_ids.iter().enumerate() {
+        if i > 0 {
+            status.push(',');
+        }
+        let _ = write!(status, "{node_id}");
+    }
+    let _ = write!(
+        status,
+        ", {} connections, {} open streams",
+        active.connection_count(),
+        active.stream_count()
+    );
+    status
+}
@@ -0,0 +1,36 @@
+use crate::systemd::{Notifier, watchdog_interval};
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+#[test]
+fn test_notify_socket() {
+    let path =
+        std::env::temp_dir().join(format!("p2proxyd-notify-test-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixDatagram::bind(&path).unwrap();
+    let notifier = Notifier::connect(path.as_os_str()).unwrap();
+    notifier.notify("READY=1\nSTATUS=serving");
+    let mut buf = [0u8; 64];
+    let read = listener.recv(&mut buf).unwrap();
+    assert_eq!(b"READY=1\nSTATUS=serving", &buf[..read]);
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(Notifier::connect("vsock:2:1234".as_ref()).is_err());
+}
+
+#[test]
+fn test_watchdog_interval() {
+    assert_eq!(
+        Some(Duration::from_secs(15)),
+        watchdog_interval(Some("30000000"), None, 42)
+    );
+    assert_eq!(
+        Some(Duration::from_secs(15)),
+        watchdog_interval(Some("30000000"), Some("42"), 42)
+    );
+    // Meant for another process
+    assert_eq!(None, watchdog_interval(Some("30000000"), Some("43"), 42));
+    assert_eq!(None, watchdog_interval(Some("0"), None, 42));
+    assert_eq!(None, watchdog_interval(Some("garbage"), None, 42));
+    assert_eq!(None, watchdog_interval(None, None, 42));
+}
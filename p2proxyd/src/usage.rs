@@ -0,0 +1,125 @@
+use crate::configuration::{P2proxydTomlConfig, quota_state_path};
+use anyhow::Context;
+use iroh::NodeId;
+use p2proxy_lib::display_chain;
+use p2proxy_server::quota::{Usage, UsageSnapshot};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often the counters are written to the state file, at most this much traffic is forgotten on a crash
+const PERSIST_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Show how much of their traffic quotas peers have used
+#[derive(clap::Parser, Debug)]
+pub struct UsageArgs {
+    /// Path to the configuration file
+    #[clap(short, long)]
+    pub cfg_path: PathBuf,
+}
+
+/// Picks up the counters of a previous run, a missing file means nothing was used yet
+pub(crate) fn load(usage: &Usage, path: &Path) -> anyhow::Result<()> {
+    let content = match std::fs::read(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("failed to read quota state file {}", path.display()));
+        }
+    };
+    let snapshot = serde_json::from_slice::<UsageSnapshot>(&content)
+        .with_context(|| format!("failed to parse quota state file {}", path.display()))?;
+    usage
+        .restore(&snapshot)
+        .with_context(|| format!("invalid quota state file {}", path.display()))
+}
+
+/// Written next to the file and renamed over it, so that a crash never leaves half a file behind
+fn save(usage: &Usage, path: &Path) -> anyhow::Result<()> {
+    let content =
+        serde_json::to_vec_pretty(&usage.snapshot()).context("failed to serialize quota state")?;
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    std::fs::write(&tmp, content)
+        .with_context(|| format!("failed to write quota state file {}", tmp.display()))?;
+    std::fs::rename(&tmp, path)
+        .with_context(|| format!("failed to replace quota state file {}", path.display()))
+}
+
+/// [`save`] on a blocking thread, the runtime may be the single thread every stream is proxied on
+pub(crate) async fn persist(usage: Arc<Usage>, path: PathBuf) -> anyhow::Result<()> {
+    tokio::task::spawn_blocking(move || save(&usage, &path))
+        .await
+        .context("quota state writer panicked")?
+}
+
+pub(crate) fn spawn_persist(usage: Arc<Usage>, path: PathBuf) {
+    tokio::task::spawn(async move {
+        let mut interval = tokio::time::interval(PERSIST_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        // The first tick completes immediately, there's nothing new to save yet
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(e) = persist(usage.clone(), path.clone()).await {
+                tracing::warn!("failed to persist quota usage: {}", display_chain(&*e));
+            }
+        }
+    });
+}
+
+pub(crate) fn run_usage(args: &UsageArgs) -> anyhow::Result<()> {
+    let mut toml = P2proxydTomlConfig::from_path(&args.cfg_path)?;
+    let explicit_identities = !toml.identities.is_empty();
+    let identities = toml.take_identities()?;
+    let Some(path) = quota_state_path(toml.quota_state_path.take(), &identities)? else {
+        println!("no quota_state_path configured, no usage is counted");
+        return Ok(());
+    };
+    let usage = Usage::new();
+    load(&usage, &path)?;
+    let snapshot = usage.snapshot();
+    let used = |node_id: &NodeId| {
+        snapshot
+            .peers
+            .iter()
+            .find(|peer| peer.node_id == *node_id)
+            .map_or((0, 0), |peer| (peer.bytes_today, peer.bytes_this_month))
+    };
+    println!("usage on {} (UTC), from {}", snapshot.day, path.display());
+    let mut any = false;
+    for identity in &identities {
+        for peer in identity.peers.iter().flatten() {
+            if peer.quota_bytes_per_day.is_none() && peer.quota_bytes_per_month.is_none() {
+                continue;
+            }
+            any = true;
+            let (today, this_month) = used(&peer.node_id);
+            let identity = if explicit_identities {
+                format!("{}\t", identity.name)
+            } else {
+                String::new()
+            };
+            println!(
+                "{identity}{}\ttoday={}\tmonth={}",
+                peer.node_id,
+                used_of(today, peer.quota_bytes_per_day),
+                used_of(this_month, peer.quota_bytes_per_month)
+            );
+        }
+    }
+    if !any {
+        println!("no peers have a quota");
+    }
+    Ok(())
+}
+
+fn used_of(used: u64, quota: Option<u64>) -> String {
+    match quota {
+        Some(quota) if used >= quota => format!("{used}B/{quota}B (exceeded)"),
+        Some(quota) => format!("{used}B/{quota}B"),
+        None => format!("{used}B/unlimited"),
+    }
+}